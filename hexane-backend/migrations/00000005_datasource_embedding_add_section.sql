@@ -0,0 +1,3 @@
+/* section is the heading path of the chunk, e.g. "Design > Storage". */
+ALTER TABLE datasource.embedding
+  ADD COLUMN section TEXT;
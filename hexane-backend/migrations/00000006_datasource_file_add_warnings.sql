@@ -0,0 +1,4 @@
+/* warnings raised while processing the file, e.g. rows of a spreadsheet that
+   were dropped. */
+ALTER TABLE datasource.file
+  ADD COLUMN warnings TEXT[] NOT NULL DEFAULT '{}';
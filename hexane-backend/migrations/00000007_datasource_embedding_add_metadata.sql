@@ -0,0 +1,4 @@
+/* metadata describes where the chunk comes from, e.g. the sender, date &
+   subject of an email. */
+ALTER TABLE datasource.embedding
+  ADD COLUMN metadata JSONB;
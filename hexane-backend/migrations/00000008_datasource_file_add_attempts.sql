@@ -0,0 +1,18 @@
+/* attempts is the number of times processing the file was attempted,
+   last_error is the error of the last failed attempt. Failed attempts are
+   retried after next_attempt, once the attempts run out the file is marked as
+   failed and it's left alone until the user retries it. */
+ALTER TABLE datasource.file
+  ADD COLUMN attempts     INTEGER NOT NULL DEFAULT 0,
+  ADD COLUMN last_error   TEXT,
+  ADD COLUMN next_attempt TIMESTAMP WITH TIME ZONE,
+  ADD COLUMN failed       TIMESTAMP WITH TIME ZONE;
+
+/* notify the file processor when a failed file is retried. */
+CREATE CONSTRAINT TRIGGER datasource_file_retry_trigger
+    AFTER UPDATE OF failed ON datasource.file
+    DEFERRABLE
+    INITIALLY DEFERRED
+    FOR EACH ROW
+    WHEN ( OLD.failed IS NOT NULL AND NEW.failed IS NULL )
+    EXECUTE PROCEDURE capture_datasource_func();
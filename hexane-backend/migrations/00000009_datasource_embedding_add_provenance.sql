@@ -0,0 +1,9 @@
+/* page_start & page_end are the pages the chunk was taken from, NULL for
+   formats without pages. char_start & char_end are the character offsets of
+   the chunk in the text extracted from the file, sections are separated by a
+   blank line. */
+ALTER TABLE datasource.embedding
+  ADD COLUMN page_start INTEGER,
+  ADD COLUMN page_end INTEGER,
+  ADD COLUMN char_start INTEGER,
+  ADD COLUMN char_end INTEGER;
@@ -0,0 +1,13 @@
+/* embedding_cache maps the SHA-256 of a text embedded with a model to its
+   vector, it's shared by every account so identical chunks are only embedded
+   (and billed) once. */
+CREATE TABLE datasource.embedding_cache(
+    hash  TEXT NOT NULL,
+    model TEXT NOT NULL,
+
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+
+    embedding VECTOR NOT NULL,
+
+    PRIMARY KEY (model, hash)
+);
@@ -0,0 +1,11 @@
+/* model & dimensions record what produced the embedding, vectors of
+   different models can't be compared. Existing embeddings have no model,
+   they're re-embedded by `hexane-file-processor reembed`. */
+ALTER TABLE datasource.embedding
+  ADD COLUMN model TEXT,
+  ADD COLUMN dimensions INTEGER;
+
+UPDATE datasource.embedding SET dimensions = vector_dims(embedding);
+
+CREATE INDEX datasource_embedding_model_idx
+    ON datasource.embedding (model);
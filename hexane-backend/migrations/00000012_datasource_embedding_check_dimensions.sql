@@ -0,0 +1,8 @@
+/* The embedding column stays untyped because vectors of the old & new model
+   coexist while re-embedding. Approximate nearest neighbour indexes are
+   instead built per model by `hexane-file-processor index`, as partial
+   indexes on embedding::vector(dimensions), which requires dimensions to
+   match the vector. */
+ALTER TABLE datasource.embedding
+  ADD CONSTRAINT embedding_dimensions_check
+  CHECK (dimensions IS NULL OR dimensions = vector_dims(embedding));
@@ -0,0 +1,11 @@
+/* tsv is the full text search vector of the chunk, the section is weighted
+   above the text. The simple configuration doesn't stem or drop stop words,
+   so identifiers like error codes & part numbers match as written. */
+ALTER TABLE datasource.embedding
+  ADD COLUMN tsv TSVECTOR GENERATED ALWAYS AS (
+    setweight(to_tsvector('simple', coalesce(section, '')), 'A')
+      || setweight(to_tsvector('simple', text), 'B')
+  ) STORED;
+
+CREATE INDEX datasource_embedding_tsv_idx
+    ON datasource.embedding USING GIN (tsv);
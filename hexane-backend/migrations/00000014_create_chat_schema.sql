@@ -0,0 +1,36 @@
+CREATE SCHEMA chat;
+
+/* thread is a conversation, its title is the first question. */
+CREATE TABLE chat.thread(
+    id      UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users.account ON DELETE CASCADE,
+
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    updated TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+
+    title TEXT NOT NULL,
+
+    CONSTRAINT chat_thread_title_length_check
+        CHECK ( 0 < LENGTH(title) AND LENGTH(title) <= 128 )
+);
+
+CREATE INDEX chat_thread_user_idx
+    ON chat.thread (user_id, updated DESC);
+
+/* message is a turn of a thread. tokens is the length of the content, used
+   to fit earlier turns in the prompt. sources are the references the answer
+   was generated from. */
+CREATE TABLE chat.message(
+    id        UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    thread_id UUID NOT NULL REFERENCES chat.thread ON DELETE CASCADE,
+
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+
+    role    TEXT    NOT NULL CHECK ( role IN ('user', 'assistant') ),
+    content TEXT    NOT NULL,
+    tokens  INTEGER NOT NULL CHECK ( tokens >= 0 ),
+    sources TEXT[]  NOT NULL DEFAULT '{}'
+);
+
+CREATE INDEX chat_message_thread_idx
+    ON chat.message (thread_id, created);
@@ -0,0 +1,35 @@
+/* Builds the approximate nearest neighbour index of every model & dimension
+   already embedded. Vectors of several models coexist while re-embedding, so
+   instead of typing the embedding column each index is an expression index
+   on embedding::vector(dimensions) over the rows of one model & dimension,
+   which fixes the dimension of the vectors it covers.
+
+   The indexes use the default method, parameters & metric (HNSW, m = 16,
+   ef_construction = 64, l2) & are named like `hexane-file-processor index`
+   names them. It builds the indexes of models embedded later & rebuilds
+   these when other parameters or another metric are configured. */
+DO $$
+DECLARE
+  indexed RECORD;
+BEGIN
+  FOR indexed IN
+    SELECT DISTINCT model, dimensions
+    FROM datasource.embedding
+    WHERE model IS NOT NULL
+      AND dimensions IS NOT NULL
+  LOOP
+    EXECUTE format(
+      'CREATE INDEX IF NOT EXISTS %I ON datasource.embedding
+       USING hnsw ((embedding::vector(%s)) vector_l2_ops)
+       WITH (m = 16, ef_construction = 64)
+       WHERE model = %L AND dimensions = %s',
+      format('datasource_embedding_ann_%s_%s_l2',
+             left(encode(sha256(convert_to(indexed.model, 'UTF8')), 'hex'), 16),
+             indexed.dimensions),
+      indexed.dimensions,
+      indexed.model,
+      indexed.dimensions
+    );
+  END LOOP;
+END
+$$;
@@ -0,0 +1,2 @@
+INSERT INTO chat.thread (id, user_id, title)
+  VALUES ($1, $2, $3);
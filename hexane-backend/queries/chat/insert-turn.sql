@@ -0,0 +1,6 @@
+WITH thread AS (
+  UPDATE chat.thread SET updated = now() WHERE id = $1
+)
+INSERT INTO chat.message (thread_id, role, content, tokens, sources)
+  VALUES ($1, 'user', $2, $3, '{}'),
+         ($1, 'assistant', $4, $5, $6);
@@ -0,0 +1,6 @@
+/* The question & the answer of a turn are inserted together, the question
+   comes first. */
+SELECT role, content, tokens, sources
+FROM chat.message
+WHERE thread_id = $1
+ORDER BY created, role = 'assistant';
@@ -0,0 +1,3 @@
+SELECT EXISTS(
+  SELECT 1 FROM chat.thread WHERE id = $1 AND user_id = $2
+) AS "exists!";
@@ -0,0 +1,5 @@
+SELECT id, title
+FROM chat.thread
+WHERE user_id = $1
+ORDER BY updated DESC
+LIMIT 50;
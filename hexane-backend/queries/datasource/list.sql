@@ -1,4 +1,5 @@
-SELECT name, category, to_char(processed, 'YYYY-MM-DD HH24:MI TZ') AS processed, size, hash
+SELECT name, category, to_char(processed, 'YYYY-MM-DD HH24:MI TZ') AS processed, size, hash, warnings,
+       attempts, last_error, failed IS NOT NULL AS "failed!"
 FROM datasource.file
 WHERE user_id = $1
   AND deleted IS NULL
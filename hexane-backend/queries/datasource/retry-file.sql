@@ -0,0 +1,9 @@
+UPDATE datasource.file
+SET failed = NULL,
+    attempts = 0,
+    last_error = NULL,
+    next_attempt = NULL
+WHERE user_id = $1
+  AND hash = $2
+  AND failed IS NOT NULL
+RETURNING id;
@@ -0,0 +1,6 @@
+SELECT count(*) AS "count!"
+FROM datasource.embedding JOIN datasource.file ON file.id = embedding.file_id
+WHERE file.user_id = $1
+  AND embedding.model IS DISTINCT FROM $2
+  AND embedding.created = file.processed
+  AND file.deleted IS NULL;
@@ -0,0 +1,23 @@
+// Answers are streamed as server-sent events: "token" events carry the text
+// as it's generated, "done" or "failed" carry the HTML to show once it ends.
+htmx.onLoad(function (content) {
+    content.querySelectorAll("[data-stream]").forEach(function (answer) {
+        var response = answer.querySelector(".query-response");
+        var source = new EventSource(answer.dataset.stream);
+        answer.removeAttribute("data-stream");
+
+        source.addEventListener("token", function (event) {
+            response.textContent += event.data;
+        });
+        ["done", "failed"].forEach(function (name) {
+            source.addEventListener(name, function (event) {
+                answer.insertAdjacentHTML("beforeend", event.data);
+                source.close();
+            });
+        });
+        // The stream can't be resumed, the answer is stored once complete.
+        source.onerror = function () {
+            source.close();
+        };
+    });
+});
@@ -32,6 +32,8 @@ pub fn app(state: AppState, session_store: PostgresStore) -> Router {
         )
         .route("/query", get(handlers::query::query))
         .route("/query", post(handlers::query::query_post))
+        .route("/query/:thread_id", get(handlers::query::thread))
+        .route("/query/stream/:stream_id", get(handlers::query::stream))
         .route("/account/logout", post(handlers::account::logout))
         .layer(middleware::from_fn_with_state(
             state.clone(),
@@ -38,6 +38,11 @@ struct FileError {
     error: String,
 }
 
+struct FileWarning {
+    name: String,
+    warning: String,
+}
+
 pub async fn upload(
     user_session: UserSession,
     State(state): State<AppState>,
@@ -67,6 +72,7 @@ pub async fn upload(
     // file_errors stores the files that weren't uploaded along with their errors.
     let mut file_uploads_count = 0;
     let mut file_errors: Vec<FileError> = vec![];
+    let mut file_warnings: Vec<FileWarning> = vec![];
 
     // Parse uploaded form-data.
     while let Some(mut field) = multipart.next_field().await.unwrap() {
@@ -88,18 +94,7 @@ pub async fn upload(
 
         let name = field.file_name().unwrap().to_string();
 
-        // Verify file's content-type.
-        let r#type = field.content_type().unwrap().to_string();
-        match r#type.as_str() {
-            "text/plain" | "application/pdf" => {}
-            _ => {
-                file_errors.push(FileError {
-                    name,
-                    error: format!("Unsupported file type ({})", &r#type),
-                });
-                break;
-            }
-        };
+        let content_type = field.content_type().unwrap_or_default().to_string();
 
         // Create a temporary file to stream the upload.
         let path_tmp = user_drive.join(&format!(
@@ -128,6 +123,25 @@ pub async fn upload(
 
         file.flush().await.unwrap();
 
+        // Detect the file's type from its content, the Content-Type sent by
+        // the client & the file extension are checked against it. The file
+        // is read synchronously.
+        let extractors = state.extractors.clone();
+        let (path_detect, name_detect) = (path_tmp.clone(), name.clone());
+        let r#type = match tokio::task::spawn_blocking(move || {
+            extractors.detect_type(&path_detect, &content_type, &name_detect)
+        })
+        .await
+        .unwrap()
+        {
+            Ok(r#type) => r#type,
+            Err(error) => {
+                fs::remove_file(&path_tmp).await.unwrap();
+                file_errors.push(FileError { name, error });
+                continue;
+            }
+        };
+
         let hash = hasher.finalize();
         let hash = hash
             .iter()
@@ -164,6 +178,20 @@ pub async fn upload(
             }
             None => {
                 fs::rename(&path_tmp, &path).await.unwrap();
+
+                // Parts of the file that won't be processed are reported
+                // now rather than once it's processed.
+                let extractors = state.extractors.clone();
+                let path_inspect = path.clone();
+                let warnings =
+                    tokio::task::spawn_blocking(move || extractors.inspect(&path_inspect, r#type))
+                        .await
+                        .unwrap();
+                file_warnings.extend(warnings.into_iter().map(|warning| FileWarning {
+                    name: name.clone(),
+                    warning,
+                }));
+
                 sqlx::query_file!(
                     "queries/datasource/insert-file.sql",
                     user_session.id(),
@@ -194,6 +222,16 @@ pub async fn upload(
         })
         .collect::<Value>();
 
+    let file_warnings_html = file_warnings
+        .iter()
+        .map(|x| {
+            json!({
+                "TEMPLATE": "html/li",
+                "text": format!("{}: {}", x.name, x.warning)
+            })
+        })
+        .collect::<Vec<Value>>();
+
     let status = json!({
         "TEMPLATE": "pages/datasource/upload-status",
         "uploaded": file_uploads_count,
@@ -201,7 +239,11 @@ pub async fn upload(
         "file-errors": {
             "TEMPLATE": "html/ul",
             "items": file_errors_html
-        }
+        },
+        "file-warnings": (!file_warnings_html.is_empty()).then(|| json!({
+            "TEMPLATE": "pages/datasource/file-warnings",
+            "items": file_warnings_html
+        }))
     });
 
     if hx_request {
@@ -226,7 +268,8 @@ pub async fn upload(
 
 #[derive(Deserialize)]
 pub struct FileActionForm {
-    delete: String,
+    delete: Option<String>,
+    retry: Option<String>,
 }
 
 pub async fn file_action(
@@ -235,21 +278,38 @@ pub async fn file_action(
     HxRequest(hx_request): HxRequest,
     Form(form): Form<FileActionForm>,
 ) -> impl IntoResponse {
-    let deleted_file = sqlx::query_file!(
-        "queries/datasource/delete-file.sql",
-        user_session.id(),
-        form.delete
-    )
-    .fetch_one(&state.pool)
-    .await
-    .unwrap();
-
-    fs::remove_file(state.config.file_store.join(deleted_file.path))
-        .await
-        .unwrap();
+    let message = match (form.delete, form.retry) {
+        (Some(hash), _) => {
+            let deleted_file = sqlx::query_file!(
+                "queries/datasource/delete-file.sql",
+                user_session.id(),
+                hash
+            )
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+
+            fs::remove_file(state.config.file_store.join(deleted_file.path))
+                .await
+                .unwrap();
+
+            "File deleted."
+        }
+        (None, Some(hash)) => {
+            match sqlx::query_file!("queries/datasource/retry-file.sql", user_session.id(), hash)
+                .fetch_optional(&state.pool)
+                .await
+                .unwrap()
+            {
+                Some(_) => "File queued for processing.",
+                None => "File cannot be retried.",
+            }
+        }
+        (None, None) => return StatusCode::BAD_REQUEST.into_response(),
+    };
 
     if hx_request {
-        return ([("HX-Trigger", "newDatasourceFile")], "File deleted.").into_response();
+        return ([("HX-Trigger", "newDatasourceFile")], message).into_response();
     }
 
     Redirect::to("/datasources").into_response()
@@ -1,15 +1,35 @@
-use axum::{extract::State, response::Html, Form};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    Form,
+};
 use axum_htmx::HxRequest;
+use futures_util::stream;
 use num_traits::cast::ToPrimitive;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use sqlx::{types::BigDecimal, Row};
-use std::{collections::HashSet, convert::TryFrom};
-use tokio::time::{Duration, Instant};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::{Infallible, TryFrom},
+};
+use tokio::{
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
 
-use hexane_shared::{get_embeddings, merge_json};
+use hexane_shared::{
+    chat::Usage, embedding::get_embeddings, rerank::rerank, ExpansionMode, Metric, Retrieval,
+    VectorIndex,
+};
 
-use crate::pages::query::Query;
+use crate::pages::query::{references, Query};
+use crate::retrieval::{condense_query, expand_query, mmr};
 use crate::types::{AppState, UserSession};
 
 fn process_query(q: &str, stop_words: &HashSet<String>) -> String {
@@ -20,22 +40,311 @@ fn process_query(q: &str, stop_words: &HashSet<String>) -> String {
         .replace(&['(', ')', ',', '\"', '.', ';', ':', '\'', '?'][..], "")
 }
 
+/// Full text search query matching any of the words of the query, in
+/// websearch_to_tsquery syntax. Unlike process_query, punctuation inside words
+/// is kept so identifiers like "E-1234" or "v2.3.1" are searched as written.
+fn keyword_query(q: &str, stop_words: &HashSet<String>) -> String {
+    q.split_whitespace()
+        .map(|w| w.trim_matches(|c: char| !c.is_alphanumeric()))
+        .filter(|w| !w.is_empty() && !stop_words.contains(&w.to_lowercase()))
+        .filter(|w| !w.eq_ignore_ascii_case("or"))
+        .collect::<Vec<&str>>()
+        .join(" or ")
+}
+
 pub async fn query(user_session: UserSession, State(state): State<AppState>) -> Html<String> {
     state
         .pages
         .render_index_body(Query::new(&state, &user_session).page().await, true)
 }
 
+pub async fn thread(
+    user_session: UserSession,
+    State(state): State<AppState>,
+    Path(thread_id): Path<Uuid>,
+) -> Html<String> {
+    if !thread_exists(&state, thread_id, user_session.id()).await {
+        return super::not_found(Some(user_session), State(state)).await;
+    }
+
+    state.pages.render_index_body(
+        Query::new(&state, &user_session)
+            .with_thread(thread_id)
+            .page()
+            .await,
+        true,
+    )
+}
+
+async fn thread_exists(state: &AppState, thread_id: Uuid, user_id: Uuid) -> bool {
+    sqlx::query_file!("queries/chat/thread-exists.sql", thread_id, user_id)
+        .fetch_one(&state.pool)
+        .await
+        .unwrap()
+        .exists
+}
+
+/// Earlier turns of the thread as chat completion messages, the most recent
+/// ones that fit in history_tokens.
+async fn history(state: &AppState, thread_id: Uuid) -> Vec<Value> {
+    let messages = sqlx::query_file!("queries/chat/messages.sql", thread_id)
+        .fetch_all(&state.pool)
+        .await
+        .unwrap();
+
+    let mut tokens = 0;
+    let mut history = messages
+        .iter()
+        .rev()
+        .take_while(|message| {
+            tokens += message.tokens as usize;
+            tokens <= state.config.backend.chat.history_tokens
+        })
+        .collect::<Vec<_>>();
+    history.reverse();
+
+    // A turn starts with the question.
+    history
+        .into_iter()
+        .skip_while(|message| message.role != "user")
+        .map(|message| json!({ "role": message.role, "content": message.content }))
+        .collect()
+}
+
+/// Charges the user for a chat completion call.
+async fn charge(state: &AppState, user_id: Uuid, cost: f64) {
+    sqlx::query_file!(
+        "queries/account/credit-decrement.sql",
+        user_id,
+        BigDecimal::try_from(cost).unwrap()
+    )
+    .execute(&state.pool)
+    .await
+    .unwrap();
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct QueryReferences {
+    id: Uuid,
     file: String,
+    section: Option<String>,
+    metadata: Option<Value>,
+    page_start: Option<i32>,
+    page_end: Option<i32>,
     text: String,
+    /// Fused rank score, higher is more relevant.
+    score: f64,
+    embedding: Vec<f32>,
+}
+
+impl QueryReferences {
+    /// Page range of the chunk, e.g. "p. 12" or "p. 12–13".
+    fn pages(&self) -> Option<String> {
+        match (self.page_start, self.page_end) {
+            (Some(start), Some(end)) if start < end => Some(format!("p. {}–{}", start, end)),
+            (Some(start), _) => Some(format!("p. {}", start)),
+            _ => None,
+        }
+    }
+
+    /// The chunk as sent in the context.
+    fn context(&self) -> String {
+        let mut context = format!("filename: {}\n", self.file);
+        if let Some(pages) = self.pages() {
+            context.push_str(&format!("pages: {}\n", pages));
+        }
+        if let Some(section) = &self.section {
+            context.push_str(&format!("section: {}\n", section));
+        }
+        if let Some(Value::Object(metadata)) = &self.metadata {
+            for (name, value) in metadata {
+                if let Some(value) = value.as_str() {
+                    context.push_str(&format!("{}: {}\n", name, value));
+                }
+            }
+        }
+        context.push_str(&self.text);
+        context
+    }
+}
+
+/// retrieve returns the chunks nearest to the embedding fused with the
+/// chunks matching the keywords, at most limit of them by decreasing score.
+async fn retrieve(
+    state: &AppState,
+    retrieval: &Retrieval,
+    user_id: Uuid,
+    category: &str,
+    embedding: Vec<f64>,
+    keywords: &str,
+    limit: i64,
+) -> Result<Vec<QueryReferences>, sqlx::Error> {
+    // Chunks are ranked by vector distance & by full text search, the
+    // rankings are fused with reciprocal rank fusion. ts_rank_cd normalized
+    // by the document length stands in for BM25.
+    //
+    // The distance & the model filter must match the indexes the file
+    // processor builds for them to be used, so must the metric.
+    // The index returns the nearest ef_search (or probes lists) vectors
+    // before the user & category are filtered, these may need raising when
+    // many users share the index.
+    //
+    // Chunks waiting to be re-embedded, of another model or embedded before
+    // the model was recorded (model NULL), are only found by keywords &
+    // their vectors aren't compared by MMR.
+    let dimensions = embedding.len() as i32;
+    let sql_query = format!(
+        "
+WITH nearest AS (
+  SELECT embedding.id,
+         embedding::vector({dimensions}) {operator} $2::vector({dimensions}) AS distance
+  FROM datasource.embedding JOIN datasource.file ON file.id = embedding.file_id
+  WHERE file.user_id = $1
+    AND embedding.created = file.processed
+    AND embedding.{predicate}
+    {category}
+    AND (embedding::vector({dimensions}) {operator} $2::vector({dimensions})) < $8
+  ORDER BY embedding::vector({dimensions}) {operator} $2::vector({dimensions})
+  LIMIT $4
+), vector AS (
+  SELECT id, row_number() OVER (ORDER BY distance) AS rank
+  FROM nearest
+  ORDER BY distance
+  LIMIT $4
+), keyword AS (
+  SELECT embedding.id,
+         row_number() OVER (ORDER BY ts_rank_cd(tsv, query, 1) DESC) AS rank
+  FROM datasource.embedding JOIN datasource.file ON file.id = embedding.file_id,
+       websearch_to_tsquery('simple', $3) query
+  WHERE file.user_id = $1
+    AND embedding.created = file.processed
+    {category}
+    AND $7 > 0
+    AND tsv @@ query
+  ORDER BY ts_rank_cd(tsv, query, 1) DESC
+  LIMIT $4
+)
+SELECT embedding.id, text, section, metadata, page_start, page_end, file.name,
+       CASE WHEN (embedding.{predicate})
+            THEN embedding.embedding::real[]
+            ELSE '{{}}'
+       END AS vector,
+       coalesce($6 / ($5 + vector.rank), 0)
+         + coalesce($7 / ($5 + keyword.rank), 0) AS score
+FROM vector FULL JOIN keyword ON keyword.id = vector.id
+  JOIN datasource.embedding ON embedding.id = coalesce(vector.id, keyword.id)
+  JOIN datasource.file ON file.id = embedding.file_id
+ORDER BY score DESC
+LIMIT $9;",
+        dimensions = dimensions,
+        operator = retrieval.metric.operator(),
+        predicate = VectorIndex::predicate(state.embedder.model(), dimensions),
+        category = if category.is_empty() {
+            ""
+        } else {
+            "AND category = $10"
+        },
+    );
+
+    let hybrid = &retrieval.hybrid;
+    let mut query_builder = sqlx::query(&sql_query)
+        .bind(user_id)
+        .bind(embedding)
+        .bind(keywords)
+        .bind(hybrid.candidates.max(limit))
+        .bind(hybrid.rrf_k)
+        .bind(hybrid.vector_weight)
+        .bind(hybrid.keyword_weight)
+        .bind(retrieval.metric.max_distance(retrieval.threshold))
+        .bind(limit);
+
+    if !category.is_empty() {
+        query_builder = query_builder.bind(category);
+    }
+
+    let mut tx = state.pool.begin().await?;
+    sqlx::query(&state.config.embedding.index.search_settings())
+        .execute(&mut *tx)
+        .await?;
+
+    let rows = query_builder.fetch_all(&mut *tx).await?;
+    tx.commit().await?;
+
+    Ok(rows
+        .iter()
+        .map(|r| QueryReferences {
+            id: r.try_get::<Uuid, _>("id").unwrap(),
+            file: r.try_get::<String, _>("name").unwrap(),
+            section: r.try_get::<Option<String>, _>("section").unwrap(),
+            metadata: r.try_get::<Option<Value>, _>("metadata").unwrap(),
+            page_start: r.try_get::<Option<i32>, _>("page_start").unwrap(),
+            page_end: r.try_get::<Option<i32>, _>("page_end").unwrap(),
+            text: r.try_get::<String, _>("text").unwrap(),
+            score: r.try_get::<f64, _>("score").unwrap(),
+            embedding: r.try_get::<Vec<f32>, _>("vector").unwrap(),
+        })
+        .collect())
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct QueryForm {
     query: String,
     category: String,
+    // Overrides of the retrieval config, empty when not set.
+    #[serde(default)]
+    top_k: String,
+    #[serde(default)]
+    metric: String,
+    #[serde(default)]
+    threshold: String,
+    #[serde(default)]
+    max_context_tokens: String,
+    #[serde(default)]
+    expansion: String,
+    /// Thread the question is asked in, empty to start a new one.
+    #[serde(default)]
+    thread: String,
+}
+
+impl QueryForm {
+    /// Returns the retrieval config with the overrides of the form applied.
+    fn retrieval(&self, config: &Retrieval) -> Result<Retrieval, String> {
+        let mut retrieval = config.clone();
+
+        if !self.top_k.is_empty() {
+            retrieval.top_k = match self.top_k.parse() {
+                Ok(top_k) if (1..=50).contains(&top_k) => top_k,
+                _ => return Err("Top-k must be a number between 1 and 50.".to_string()),
+            };
+        }
+        if !self.metric.is_empty() {
+            retrieval.metric =
+                Metric::from_name(&self.metric).ok_or_else(|| "Unknown metric.".to_string())?;
+        }
+        if !self.threshold.is_empty() {
+            retrieval.threshold = match self.threshold.parse::<f64>() {
+                Ok(threshold) if threshold.is_finite() => threshold,
+                _ => return Err("Threshold must be a number.".to_string()),
+            };
+        }
+        if !self.max_context_tokens.is_empty() {
+            retrieval.max_context_tokens = match self.max_context_tokens.parse() {
+                Ok(tokens) if (1..=100_000).contains(&tokens) => tokens,
+                _ => {
+                    return Err(
+                        "Maximum context length must be a number between 1 and 100000.".to_string(),
+                    )
+                }
+            };
+        }
+
+        if !self.expansion.is_empty() {
+            retrieval.expansion.mode = ExpansionMode::from_name(&self.expansion)
+                .ok_or_else(|| "Unknown query expansion.".to_string())?;
+        }
+
+        Ok(retrieval)
+    }
 }
 
 pub async fn query_post(
@@ -43,16 +352,43 @@ pub async fn query_post(
     State(state): State<AppState>,
     HxRequest(hx_request): HxRequest,
     Form(form): Form<QueryForm>,
-) -> Html<String> {
+) -> Response {
     let query_page = Query::new(&state, &user_session)
         .with_selected_category(&form.category)
-        .with_query(&form.query);
+        .with_query(&form.query)
+        .with_retrieval(
+            &form.top_k,
+            &form.metric,
+            &form.threshold,
+            &form.max_context_tokens,
+            &form.expansion,
+        );
+
+    let retrieval = match form.retrieval(&state.config.backend.retrieval) {
+        Ok(retrieval) => retrieval,
+        Err(message) => {
+            return query_page
+                .with_query_failure(&message)
+                .page_rendered(hx_request)
+                .await
+                .into_response()
+        }
+    };
+
+    if form.query.trim().is_empty() {
+        return query_page
+            .with_query_failure("Query is empty")
+            .page_rendered(hx_request)
+            .await
+            .into_response();
+    }
 
     if form.query.len() > 1024 {
         return query_page
             .with_query_failure("Query too long")
             .page_rendered(hx_request)
-            .await;
+            .await
+            .into_response();
     }
 
     let credit = sqlx::query_file!("queries/account/credit.sql", user_session.id(),)
@@ -67,53 +403,205 @@ pub async fn query_post(
                 "You've run out of credits. Reach out to hexane@unfla.me for additional credits.",
             )
             .page_rendered(hx_request)
-            .await;
+            .await
+            .into_response();
+    }
+
+    let thread_id = match Uuid::parse_str(&form.thread) {
+        Ok(thread_id) if thread_exists(&state, thread_id, user_session.id()).await => {
+            Some(thread_id)
+        }
+        _ if form.thread.is_empty() => None,
+        _ => {
+            return query_page
+                .with_query_failure("Thread not found.")
+                .page_rendered(hx_request)
+                .await
+                .into_response()
+        }
+    };
+    let query_page = match thread_id {
+        Some(thread_id) => query_page.with_thread(thread_id),
+        None => query_page,
+    };
+
+    let history = match thread_id {
+        Some(thread_id) => history(&state, thread_id).await,
+        None => vec![],
+    };
+
+    // Follow-ups like "what about the second one?" retrieve nothing by
+    // themselves, the context is retrieved for the standalone question.
+    let mut question = form.query.clone();
+    if !history.is_empty() && state.config.backend.chat.condense {
+        match condense_query(&state.chat, &history, &form.query).await {
+            Ok(condensed) => {
+                charge(&state, user_session.id(), condensed.cost).await;
+                question = condensed.content;
+            }
+            Err(err) => tracing::warn!("condensing the question failed: {}", err),
+        }
+    }
+
+    // Expanded queries are retrieved along with the query, their results are
+    // fused with reciprocal rank fusion. The expansion is charged even if
+    // it's of no help.
+    let mut queries = vec![process_query(&question, &state.stop_words)];
+    if retrieval.expansion.mode != ExpansionMode::None {
+        match expand_query(
+            &state.chat,
+            &question,
+            retrieval.expansion.mode,
+            retrieval.expansion.queries,
+        )
+        .await
+        {
+            Ok(expanded) => {
+                charge(&state, user_session.id(), expanded.cost).await;
+                queries.extend(expanded.queries);
+            }
+            Err(err) => tracing::warn!("query expansion failed, using the query as is: {}", err),
+        }
     }
 
-    let query_processed = process_query(&form.query, &state.stop_words);
-    let query_embedding = &get_embeddings(
-        json!(&query_processed),
-        &state.config,
+    let query_embeddings = match get_embeddings(
+        &state.embedder,
+        &queries,
         &user_session.id(),
         &mut state.pool.acquire().await.unwrap(),
     )
-    .await[0];
+    .await
+    {
+        Ok(embeddings) => embeddings,
+        Err(err) => {
+            tracing::error!("embedding query: {}", err);
+            return query_page
+                .with_query_failure("Failed to process the query, please try again later.")
+                .page_rendered(hx_request)
+                .await
+                .into_response();
+        }
+    };
 
-    let sql_query = format!(
-        "
-SELECT text, file.name
-FROM datasource.embedding JOIN datasource.file ON file.id = embedding.file_id
-WHERE file.user_id = $1
-  AND embedding.created = file.processed
-  {}
-  AND (embedding <-> $2::vector) < 1.20
-ORDER BY (embedding <-> $2::vector)
-LIMIT 5;",
-        if form.category.is_empty() {
-            ""
+    // Reranking & MMR select top_k of a wider set of candidates.
+    let mut limit = retrieval.top_k;
+    if retrieval.mmr.diversity > 0.0 {
+        limit = limit.max(retrieval.mmr.candidates);
+    }
+    if let Some(reranker) = &state.reranker {
+        limit = limit.max(reranker.candidates());
+    }
+
+    let mut runs: Vec<Vec<QueryReferences>> = vec![];
+    for (idx, embedding) in query_embeddings.into_iter().enumerate() {
+        // Keywords are only searched for the user's query.
+        let keywords = if idx == 0 {
+            keyword_query(&question, &state.stop_words)
         } else {
-            "AND category = $3"
+            String::new()
+        };
+
+        match retrieve(
+            &state,
+            &retrieval,
+            user_session.id(),
+            &form.category,
+            embedding,
+            &keywords,
+            limit,
+        )
+        .await
+        {
+            Ok(run) => runs.push(run),
+            Err(err) => {
+                tracing::error!("retrieving context: {}", err);
+                return query_page
+                    .with_query_failure("Failed to process the query, please try again later.")
+                    .page_rendered(hx_request)
+                    .await
+                    .into_response();
+            }
         }
-    );
+    }
 
-    let mut query_builder = sqlx::query(&sql_query)
-        .bind(user_session.id())
-        .bind(query_embedding);
+    let mut context_vec: Vec<QueryReferences> = if runs.len() == 1 {
+        runs.remove(0)
+    } else {
+        let mut fused: HashMap<Uuid, QueryReferences> = HashMap::new();
+        for run in runs {
+            for (rank, mut r) in run.into_iter().enumerate() {
+                let score = 1.0 / (retrieval.hybrid.rrf_k + rank as f64 + 1.0);
+                fused
+                    .entry(r.id)
+                    .and_modify(|fused| fused.score += score)
+                    .or_insert_with(|| {
+                        r.score = score;
+                        r
+                    });
+            }
+        }
 
-    if !form.category.is_empty() {
-        query_builder = query_builder.bind(form.category);
+        let mut fused = fused.into_values().collect::<Vec<QueryReferences>>();
+        fused.sort_by(|a, b| b.score.total_cmp(&a.score));
+        fused.truncate(limit as usize);
+        fused
+    };
+
+    // Candidates are scored against the query as asked, the fused rank is
+    // kept if the reranker fails.
+    if let Some(reranker) = state.reranker.as_ref().filter(|_| context_vec.len() > 1) {
+        match rerank(
+            reranker,
+            &question,
+            &context_vec
+                .iter()
+                .map(QueryReferences::context)
+                .collect::<Vec<String>>(),
+            &user_session.id(),
+            &mut state.pool.acquire().await.unwrap(),
+        )
+        .await
+        {
+            Ok(scores) => {
+                for (r, score) in context_vec.iter_mut().zip(scores) {
+                    r.score = score;
+                }
+                context_vec.sort_by(|a, b| b.score.total_cmp(&a.score));
+            }
+            Err(err) => tracing::warn!("reranking failed, keeping the fused rank: {}", err),
+        }
     }
 
-    let context_vec: Vec<QueryReferences> = match query_builder.fetch_all(&state.pool).await {
-        Ok(rows) => rows
-            .iter()
-            .map(|r| QueryReferences {
-                file: r.try_get::<String, _>("name").unwrap(),
-                text: r.try_get::<String, _>("text").unwrap(),
-            })
-            .collect(),
-        Err(err) => panic!("{}", err),
-    };
+    if retrieval.mmr.diversity > 0.0 {
+        let selected = mmr(
+            &context_vec.iter().map(|r| r.score).collect::<Vec<f64>>(),
+            &context_vec
+                .iter()
+                .map(|r| r.embedding.as_slice())
+                .collect::<Vec<&[f32]>>(),
+            retrieval.top_k as usize,
+            retrieval.mmr.diversity,
+        );
+
+        let mut candidates = context_vec.into_iter().map(Some).collect::<Vec<_>>();
+        context_vec = selected
+            .into_iter()
+            .filter_map(|idx| candidates[idx].take())
+            .collect();
+    } else {
+        context_vec.truncate(retrieval.top_k as usize);
+    }
+
+    // Chunks are kept in order as long as they fit in the context.
+    let mut context_tokens = 0;
+    context_vec.retain(|r| {
+        let tokens = state.chat.tokenizer().encode_ordinary(&r.context()).len();
+        if context_tokens + tokens > retrieval.max_context_tokens {
+            return false;
+        }
+        context_tokens += tokens;
+        true
+    });
 
     // If we don't have any data from the context then we cannot answer this
     // query.
@@ -123,118 +611,314 @@ LIMIT 5;",
         return query_page
             .with_query_failure(message)
             .page_rendered(hx_request)
-            .await;
+            .await
+            .into_response();
     }
 
     let context: String = context_vec
         .iter()
-        .map(|r| format!("filename: {}\n{}", r.file, r.text))
+        .map(QueryReferences::context)
         .collect::<Vec<String>>()
         .join("\n\n");
 
-    let mut body_params = json!({
-        "messages": [
-            {
-                "role": "system",
-                "content": &state.config.backend.system_prompt
-            },
-            {
-                "role": "system",
-                "content": format!("CONTEXT:\n{}", context)
-            },
-            {
-                "role": "user",
-                "content": &form.query
-            }
-        ]
-    });
-    merge_json(&mut body_params, &state.config.chat_completion.body_param);
+    let mut messages = vec![
+        json!({
+            "role": "system",
+            "content": &state.config.backend.system_prompt
+        }),
+        json!({
+            "role": "system",
+            "content": format!("CONTEXT:\n{}", context)
+        }),
+    ];
+    messages.extend(history);
+    messages.push(json!({
+        "role": "user",
+        "content": &form.query
+    }));
 
-    let model_start = Instant::now();
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(30))
-        .connect_timeout(Duration::from_secs(10))
-        .build()
-        .unwrap();
+    let sources = sources(&context_vec);
 
-    let model_response = client
-        .post(&state.config.chat_completion.api)
-        .header(
-            "Authorization",
-            format!("Bearer {}", &state.config.chat_completion.key),
-        )
-        .json(&body_params)
-        .send()
-        .await;
+    // The answer is streamed over server-sent events from /query/stream when
+    // the page can receive it. A new thread gets its id now but is only
+    // created with its first turn, once the answer is complete.
+    if hx_request && state.config.backend.chat.stream {
+        let new_thread = thread_id.is_none();
+        let thread_id = thread_id.unwrap_or_else(Uuid::new_v4);
 
-    let model_response = match model_response {
-        Ok(response) => match response.json::<serde_json::Value>().await {
-            Ok(body) => Some(body),
-            Err(_) => None,
-        },
-        Err(_) => None,
-    };
+        let stream_id = Uuid::new_v4();
+        {
+            let mut streams = state.streams.lock().unwrap();
+            // Streams that were never opened are dropped.
+            streams.retain(|_, pending| pending.created.elapsed() < Duration::from_secs(300));
+            streams.insert(
+                stream_id,
+                PendingAnswer {
+                    user_id: user_session.id(),
+                    thread_id,
+                    new_thread,
+                    question: form.query.clone(),
+                    messages,
+                    sources,
+                    created: Instant::now(),
+                },
+            );
+        }
 
-    if model_response.is_none() {
-        return query_page
-            .with_query_failure("Failed to generate a response.")
+        let page = query_page
+            .with_thread(thread_id)
+            .with_query("")
+            .with_pending(json!({
+                "TEMPLATE": "pages/query/thread-stream",
+                "question": &form.query,
+                "stream": format!("/query/stream/{}", stream_id)
+            }))
             .page_rendered(hx_request)
             .await;
+
+        return if new_thread {
+            ([("HX-Push-Url", format!("/query/{}", thread_id))], page).into_response()
+        } else {
+            page.into_response()
+        };
+    }
+
+    let model_start = Instant::now();
+    let completion = match state.chat.complete(&messages).await {
+        Ok(completion) => completion,
+        Err(err) => {
+            tracing::error!("generating the answer: {}", err);
+            return query_page
+                .with_query_failure("Failed to generate a response.")
+                .page_rendered(hx_request)
+                .await
+                .into_response();
+        }
+    };
+
+    charge(&state, user_session.id(), completion.cost).await;
+
+    let new_thread = thread_id.is_none();
+    let thread_id = thread_id.unwrap_or_else(Uuid::new_v4);
+    if new_thread {
+        create_thread(&state, thread_id, user_session.id(), &form.query).await;
     }
+    store_turn(
+        &state,
+        thread_id,
+        &form.query,
+        &completion.content,
+        &sources,
+    )
+    .await;
 
-    let res = model_response.unwrap();
-    let usage = &res["usage"];
-    let message = res["choices"][0]["message"]["content"]
-        .to_string()
-        .replace("\\n", "\n");
+    // The question & answer are shown with the thread.
+    let query_response = usage_status(model_start.elapsed().as_secs(), &completion.usage);
 
-    let pricing = &state.config.chat_completion.pricing;
+    let page = query_page
+        .with_thread(thread_id)
+        .with_query("")
+        .with_query_response(query_response)
+        .page_rendered(hx_request)
+        .await;
 
-    let total_cost = (usage["prompt_tokens"].as_f64().unwrap() * pricing.input)
-        + (usage["completion_tokens"].as_f64().unwrap() * pricing.output);
-    let total_cost = total_cost / 1000.0;
+    if new_thread && hx_request {
+        ([("HX-Push-Url", format!("/query/{}", thread_id))], page).into_response()
+    } else {
+        page.into_response()
+    }
+}
 
-    sqlx::query_file!(
-        "queries/account/credit-decrement.sql",
-        user_session.id(),
-        BigDecimal::try_from(total_cost).unwrap()
+/// PendingAnswer is a question whose context was retrieved, waiting for its
+/// answer to be streamed.
+pub struct PendingAnswer {
+    user_id: Uuid,
+    thread_id: Uuid,
+    /// The thread is created along with the turn.
+    new_thread: bool,
+    question: String,
+    messages: Vec<Value>,
+    sources: Vec<String>,
+    created: Instant,
+}
+
+/// stream relays the answer of a pending question as server-sent events:
+/// "token" events with the text as it's generated, then a "done" event
+/// with the references & token usage, or a "failed" event. The turn (& a new
+/// thread) is stored & charged once the answer is complete, an answer cut
+/// short is charged but not stored.
+pub async fn stream(
+    user_session: UserSession,
+    State(state): State<AppState>,
+    Path(stream_id): Path<Uuid>,
+) -> Response {
+    let pending = state.streams.lock().unwrap().remove(&stream_id);
+    let pending = match pending {
+        Some(pending) if pending.user_id == user_session.id() => pending,
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let (tx, rx) = mpsc::channel::<Event>(64);
+    tokio::spawn(async move {
+        if let Err(err) = stream_answer(&state, pending, &tx).await {
+            tracing::error!("streaming answer: {}", err);
+            let failure = state
+                .pages
+                .render(state.pages.status_failed("Failed to generate a response."));
+            let _ = tx
+                .send(Event::default().event("failed").data(failure.0))
+                .await;
+        }
+    });
+
+    Sse::new(stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|event| (Ok::<Event, Infallible>(event), rx))
+    }))
+    .keep_alive(KeepAlive::default())
+    .into_response()
+}
+
+async fn stream_answer(
+    state: &AppState,
+    pending: PendingAnswer,
+    tx: &mpsc::Sender<Event>,
+) -> Result<(), String> {
+    let model_start = Instant::now();
+    let mut stream = state.chat.stream(&pending.messages).await?;
+
+    let mut answer = String::new();
+    let mut carriage_return = false;
+    loop {
+        let token = match stream.next().await {
+            Ok(Some(token)) => normalize_newlines(&token, &mut carriage_return),
+            Ok(None) => break,
+            // The tokens generated until the stream broke are charged, the
+            // half written answer isn't stored.
+            Err(err) => {
+                let completion = state
+                    .chat
+                    .completion(&pending.messages, answer, stream.usage());
+                charge(state, pending.user_id, completion.cost).await;
+                return Err(err);
+            }
+        };
+
+        answer.push_str(&token);
+        // The page is gone if sending fails, the answer is still completed
+        // & stored.
+        let _ = tx.send(Event::default().event("token").data(&token)).await;
+    }
+
+    // Servers that don't report usage on streams are billed for our count.
+    let completion = state
+        .chat
+        .completion(&pending.messages, answer, stream.usage());
+
+    charge(state, pending.user_id, completion.cost).await;
+    if pending.new_thread {
+        create_thread(state, pending.thread_id, pending.user_id, &pending.question).await;
+    }
+    store_turn(
+        state,
+        pending.thread_id,
+        &pending.question,
+        &completion.content,
+        &pending.sources,
     )
-    .execute(&state.pool)
-    .await
-    .unwrap();
+    .await;
 
-    let references = {
-        let items = context_vec
-            .iter()
-            .map(|r| &r.file)
-            .collect::<HashSet<&String>>()
-            .iter()
-            .map(|file| {
-                json!({
-                    "TEMPLATE": "html/li",
-                    "text": file
-                })
-            })
-            .collect::<Vec<Value>>();
+    let done = state.pages.render(json!({
+        "TEMPLATE": "pages/query/thread-stream-done",
+        "references": references(&pending.sources),
+        "status": usage_status(model_start.elapsed().as_secs(), &completion.usage)
+    }));
+    let _ = tx.send(Event::default().event("done").data(done.0)).await;
 
-        json!({
-            "TEMPLATE": "pages/query/query-response-references",
-            "items": items
-        })
+    Ok(())
+}
+
+/// normalize_newlines replaces \r\n & \r with \n, event data can't hold
+/// carriage returns. carriage_return tells whether the previous token ended
+/// with one, for a \r\n split across tokens.
+fn normalize_newlines(token: &str, carriage_return: &mut bool) -> String {
+    let token = match token.strip_prefix('\n') {
+        Some(rest) if *carriage_return => rest,
+        _ => token,
     };
+    *carriage_return = token.ends_with('\r');
+    token.replace("\r\n", "\n").replace('\r', "\n")
+}
 
-    let query_response = json!({
+/// References of the chunks in the order they were given as context.
+fn sources(context_vec: &[QueryReferences]) -> Vec<String> {
+    let mut sources: Vec<String> = vec![];
+    for r in context_vec {
+        let mut reference = r.file.clone();
+        if let Some(pages) = r.pages() {
+            reference.push_str(&format!(", {}", pages));
+        }
+        if let Some(section) = &r.section {
+            reference.push_str(&format!(" ({})", section));
+        }
+        if !sources.contains(&reference) {
+            sources.push(reference);
+        }
+    }
+    sources
+}
+
+fn usage_status(response_time: u64, usage: &Usage) -> Value {
+    json!({
         "TEMPLATE": "pages/query/query-response",
-        "response-time": model_start.elapsed().as_secs(),
-        "references": references,
-        "tokens-total": usage["total_tokens"],
-        "tokens-prompt": usage["prompt_tokens"],
-        "tokens-completion": usage["completion_tokens"],
-        "response": message[1..message.len() - 1].to_string(),
-    });
+        "response-time": response_time,
+        "tokens-total": usage.total_tokens(),
+        "tokens-prompt": usage.prompt_tokens,
+        "tokens-completion": usage.completion_tokens,
+    })
+}
 
-    query_page
-        .with_query_response(query_response)
-        .page_rendered(hx_request)
+async fn create_thread(state: &AppState, thread_id: Uuid, user_id: Uuid, question: &str) {
+    let title = question.trim().chars().take(128).collect::<String>();
+    sqlx::query_file!("queries/chat/insert-thread.sql", thread_id, user_id, title)
+        .execute(&state.pool)
         .await
+        .unwrap();
+}
+
+async fn store_turn(
+    state: &AppState,
+    thread_id: Uuid,
+    question: &str,
+    answer: &str,
+    sources: &[String],
+) {
+    sqlx::query_file!(
+        "queries/chat/insert-turn.sql",
+        thread_id,
+        question,
+        state.chat.tokenizer().encode_ordinary(question).len() as i32,
+        answer,
+        state.chat.tokenizer().encode_ordinary(answer).len() as i32,
+        sources
+    )
+    .execute(&state.pool)
+    .await
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newlines_are_normalized() {
+        let mut carriage_return = false;
+        let tokens = ["a\r\nb", "\rc\r", "\nd", "\ne"]
+            .iter()
+            .map(|token| normalize_newlines(token, &mut carriage_return))
+            .collect::<String>();
+        assert_eq!(tokens, "a\nb\nc\nd\ne");
+    }
 }
@@ -1,7 +1,10 @@
 use clap::Parser;
 use sqlx::postgres::PgPoolOptions;
-use std::sync::Arc;
-use std::{collections::HashSet, path::PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
 use template_nest::{TemplateNest, TemplateNestOption};
 use tokio::signal;
 use tokio_util::sync::CancellationToken;
@@ -9,12 +12,14 @@ use tower_sessions::ExpiredDeletion;
 use tower_sessions_sqlx_store::PostgresStore;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use hexane_shared::Config;
+use hexane_file_processor::extractor::Registry;
+use hexane_shared::{chat::ChatModel, embedding::Embedder, rerank::Reranker, Config};
 
 mod app;
 mod handlers;
 mod middlewares;
 mod pages;
+mod retrieval;
 mod types;
 
 use crate::pages::Pages;
@@ -79,9 +84,25 @@ async fn main() {
     })
     .expect("failed to create nest object");
 
+    let embedder = Embedder::new(&config.embedding)
+        .unwrap_or_else(|err| panic!("setting up embedding provider: {}", err));
+
+    let reranker =
+        Reranker::new(&config).unwrap_or_else(|err| panic!("setting up reranker: {}", err));
+
+    let extractors = Registry::from_config(&config.file_processor);
+
+    let chat = ChatModel::new(&config.chat_completion)
+        .unwrap_or_else(|err| panic!("setting up chat completion provider: {}", err));
+
     let state = AppState {
         config: Arc::new(config),
         stop_words: Arc::new(stop_words),
+        extractors: Arc::new(extractors),
+        embedder: Arc::new(embedder),
+        reranker: reranker.map(Arc::new),
+        chat: Arc::new(chat),
+        streams: Arc::new(Mutex::new(HashMap::new())),
         pool: pool.clone(),
         pages: Arc::new(Pages { nest }),
     };
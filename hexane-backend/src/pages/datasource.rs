@@ -10,6 +10,10 @@ struct DatasourceFile {
     processed: Option<String>,
     hash: String,
     size: i64,
+    warnings: Vec<String>,
+    attempts: i32,
+    last_error: Option<String>,
+    failed: bool,
 }
 
 pub struct Datasource {
@@ -68,15 +72,25 @@ impl Datasource {
             ""
         };
 
+        let supported_types = self
+            .state
+            .extractors
+            .extractors()
+            .map(|extractor| extractor.description())
+            .collect::<Vec<&str>>()
+            .join(", ");
+
         json!({
             "body-main": {
                 "TEMPLATE": "pages/datasource",
+                "supported-types": supported_types,
                 "status": self.status,
                 "file-list": file_list,
                 "category-options": categories,
                 "upload-form": {
                     "TEMPLATE": "pages/datasource/upload-form",
                     "usage-limits": usage_limits,
+                    "accept": self.state.extractors.accept(),
                     "class": form_class
                 }
             }
@@ -99,14 +113,58 @@ impl Datasource {
 
         let total_size: i64 = datasources.iter().map(|x| x.size).sum();
         let processed_file_count = datasources.iter().filter(|x| x.processed.is_some()).count();
+        let failed_file_count = datasources.iter().filter(|x| x.failed).count();
+
+        // Chunks embedded with another model are only found by keywords until
+        // they're re-embedded.
+        let stale_embedding_count = sqlx::query_file!(
+            "queries/datasource/stale-embedding-count.sql",
+            self.user_id,
+            self.state.embedder.model()
+        )
+        .fetch_one(&self.state.pool)
+        .await
+        .unwrap()
+        .count;
 
         let files = datasources
             .iter()
             .map(|x| {
-                let (processed, class) = match &x.processed {
-                    Some(processed) => (processed.as_str(), ""),
-                    None => ("Not Processed", "datasource-file-unprocessed"),
+                let (processed, class) = match (&x.processed, x.failed) {
+                    (Some(processed), _) => (processed.to_string(), ""),
+                    (None, true) => ("Failed".to_string(), "datasource-file-failed"),
+                    (None, false) if x.attempts > 0 => (
+                        format!("Not Processed (attempt {} failed)", x.attempts),
+                        "datasource-file-unprocessed",
+                    ),
+                    (None, false) => ("Not Processed".to_string(), "datasource-file-unprocessed"),
+                };
+
+                // Errors are only shown while the file isn't processed.
+                let error = match (&x.processed, &x.last_error) {
+                    (None, Some(error)) => Some(json!({
+                        "TEMPLATE": "pages/datasource/file-error",
+                        "text": error
+                    })),
+                    _ => None,
                 };
+                let retry = x.failed.then(|| {
+                    json!({
+                        "TEMPLATE": "pages/datasource/file-retry",
+                        "hash": x.hash
+                    })
+                });
+
+                let warnings = x
+                    .warnings
+                    .iter()
+                    .map(|warning| {
+                        json!({
+                            "TEMPLATE": "html/li",
+                            "text": warning
+                        })
+                    })
+                    .collect::<Vec<Value>>();
 
                 json!({
                     "TEMPLATE": "pages/datasource/file-list-entry",
@@ -114,7 +172,13 @@ impl Datasource {
                     "name": x.name,
                     "hash": x.hash,
                     "category": x.category,
-                    "processed": processed
+                    "processed": processed,
+                    "error": error,
+                    "retry": retry,
+                    "warnings": (!warnings.is_empty()).then(|| json!({
+                        "TEMPLATE": "pages/datasource/file-warnings",
+                        "items": warnings
+                    }))
                 })
             })
             .collect::<Vec<Value>>();
@@ -125,6 +189,14 @@ impl Datasource {
             "total-size": human_bytes(total_size as f64),
             "total-file-count": datasources.len(),
             "processed-file-count": processed_file_count,
+            "failed-file-count": (failed_file_count > 0).then(|| json!({
+                "TEMPLATE": "pages/datasource/file-list-failed",
+                "count": failed_file_count
+            })),
+            "stale-embedding-count": (stale_embedding_count > 0).then(|| json!({
+                "TEMPLATE": "pages/datasource/file-list-stale",
+                "count": stale_embedding_count
+            })),
         })
     }
 }
@@ -1,5 +1,6 @@
 use crate::types::{AppState, UserSession};
 use axum::response::Html;
+use hexane_shared::{ExpansionMode, Metric};
 use serde_json::{json, Value};
 use uuid::Uuid;
 
@@ -9,6 +10,9 @@ pub struct Query {
     query: Option<String>,
     query_response: Option<Value>,
     category: Option<String>,
+    retrieval: Option<Value>,
+    thread: Option<Uuid>,
+    pending: Option<Value>,
 }
 
 impl Query {
@@ -19,6 +23,9 @@ impl Query {
             query: None,
             query_response: None,
             category: None,
+            retrieval: None,
+            thread: None,
+            pending: None,
         }
     }
 
@@ -41,6 +48,36 @@ impl Query {
         self
     }
 
+    /// Retrieval overrides entered in the query form.
+    pub fn with_retrieval(
+        mut self,
+        top_k: &str,
+        metric: &str,
+        threshold: &str,
+        max_context_tokens: &str,
+        expansion: &str,
+    ) -> Query {
+        self.retrieval = Some(json!({
+            "top-k": top_k,
+            "metric": metric,
+            "threshold": threshold,
+            "max-context-tokens": max_context_tokens,
+            "expansion": expansion,
+        }));
+        self
+    }
+
+    pub fn with_thread(mut self, thread: Uuid) -> Query {
+        self.thread = Some(thread);
+        self
+    }
+
+    /// Question of the thread whose answer is being streamed.
+    pub fn with_pending(mut self, pending: Value) -> Query {
+        self.pending = Some(pending);
+        self
+    }
+
     pub fn with_selected_category(mut self, category: &str) -> Query {
         self.category = Some(category.to_string());
         self
@@ -49,6 +86,7 @@ impl Query {
     pub async fn page(&self) -> Value {
         json!({
             "TEMPLATE": "pages/query",
+            "threads": self.threads().await,
             "query-form": self.query_form().await
         })
     }
@@ -62,15 +100,114 @@ impl Query {
     }
 
     pub async fn query_form(&self) -> Value {
+        let retrieval = &self.state.config.backend.retrieval;
+        let value = |name: &str| self.retrieval.as_ref().map(|values| values[name].clone());
+
         json!({
             "TEMPLATE": "pages/query/query-form",
             "query": self.query,
             "query-response": self.query_response,
-            "category-options": self.categories().await
-
+            "thread": self.thread,
+            "messages": self.messages().await,
+            "pending": self.pending,
+            "category-options": self.categories().await,
+            "top-k": value("top-k"),
+            "top-k-default": retrieval.top_k,
+            "metric-options": self.metrics(),
+            "threshold": value("threshold"),
+            "threshold-default": retrieval.threshold,
+            "max-context-tokens": value("max-context-tokens"),
+            "max-context-tokens-default": retrieval.max_context_tokens,
+            "expansion-options": self.expansions(),
         })
     }
 
+    async fn threads(&self) -> Vec<Value> {
+        sqlx::query_file!("queries/chat/threads.sql", self.user_id)
+            .fetch_all(&self.state.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|thread| {
+                json!({
+                    "TEMPLATE": "html/li-anchor",
+                    "href": format!("/query/{}", thread.id),
+                    "title": &thread.title
+                })
+            })
+            .collect::<Vec<Value>>()
+    }
+
+    async fn messages(&self) -> Vec<Value> {
+        let Some(thread) = self.thread else {
+            return vec![];
+        };
+
+        sqlx::query_file!("queries/chat/messages.sql", thread)
+            .fetch_all(&self.state.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|message| match message.role.as_str() {
+                "user" => json!({
+                    "TEMPLATE": "pages/query/thread-question",
+                    "content": &message.content
+                }),
+                _ => json!({
+                    "TEMPLATE": "pages/query/thread-answer",
+                    "content": &message.content,
+                    "references": references(&message.sources)
+                }),
+            })
+            .collect::<Vec<Value>>()
+    }
+
+    fn expansions(&self) -> Vec<Value> {
+        let selected = self
+            .retrieval
+            .as_ref()
+            .and_then(|values| values["expansion"].as_str())
+            .and_then(ExpansionMode::from_name);
+
+        ExpansionMode::ALL
+            .iter()
+            .map(|mode| {
+                json!({
+                    "TEMPLATE": "html/option",
+                    "attributes": if Some(*mode) == selected { "selected" } else { "" },
+                    "value": mode.name()
+                })
+            })
+            .collect::<Vec<Value>>()
+    }
+
+    fn metrics(&self) -> Vec<Value> {
+        let selected = self
+            .retrieval
+            .as_ref()
+            .and_then(|values| values["metric"].as_str())
+            .and_then(Metric::from_name);
+
+        // Only the configured metric has an index, the others scan every
+        // chunk of the model.
+        let indexed = self.state.config.backend.retrieval.metric;
+        Metric::ALL
+            .iter()
+            .map(|metric| {
+                json!({
+                    "TEMPLATE": "pages/query/metric-option",
+                    "attributes": if Some(*metric) == selected { "selected" } else { "" },
+                    "value": metric.name(),
+                    "label": if *metric == indexed {
+                        metric.name().to_string()
+                    } else {
+                        format!("{} (not indexed, slow)", metric.name())
+                    }
+                })
+            })
+            .collect::<Vec<Value>>()
+    }
+
     async fn categories(&self) -> Vec<Value> {
         let rows = sqlx::query_file!("queries/datasource/select-categories.sql", self.user_id)
             .fetch_all(&self.state.pool)
@@ -93,3 +230,14 @@ impl Query {
             .collect::<Vec<Value>>()
     }
 }
+
+/// References list of an answer.
+pub fn references(sources: &[String]) -> Value {
+    json!({
+        "TEMPLATE": "pages/query/query-response-references",
+        "items": sources
+            .iter()
+            .map(|source| json!({ "TEMPLATE": "html/li", "text": source }))
+            .collect::<Vec<Value>>()
+    })
+}
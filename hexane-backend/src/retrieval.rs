@@ -0,0 +1,240 @@
+use serde_json::{json, Value};
+
+use hexane_shared::chat::{ChatModel, Completion};
+use hexane_shared::ExpansionMode;
+
+/// Queries generated from the user's query along with the cost of
+/// generating them.
+pub struct Expanded {
+    pub queries: Vec<String>,
+    pub cost: f64,
+}
+
+/// expand_query asks the chat completion model for reformulations of the
+/// query (rewrite) or for a hypothetical answer to embed (hyde).
+pub async fn expand_query(
+    chat: &ChatModel,
+    query: &str,
+    mode: ExpansionMode,
+    queries: usize,
+) -> Result<Expanded, String> {
+    let prompt = match mode {
+        ExpansionMode::None => {
+            return Ok(Expanded {
+                queries: vec![],
+                cost: 0.0,
+            })
+        }
+        ExpansionMode::Rewrite => format!(
+            "Rewrite the question into {} different search queries that would find the \
+             documents answering it. Make vague references explicit & use the terms the \
+             documents would use. Reply with one query per line & nothing else.",
+            queries
+        ),
+        ExpansionMode::Hyde => "Write a short passage answering the question, as it would \
+            appear in a document. If you don't know the answer, write a plausible one. Reply \
+            with the passage only."
+            .to_string(),
+    };
+
+    let completion = chat
+        .complete(&[
+            json!({ "role": "system", "content": prompt }),
+            json!({ "role": "user", "content": query }),
+        ])
+        .await?;
+
+    let expanded = match mode {
+        ExpansionMode::Rewrite => query_lines(&completion.content, queries),
+        _ => vec![completion.content.trim().to_string()],
+    };
+
+    Ok(Expanded {
+        queries: expanded,
+        cost: completion.cost,
+    })
+}
+
+/// query_lines returns up to queries non-empty lines of the completion,
+/// without the numbers or bullets models tend to prefix them with.
+fn query_lines(content: &str, queries: usize) -> Vec<String> {
+    content
+        .lines()
+        .map(strip_list_marker)
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .take(queries)
+        .map(|line| line.to_string())
+        .collect()
+}
+
+/// strip_list_marker removes a leading list marker, "1." or "2)" numbering
+/// or a "-", "*" or "•" bullet followed by a space. Other leading numbers
+/// are part of the query ("2024 budget", "404 errors").
+fn strip_list_marker(line: &str) -> &str {
+    let line = line.trim_start();
+    let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let rest = match digits {
+        0 => line.strip_prefix(['-', '*', '•']),
+        _ => line[digits..].strip_prefix(['.', ')']),
+    };
+
+    match rest {
+        Some(rest) if rest.starts_with(char::is_whitespace) => rest,
+        _ => line,
+    }
+}
+
+/// condense_query asks the chat completion model to rewrite a follow-up
+/// question as a standalone question, to retrieve the context with. history
+/// is the earlier turns as chat completion messages.
+pub async fn condense_query(
+    chat: &ChatModel,
+    history: &[Value],
+    question: &str,
+) -> Result<Completion, String> {
+    let mut messages = vec![json!({
+        "role": "system",
+        "content": "Rewrite the user's last question as a standalone question that can be \
+            understood without the conversation, it's used to search documents. Reply with \
+            the question only."
+    })];
+    messages.extend(history.iter().cloned());
+    messages.push(json!({ "role": "user", "content": question }));
+
+    let completion = chat.complete(&messages).await?;
+    Ok(Completion {
+        content: completion.content.trim().to_string(),
+        ..completion
+    })
+}
+
+/// mmr selects k of the candidates with maximal marginal relevance, returns
+/// their indices in order of selection. Relevance is min-max normalized to
+/// [0, 1], candidates left unscored (f64::MIN) count as 0 & don't widen the
+/// range. Similarity between candidates is the cosine similarity of their
+/// embeddings.
+pub fn mmr(relevance: &[f64], embeddings: &[&[f32]], k: usize, diversity: f64) -> Vec<usize> {
+    let scored = relevance.iter().filter(|r| **r > f64::MIN && r.is_finite());
+    let min_relevance = scored.clone().cloned().fold(f64::INFINITY, f64::min);
+    let max_relevance = scored.cloned().fold(f64::NEG_INFINITY, f64::max);
+    let normalized = relevance
+        .iter()
+        .map(|r| {
+            if *r == f64::MIN || !r.is_finite() {
+                0.0
+            } else if max_relevance > min_relevance {
+                (r - min_relevance) / (max_relevance - min_relevance)
+            } else {
+                1.0
+            }
+        })
+        .collect::<Vec<f64>>();
+
+    let mut selected: Vec<usize> = Vec::with_capacity(k);
+    // Highest similarity of every candidate to the selected ones.
+    let mut redundancy = vec![0.0_f64; relevance.len()];
+
+    while selected.len() < k.min(relevance.len()) {
+        let best = (0..relevance.len())
+            .filter(|idx| !selected.contains(idx))
+            .map(|idx| {
+                let score = (1.0 - diversity) * normalized[idx] - diversity * redundancy[idx];
+                (idx, score)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(idx, _)| idx)
+            .unwrap();
+
+        for (idx, redundancy) in redundancy.iter_mut().enumerate() {
+            *redundancy = redundancy.max(cosine_similarity(embeddings[best], embeddings[idx]));
+        }
+        selected.push(best);
+    }
+
+    selected
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f64 {
+    let (mut dot, mut norm_a, mut norm_b) = (0.0_f64, 0.0_f64, 0.0_f64);
+    for (x, y) in a.iter().zip(b) {
+        let (x, y) = (*x as f64, *y as f64);
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a.sqrt() * norm_b.sqrt())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_lines_strip_numbers_and_bullets() {
+        let content = "1. first query\n2) second query\n\n- third query\n* fourth query";
+        assert_eq!(
+            query_lines(content, 3),
+            vec!["first query", "second query", "third query"]
+        );
+    }
+
+    #[test]
+    fn query_lines_keep_leading_numbers() {
+        let content = "2024 budget by region\n404 errors after deploy\n3M adhesive datasheet\n\
+            1. 3.5 inch drives\n2) 10 year warranty\n-5 degrees storage\n• 2FA setup";
+        assert_eq!(
+            query_lines(content, 7),
+            vec![
+                "2024 budget by region",
+                "404 errors after deploy",
+                "3M adhesive datasheet",
+                "3.5 inch drives",
+                "10 year warranty",
+                "-5 degrees storage",
+                "2FA setup"
+            ]
+        );
+    }
+
+    #[test]
+    fn query_lines_keep_plain_lines() {
+        assert_eq!(
+            query_lines("  how to bake bread \r\n\n", 3),
+            vec!["how to bake bread"]
+        );
+    }
+
+    #[test]
+    fn mmr_orders_by_relevance_without_diversity() {
+        let embedding = [1.0_f32, 0.0];
+        let embeddings = [&embedding[..]; 3];
+
+        // Rerank scores can all be negative.
+        assert_eq!(mmr(&[-3.0, -1.0, -2.0], &embeddings, 3, 0.0), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn mmr_skips_redundant_candidates() {
+        let (a, b) = ([1.0_f32, 0.0], [0.0_f32, 1.0]);
+        let embeddings = [&a[..], &a[..], &b[..]];
+
+        assert_eq!(mmr(&[0.9, 0.8, 0.1], &embeddings, 2, 0.5), vec![0, 2]);
+    }
+
+    #[test]
+    fn mmr_unscored_candidates() {
+        let (a, b, c) = ([1.0_f32, 0.0], [0.0_f32, 1.0], [0.7_f32, 0.7]);
+        let embeddings = [&a[..], &a[..], &b[..], &c[..]];
+
+        // The unscored candidate neither widens the range nor gets picked
+        // over a scored one.
+        let selected = mmr(&[0.9, 0.8, 0.7, f64::MIN], &embeddings, 3, 0.5);
+        assert_eq!(selected, vec![0, 2, 1]);
+    }
+}
@@ -3,14 +3,16 @@ use axum::{
     extract::FromRequestParts,
     http::{request::Parts, StatusCode},
 };
-use hexane_shared::Config;
+use hexane_file_processor::extractor::Registry;
+use hexane_shared::{chat::ChatModel, embedding::Embedder, rerank::Reranker, Config};
 use serde::{Deserialize, Serialize};
 use sqlx::postgres::PgPool;
-use std::collections::HashSet;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use tower_sessions::Session;
 use uuid::Uuid;
 
+use crate::handlers::query::PendingAnswer;
 use crate::pages::Pages;
 
 /// App state for routers.
@@ -20,6 +22,12 @@ pub struct AppState {
     pub pages: Arc<Pages>,
     pub config: Arc<Config>,
     pub stop_words: Arc<HashSet<String>>,
+    pub extractors: Arc<Registry>,
+    pub embedder: Arc<Embedder>,
+    pub reranker: Option<Arc<Reranker>>,
+    pub chat: Arc<ChatModel>,
+    /// Answers waiting to be streamed, by stream id.
+    pub streams: Arc<Mutex<HashMap<Uuid, PendingAnswer>>>,
 }
 
 #[derive(Default, Clone, Debug, Deserialize, Serialize)]
@@ -0,0 +1,3 @@
+SELECT DISTINCT dimensions AS "dimensions!"
+FROM datasource.embedding
+WHERE model = $1 AND dimensions IS NOT NULL;
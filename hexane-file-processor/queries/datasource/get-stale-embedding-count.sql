@@ -0,0 +1,5 @@
+SELECT count(*) AS "count!"
+FROM datasource.embedding JOIN datasource.file ON file.id = embedding.file_id
+WHERE embedding.model IS DISTINCT FROM $1
+  AND embedding.created = file.processed
+  AND file.deleted IS NULL;
@@ -0,0 +1,7 @@
+SELECT DISTINCT file.user_id
+FROM datasource.embedding JOIN datasource.file ON file.id = embedding.file_id
+  JOIN users.account ON account.id = file.user_id
+WHERE embedding.model IS DISTINCT FROM $1
+  AND embedding.created = file.processed
+  AND file.deleted IS NULL
+  AND account.credit > 0;
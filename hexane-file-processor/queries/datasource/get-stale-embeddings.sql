@@ -0,0 +1,8 @@
+SELECT embedding.id, embedding.text, embedding.section, embedding.metadata
+FROM datasource.embedding JOIN datasource.file ON file.id = embedding.file_id
+WHERE embedding.model IS DISTINCT FROM $1
+  AND file.user_id = $2
+  AND embedding.created = file.processed
+  AND file.deleted IS NULL
+LIMIT $3
+FOR UPDATE OF embedding SKIP LOCKED;
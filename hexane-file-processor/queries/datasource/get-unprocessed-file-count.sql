@@ -1,4 +1,6 @@
 SELECT COUNT(id)
 FROM datasource.file
 WHERE deleted IS NULL
-  AND processed IS NULL;
+  AND processed IS NULL
+  AND failed IS NULL
+  AND (next_attempt IS NULL OR next_attempt <= now());
@@ -1,7 +1,9 @@
-SELECT id, path, type, user_id
+SELECT id, path, type, category, user_id
 FROM datasource.file
 WHERE deleted IS NULL
   AND processed IS NULL
+  AND failed IS NULL
+  AND (next_attempt IS NULL OR next_attempt <= now())
 ORDER BY created
 LIMIT 1
 FOR UPDATE SKIP LOCKED;
@@ -0,0 +1,6 @@
+SELECT class.relname AS "name!", pg_index.indisvalid AS "valid!"
+FROM pg_index
+  JOIN pg_class class ON class.oid = pg_index.indexrelid
+  JOIN pg_namespace namespace ON namespace.oid = class.relnamespace
+WHERE namespace.nspname = 'datasource'
+  AND class.relname LIKE 'datasource\_embedding\_ann\_%';
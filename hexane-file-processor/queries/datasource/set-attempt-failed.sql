@@ -0,0 +1,9 @@
+/* The retry delay ($3 seconds) doubles with every attempt, the file is marked
+   as failed after $4 attempts. */
+UPDATE datasource.file
+SET attempts = attempts + 1,
+    last_error = $2,
+    next_attempt = now() + make_interval(secs => $3 * 2 ^ attempts),
+    failed = CASE WHEN attempts + 1 >= $4 THEN now() END
+WHERE id = $1
+RETURNING attempts, failed IS NOT NULL AS "failed!";
@@ -0,0 +1,5 @@
+UPDATE datasource.embedding
+SET embedding = $2::float8[],
+    model = $3,
+    dimensions = $4
+WHERE id = $1;
@@ -1,4 +1,9 @@
-UPDATE datasource.file SET processed = now()
+UPDATE datasource.file
+SET processed = now(),
+    warnings = $2,
+    attempts = attempts + 1,
+    last_error = NULL,
+    next_attempt = NULL
 WHERE id = $1
   AND deleted IS NULL
   AND processed IS NULL
@@ -0,0 +1,879 @@
+use hexane_shared::{Chunking, ChunkingStrategy, Config};
+use std::borrow::Cow;
+use text_splitter::{Characters, TextSplitter};
+use tiktoken_rs::CoreBPE;
+
+use crate::document::{Document, Section};
+
+/// Characters per token assumed by the characters strategy, one token is
+/// roughly 4 characters of common English text.
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Columns of a layout are separated by at least this many blank characters
+/// on every line.
+const COLUMN_GAP: usize = 3;
+/// Narrower columns are more likely to be the cells of a table than columns
+/// of text, they're kept together.
+const MIN_COLUMN_WIDTH: usize = 25;
+/// Blocks with fewer lines aren't split into columns.
+const MIN_COLUMN_LINES: usize = 3;
+
+/// Blocks are separated by a blank line, so are merged sections & blocks.
+const BLOCK_SEPARATOR: &str = "\n\n";
+
+/// Chunk is a piece of a document along with its position in the text
+/// extracted from the file.
+pub struct Chunk<'a> {
+    /// Section the chunk starts in, its header & metadata apply to the chunk.
+    pub section: &'a Section,
+    /// Heading path of the chunk, that of the parent section when sibling
+    /// sections are merged into a single chunk.
+    pub heading: &'a [String],
+    pub text: Cow<'a, str>,
+    pub pages: Option<(i32, i32)>,
+    /// Character offsets of the chunk, start inclusive & end exclusive.
+    pub chars: (i32, i32),
+}
+
+impl Chunk<'_> {
+    /// Heading path joined for display, e.g. "Design > Storage".
+    pub fn heading_path(&self) -> Option<String> {
+        (!self.heading.is_empty()).then(|| self.heading.join(" > "))
+    }
+
+    /// Text that's stored, the chunk with the section header.
+    pub fn with_header(&self) -> String {
+        self.section.with_header(&self.text)
+    }
+
+    /// Text that's embedded, the chunk with its heading path, metadata &
+    /// section header.
+    pub fn with_heading(&self) -> String {
+        self.section.with_context(self.heading, &self.text)
+    }
+}
+
+/// Chunker splits the sections of a document into chunks that fit in the
+/// configured size, tokens are counted with the tokenizer of the embedding
+/// model.
+pub struct Chunker {
+    config: Chunking,
+    tokenizer: CoreBPE,
+    splitter: TextSplitter<CoreBPE>,
+}
+
+impl Chunker {
+    pub fn new(config: &Config) -> Result<Chunker, String> {
+        let chunking = config.file_processor.chunking.clone();
+        if chunking.overlap * 2 > chunking.max_tokens {
+            return Err(format!(
+                "chunk overlap ({}) is more than half of max_tokens ({})",
+                chunking.overlap, chunking.max_tokens
+            ));
+        }
+
+        let tokenizer = match chunking.tokenizer.as_deref() {
+            Some("cl100k_base") => tiktoken_rs::cl100k_base(),
+            Some("o200k_base") => tiktoken_rs::o200k_base(),
+            Some("p50k_base") => tiktoken_rs::p50k_base(),
+            Some("r50k_base") => tiktoken_rs::r50k_base(),
+            Some(tokenizer) => return Err(format!("unknown tokenizer: {}", tokenizer)),
+            // Models tiktoken doesn't know about (e.g. local models) get the
+            // tokenizer of OpenAI's embedding models, it's close enough to
+            // size chunks.
+            None => tiktoken_rs::get_bpe_from_model(&config.embedding.model)
+                .or_else(|_| tiktoken_rs::cl100k_base()),
+        }
+        .map_err(|e| format!("loading tokenizer: {}", e))?;
+
+        Ok(Chunker {
+            config: chunking,
+            splitter: TextSplitter::new(tokenizer.clone()).with_trim_chunks(true),
+            tokenizer,
+        })
+    }
+
+    pub fn count_tokens(&self, text: &str) -> usize {
+        self.tokenizer.encode_ordinary(text).len()
+    }
+
+    /// Returns the strategy used for files of the category.
+    pub fn strategy(&self, category: &str) -> ChunkingStrategy {
+        self.config
+            .categories
+            .get(category)
+            .copied()
+            .unwrap_or(self.config.strategy)
+    }
+
+    /// Splits the document into chunks with the strategy of the category.
+    /// Offsets are counted in characters over the text of all the sections,
+    /// separated by a blank line.
+    pub fn chunk<'a>(&self, document: &'a Document, category: &str) -> Vec<Chunk<'a>> {
+        let mut section_start = 0;
+        let sections = document
+            .sections
+            .iter()
+            .map(|section| {
+                let start = section_start;
+                section_start += section.text.chars().count() + 2;
+                (section, start)
+            })
+            .collect::<Vec<(&Section, usize)>>();
+
+        let strategy = self.strategy(category);
+        if let ChunkingStrategy::Structure = strategy {
+            return self.chunk_structure(&sections);
+        }
+
+        let mut chunks: Vec<Chunk> = vec![];
+        for (section, section_start) in sections {
+            // Starts & ends come in order (chunks overlap so one can't be
+            // counted from the other), characters are counted from the
+            // previous one.
+            let mut starts = CharCounter::new(&section.text, section_start);
+            let mut ends = CharCounter::new(&section.text, section_start);
+            for (start, end) in self.split(section, strategy) {
+                chunks.push(Chunk {
+                    section,
+                    heading: &section.heading,
+                    text: Cow::Borrowed(&section.text[start..end]),
+                    pages: section.page_range(start, end),
+                    chars: (starts.at(start), ends.at(end)),
+                });
+            }
+        }
+
+        chunks
+    }
+
+    /// Chunk size for the section's chunks under the heading path. Every
+    /// chunk is embedded after its heading path & the section's metadata &
+    /// header (see Section::with_context) so they're taken out of the
+    /// chunk's budget. Chunks are kept to a quarter of max_tokens at least,
+    /// even when that prefix is longer.
+    fn budget(&self, section: &Section, heading: &[String], overlap: usize) -> usize {
+        let prefix = self.count_tokens(&section.with_context(heading, ""));
+        self.config
+            .max_tokens
+            .saturating_sub(prefix + overlap)
+            .max(self.config.max_tokens / 4)
+            .max(1)
+    }
+
+    /// Returns the byte ranges of the chunks of the section for the size
+    /// based strategies, the overlap is taken out of the chunk's budget.
+    fn split(&self, section: &Section, strategy: ChunkingStrategy) -> Vec<(usize, usize)> {
+        let max = self.budget(section, &section.heading, self.config.overlap);
+
+        match strategy {
+            ChunkingStrategy::Characters => {
+                let (max, overlap) = (max * CHARS_PER_TOKEN, self.config.overlap * CHARS_PER_TOKEN);
+                let chunks = TextSplitter::new(Characters)
+                    .with_trim_chunks(true)
+                    .chunk_indices(&section.text, (max * 9 / 10)..max)
+                    .map(|(start, chunk)| (start, start + chunk.len()))
+                    .collect();
+                with_overlap(&section.text, chunks, overlap, |text| text.chars().count())
+            }
+            _ => {
+                let chunks = self
+                    .splitter
+                    .chunk_indices(&section.text, (max * 9 / 10)..max)
+                    .map(|(start, chunk)| (start, start + chunk.len()))
+                    .collect();
+                with_overlap(&section.text, chunks, self.config.overlap, |text| {
+                    self.count_tokens(text)
+                })
+            }
+        }
+    }
+
+    /// Chunks sections along their structure. Runs of small sibling
+    /// sections are merged into a single chunk under their parent heading,
+    /// other sections are chunked by blocks.
+    fn chunk_structure<'a>(&self, sections: &[(&'a Section, usize)]) -> Vec<Chunk<'a>> {
+        let mut chunks: Vec<Chunk> = vec![];
+        let mut group: Vec<(&Section, usize)> = vec![];
+        let mut group_tokens = 0;
+        let separator = self.count_tokens(BLOCK_SEPARATOR);
+
+        for (section, section_start) in sections.iter().copied() {
+            let tokens = self.count_tokens(&titled(section));
+            let budget = self.budget(section, &section.heading, 0);
+
+            // A group is embedded under the parent heading, with the
+            // metadata of its first section.
+            if group.first().is_some_and(|(first, _)| {
+                siblings(first, section)
+                    && group_tokens + separator + tokens <= self.budget(first, parent(first), 0)
+            }) {
+                group.push((section, section_start));
+                group_tokens += separator + tokens;
+                continue;
+            }
+
+            self.flush_group(&mut group, &mut chunks);
+            group.push((section, section_start));
+            group_tokens = tokens;
+
+            if tokens > budget {
+                self.flush_group(&mut group, &mut chunks);
+            }
+        }
+        self.flush_group(&mut group, &mut chunks);
+
+        chunks
+    }
+
+    fn flush_group<'a>(&self, group: &mut Vec<(&'a Section, usize)>, chunks: &mut Vec<Chunk<'a>>) {
+        match group.as_slice() {
+            [] => {}
+            [(section, section_start)] => chunks.extend(self.chunk_blocks(section, *section_start)),
+            [(first, first_start), .., (last, last_start)] => {
+                let pages = group
+                    .iter()
+                    .filter_map(|(section, _)| section.page_range(0, section.text.len()))
+                    .reduce(|a, b| (a.0.min(b.0), a.1.max(b.1)));
+
+                chunks.push(Chunk {
+                    section: first,
+                    heading: parent(first),
+                    text: Cow::Owned(
+                        group
+                            .iter()
+                            .map(|(section, _)| titled(section))
+                            .collect::<Vec<String>>()
+                            .join(BLOCK_SEPARATOR),
+                    ),
+                    pages,
+                    chars: (
+                        *first_start as i32,
+                        (last_start + last.text.chars().count()) as i32,
+                    ),
+                });
+            }
+        }
+        group.clear();
+    }
+
+    /// Chunks a section by blocks (paragraphs, list items, tables & code
+    /// blocks), consecutive blocks are merged up to the chunk size. Blocks
+    /// laid out in columns are split into their columns & blocks larger
+    /// than a chunk are split with the text splitter.
+    fn chunk_blocks<'a>(&self, section: &'a Section, section_start: usize) -> Vec<Chunk<'a>> {
+        let budget = self.budget(section, &section.heading, 0);
+
+        let mut pieces: Vec<Piece> = vec![];
+        for (start, end) in blocks(&section.text) {
+            let block = &section.text[start..end];
+            let texts: Vec<Cow<str>> = match columns(block) {
+                Some(columns) => columns.into_iter().map(Cow::Owned).collect(),
+                None => vec![Cow::Borrowed(block)],
+            };
+
+            for text in texts {
+                let tokens = self.count_tokens(&text);
+                if tokens <= budget {
+                    pieces.push(Piece {
+                        start,
+                        end,
+                        text,
+                        tokens,
+                    });
+                    continue;
+                }
+
+                for (offset, chunk) in self
+                    .splitter
+                    .chunk_indices(&text, (budget * 9 / 10)..budget)
+                {
+                    let (text, start, end) = match &text {
+                        Cow::Borrowed(_) => (
+                            Cow::Borrowed(
+                                &section.text[start + offset..start + offset + chunk.len()],
+                            ),
+                            start + offset,
+                            start + offset + chunk.len(),
+                        ),
+                        Cow::Owned(_) => (Cow::Owned(chunk.to_string()), start, end),
+                    };
+                    pieces.push(Piece {
+                        start,
+                        end,
+                        tokens: self.count_tokens(&text),
+                        text,
+                    });
+                }
+            }
+        }
+
+        let mut chunks: Vec<Chunk> = vec![];
+        let mut starts = CharCounter::new(&section.text, section_start);
+        let mut ends = CharCounter::new(&section.text, section_start);
+        let mut merged: Vec<Piece> = vec![];
+        let mut merged_tokens = 0;
+
+        let mut flush = |merged: &mut Vec<Piece<'a>>| {
+            let (Some(first), Some(last)) = (merged.first(), merged.last()) else {
+                return;
+            };
+            let (start, end) = (first.start, last.end);
+
+            // Consecutive blocks are taken as is from the section, blocks
+            // split into columns don't appear in it.
+            let text = if merged
+                .iter()
+                .all(|piece| matches!(piece.text, Cow::Borrowed(_)))
+            {
+                Cow::Borrowed(&section.text[start..end])
+            } else {
+                Cow::Owned(
+                    merged
+                        .iter()
+                        .map(|piece| piece.text.as_ref())
+                        .collect::<Vec<&str>>()
+                        .join(BLOCK_SEPARATOR),
+                )
+            };
+
+            chunks.push(Chunk {
+                section,
+                heading: &section.heading,
+                text,
+                pages: section.page_range(start, end),
+                chars: (starts.at(start), ends.at(end)),
+            });
+            merged.clear();
+        };
+
+        // Merged pieces are separated by a blank line, it's counted along
+        // with them.
+        let separator = self.count_tokens(BLOCK_SEPARATOR);
+        for piece in pieces {
+            if merged.is_empty() {
+                merged_tokens = piece.tokens;
+            } else if merged_tokens + separator + piece.tokens > budget {
+                flush(&mut merged);
+                merged_tokens = piece.tokens;
+            } else {
+                merged_tokens += separator + piece.tokens;
+            }
+            merged.push(piece);
+        }
+        flush(&mut merged);
+
+        chunks
+    }
+}
+
+/// Piece is a block of a section, or a part of it, that's merged with its
+/// neighbours into a chunk.
+struct Piece<'a> {
+    start: usize,
+    end: usize,
+    text: Cow<'a, str>,
+    tokens: usize,
+}
+
+/// Counts the characters up to byte offsets of text, offsets are expected
+/// in order.
+struct CharCounter<'a> {
+    text: &'a str,
+    byte: usize,
+    chars: usize,
+}
+
+impl<'a> CharCounter<'a> {
+    fn new(text: &'a str, start: usize) -> CharCounter<'a> {
+        CharCounter {
+            text,
+            byte: 0,
+            chars: start,
+        }
+    }
+
+    fn at(&mut self, byte: usize) -> i32 {
+        self.chars += self.text[self.byte..byte].chars().count();
+        self.byte = byte;
+        self.chars as i32
+    }
+}
+
+/// Text of the section with its own heading, used when it's merged with its
+/// siblings.
+fn titled(section: &Section) -> String {
+    match section.heading.last() {
+        Some(title) => format!("{}\n{}", title, section.text),
+        None => section.text.clone(),
+    }
+}
+
+/// Heading path of the section's parent, merged siblings sit under it.
+fn parent(section: &Section) -> &[String] {
+    &section.heading[..section.heading.len().saturating_sub(1)]
+}
+
+/// Reports whether section follows first under the same parent heading, with
+/// the same metadata. Sections with a header aren't merged.
+fn siblings(first: &Section, section: &Section) -> bool {
+    let depth = first.heading.len();
+    depth > 0
+        && section.heading.len() == depth
+        && first.heading[..depth - 1] == section.heading[..depth - 1]
+        && first.header.is_none()
+        && section.header.is_none()
+        && first.metadata == section.metadata
+}
+
+/// Returns the byte ranges of the blocks of text, runs of lines separated by
+/// blank lines. A fenced code block is a single block, blank lines in it
+/// included.
+fn blocks(text: &str) -> Vec<(usize, usize)> {
+    let mut blocks: Vec<(usize, usize)> = vec![];
+    let mut block: Option<(usize, usize)> = None;
+    let mut fenced = false;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        let (start, end) = (offset, offset + line.trim_end().len());
+        offset += line.len();
+
+        if line.trim_start().starts_with("```") {
+            fenced = !fenced;
+        }
+        if line.trim().is_empty() {
+            if !fenced {
+                blocks.extend(block.take());
+            }
+            continue;
+        }
+        block = Some((block.map_or(start, |(start, _)| start), end));
+    }
+    blocks.extend(block);
+
+    blocks
+}
+
+/// Splits a block laid out in columns, e.g. a two column page rebuilt by
+/// tesseract_tsv_to_text or pdftotext, into the text of each column read top
+/// to bottom. Returns None if the block isn't laid out in columns.
+fn columns(block: &str) -> Option<Vec<String>> {
+    let lines = block
+        .lines()
+        .map(|line| line.chars().collect())
+        .collect::<Vec<Vec<char>>>();
+    if lines.len() < MIN_COLUMN_LINES {
+        return None;
+    }
+
+    // A position is blank if no line has text at it.
+    let width = lines.iter().map(|line| line.len()).max()?;
+    let blank = (0..width)
+        .map(|idx| {
+            lines
+                .iter()
+                .all(|line| line.get(idx).is_none_or(|c| c.is_whitespace()))
+        })
+        .collect::<Vec<bool>>();
+
+    // Columns are the runs of positions between gaps.
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    let mut column_start: Option<usize> = None;
+    let mut gap = 0;
+    for (idx, blank) in blank.iter().enumerate() {
+        match (blank, column_start) {
+            (false, None) => column_start = Some(idx),
+            (false, Some(start)) if gap >= COLUMN_GAP => {
+                ranges.push((start, idx - gap));
+                column_start = Some(idx);
+            }
+            _ => {}
+        }
+        gap = if *blank { gap + 1 } else { 0 };
+    }
+    ranges.extend(column_start.map(|start| (start, width - gap)));
+
+    if ranges.len() < 2
+        || ranges
+            .iter()
+            .any(|(start, end)| end - start < MIN_COLUMN_WIDTH)
+    {
+        return None;
+    }
+
+    Some(
+        ranges
+            .iter()
+            .map(|(start, end)| {
+                lines
+                    .iter()
+                    .map(|line| {
+                        line.get(*start..(*end).min(line.len()))
+                            .unwrap_or_default()
+                            .iter()
+                            .collect::<String>()
+                            .trim()
+                            .to_string()
+                    })
+                    .filter(|line| !line.is_empty())
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            })
+            .collect(),
+    )
+}
+
+/// Moves the start of every chunk but the first back into the previous chunk
+/// so that the chunks share up to overlap units of text. The start is moved
+/// to the beginning of a word.
+fn with_overlap(
+    text: &str,
+    chunks: Vec<(usize, usize)>,
+    overlap: usize,
+    size: impl Fn(&str) -> usize,
+) -> Vec<(usize, usize)> {
+    if overlap == 0 {
+        return chunks;
+    }
+
+    let mut result: Vec<(usize, usize)> = Vec::with_capacity(chunks.len());
+    for (idx, (start, end)) in chunks.iter().enumerate() {
+        if idx == 0 {
+            result.push((*start, *end));
+            continue;
+        }
+
+        // Words of the previous chunk, the overlap grows with every word
+        // taken from its end so the first one that fits is searched for.
+        let previous = chunks[idx - 1].0;
+        let words = text[previous..*start]
+            .char_indices()
+            .zip(text[previous..*start].chars().skip(1))
+            .filter(|((_, c), next)| c.is_whitespace() && !next.is_whitespace())
+            .map(|((i, c), _)| previous + i + c.len_utf8())
+            .collect::<Vec<usize>>();
+        let fits = words.partition_point(|word| size(&text[*word..*start]) > overlap);
+
+        result.push((words.get(fits).copied().unwrap_or(*start), *end));
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunker_with(chunking: &str) -> Chunker {
+        let config: Config = toml::from_str(&format!(
+            r#"
+            file_store = "/tmp"
+
+            [backend]
+            template_directory = "/tmp"
+            resources = "/tmp"
+            stop_words = "/tmp"
+            system_prompt = ""
+
+            [file_processor]
+            max_active_process = 1
+
+            [file_processor.chunking]
+            tokenizer = "cl100k_base"
+            {}
+
+            [embedding]
+            provider = "local"
+            model = "local"
+
+            [chat_completion]
+            api = ""
+            body_param = {{}}
+            pricing = {{ input = 0.0, output = 0.0 }}
+            "#,
+            chunking
+        ))
+        .unwrap();
+        Chunker::new(&config).unwrap()
+    }
+
+    fn prose(sentences: usize) -> String {
+        (0..sentences)
+            .map(|idx| {
+                let sentence = format!(
+                    "Sentence {} is about the {} stored on shelf {}.",
+                    idx,
+                    ["jars", "knives", "spices", "plates"][idx % 4],
+                    idx * 7
+                );
+                if idx % 5 == 4 {
+                    sentence + "\n\n"
+                } else {
+                    sentence + " "
+                }
+            })
+            .collect::<String>()
+    }
+
+    /// Text of the document between character offsets, sections are
+    /// separated by a blank line.
+    fn source(document: &Document, (start, end): (i32, i32)) -> String {
+        let text = document
+            .sections
+            .iter()
+            .map(|section| section.text.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n\n");
+        text.chars()
+            .skip(start as usize)
+            .take((end - start) as usize)
+            .collect()
+    }
+
+    #[test]
+    fn chunks_fit_in_max_tokens() {
+        let chunker = chunker_with("max_tokens = 64\noverlap = 16");
+        let document = Document::from(vec![
+            Section::new(prose(120)),
+            Section {
+                header: Some("name | shelf".to_string()),
+                ..Section::new(
+                    (0..200)
+                        .map(|idx| format!("item {} | shelf {}", idx, idx % 9))
+                        .collect::<Vec<String>>()
+                        .join("\n"),
+                )
+            },
+        ]);
+
+        for config in [
+            "max_tokens = 64\noverlap = 16",
+            "max_tokens = 64\noverlap = 0\nstrategy = \"structure\"",
+        ] {
+            let chunks = chunker_with(config).chunk(&document, "default");
+            assert!(chunks.len() > 10);
+            for chunk in &chunks {
+                let tokens = chunker.count_tokens(&chunk.with_header());
+                assert!(tokens <= 64, "{}: chunk of {} tokens", config, tokens);
+            }
+        }
+    }
+
+    #[test]
+    fn embedded_text_fits_in_max_tokens() {
+        let heading = [
+            "Quarterly report of the regional distribution centres",
+            "Inventory management & stock rotation procedures",
+            "Handling of perishable goods in cold storage",
+        ]
+        .map(str::to_string)
+        .to_vec();
+        let metadata = [
+            ("from", "Warehouse Operations <operations@example.com>"),
+            ("date", "Mon, 4 Mar 2024 09:12:44 +0100"),
+            (
+                "subject",
+                "Re: Updated cold storage rotation schedule for spring",
+            ),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .to_vec();
+
+        let section = |heading: Vec<String>, text: String| Section {
+            heading,
+            metadata: metadata.clone(),
+            ..Section::new(text)
+        };
+        let mut sibling = heading.clone();
+        *sibling.last_mut().unwrap() = "Labelling of returned goods".to_string();
+        let document = Document::from(vec![
+            section(heading.clone(), prose(80)),
+            Section {
+                header: Some("name | shelf".to_string()),
+                ..section(
+                    heading.clone(),
+                    (0..100)
+                        .map(|idx| format!("item {} | shelf {}", idx, idx % 9))
+                        .collect::<Vec<String>>()
+                        .join("\n"),
+                )
+            },
+            section(heading.clone(), prose(3)),
+            section(sibling, prose(3)),
+        ]);
+
+        let chunker = chunker_with("");
+        for config in [
+            "max_tokens = 128\noverlap = 16",
+            "max_tokens = 128\noverlap = 0\nstrategy = \"structure\"",
+        ] {
+            let chunks = chunker_with(config).chunk(&document, "default");
+            assert!(chunks.len() > 5);
+            for chunk in &chunks {
+                let tokens = chunker.count_tokens(&chunk.with_heading());
+                assert!(tokens <= 128, "{}: embedded {} tokens", config, tokens);
+            }
+        }
+    }
+
+    #[test]
+    fn consecutive_chunks_share_the_overlap() {
+        let chunker = chunker_with("max_tokens = 64\noverlap = 16");
+        let document = Document::from(vec![Section::new(prose(60))]);
+        let chunks = chunker.chunk(&document, "default");
+        assert!(chunks.len() > 3);
+
+        for pair in chunks.windows(2) {
+            let (previous, chunk) = (&pair[0], &pair[1]);
+            assert!(chunk.chars.0 > previous.chars.0);
+            assert!(chunk.chars.0 < previous.chars.1);
+
+            let shared = source(&document, (chunk.chars.0, previous.chars.1));
+            assert!(previous.text.ends_with(&shared));
+            assert!(chunk.text.starts_with(&shared));
+
+            let tokens = chunker.count_tokens(&shared);
+            assert!(tokens > 0 && tokens <= 16, "overlap of {} tokens", tokens);
+        }
+
+        // Without overlap chunks follow each other.
+        let chunks = chunker_with("max_tokens = 64\noverlap = 0").chunk(&document, "default");
+        for pair in chunks.windows(2) {
+            assert!(pair[1].chars.0 >= pair[0].chars.1);
+        }
+    }
+
+    #[test]
+    fn char_offsets_of_multibyte_text() {
+        let paragraph = "東京の台所では、毎朝パンを焼きます。味噌汁と漬物も用意します。 ";
+        let document = Document::from(vec![
+            Section::new("前書き 😀 ".repeat(10)),
+            Section::new(paragraph.repeat(12)),
+            Section::new(format!("{}\n\nCafé crème {}", paragraph, paragraph)),
+        ]);
+
+        for config in [
+            "max_tokens = 48\noverlap = 8",
+            "max_tokens = 48\noverlap = 8\nstrategy = \"characters\"",
+            "max_tokens = 48\noverlap = 0\nstrategy = \"structure\"",
+        ] {
+            let chunks = chunker_with(config).chunk(&document, "default");
+            assert!(chunks.len() > 3);
+            for chunk in &chunks {
+                assert_eq!(source(&document, chunk.chars), chunk.text, "{}", config);
+            }
+        }
+    }
+
+    fn headings<'a>(chunks: &'a [Chunk]) -> Vec<(Option<String>, &'a str)> {
+        chunks
+            .iter()
+            .map(|chunk| (chunk.heading_path(), chunk.text.as_ref()))
+            .collect()
+    }
+
+    fn section(heading: &[&str], text: &str) -> Section {
+        Section {
+            heading: heading.iter().map(|h| h.to_string()).collect(),
+            ..Section::new(text.to_string())
+        }
+    }
+
+    #[test]
+    fn small_siblings_are_merged() {
+        let document = Document::from(vec![
+            section(&["Guide", "Alpha"], "Alpha is the first step."),
+            section(&["Guide", "Beta"], "Beta is the second step."),
+            section(&["Guide", "Gamma"], "Gamma is the third step."),
+            section(&["Other", "Delta"], "Delta sits under another heading."),
+        ]);
+
+        // Room for exactly two of the siblings under their parent heading.
+        let probe = chunker_with("");
+        let max_tokens = probe
+            .count_tokens(&document.sections[0].with_context(&["Guide".to_string()], ""))
+            + probe.count_tokens(&titled(&document.sections[0]))
+            + probe.count_tokens(BLOCK_SEPARATOR)
+            + probe.count_tokens(&titled(&document.sections[1]));
+        let chunker = chunker_with(&format!(
+            "max_tokens = {}\noverlap = 0\nstrategy = \"structure\"",
+            max_tokens
+        ));
+
+        let chunks = chunker.chunk(&document, "default");
+        assert_eq!(
+            headings(&chunks),
+            vec![
+                (
+                    Some("Guide".to_string()),
+                    "Alpha\nAlpha is the first step.\n\nBeta\nBeta is the second step."
+                ),
+                (
+                    Some("Guide > Gamma".to_string()),
+                    "Gamma is the third step."
+                ),
+                (
+                    Some("Other > Delta".to_string()),
+                    "Delta sits under another heading."
+                ),
+            ]
+        );
+        assert_eq!(chunks[0].chars, (0, 50));
+        for chunk in &chunks {
+            assert!(chunker.count_tokens(&chunk.with_heading()) <= max_tokens);
+        }
+    }
+
+    #[test]
+    fn code_blocks_and_tables_are_kept_whole() {
+        let code = "```rust\nfn main() {\n    let jars = shelf();\n\n    for jar in jars {\n        label(jar);\n    }\n}\n```";
+        let table = "name | shelf\njars | 3\nknives | 7\nspices | 2\nplates | 9";
+        let chunker = chunker_with("max_tokens = 40\noverlap = 0\nstrategy = \"structure\"");
+        assert!(chunker.count_tokens(code) <= 40 && chunker.count_tokens(table) <= 40);
+
+        // The paragraph leaves room for the code up to its blank line, not
+        // for all of it.
+        let head = chunker.count_tokens(code.split("\n\n").next().unwrap());
+        let separator = chunker.count_tokens(BLOCK_SEPARATOR);
+        let mut paragraph = "Jars".to_string();
+        while chunker.count_tokens(&paragraph) + separator + head < 40 {
+            paragraph.push_str(" jars");
+        }
+
+        let text = format!("{}\n\n{}\n\n{}\n\n{}", paragraph, code, table, paragraph);
+        let document = Document::from(vec![section(&["Kitchen"], &text)]);
+        let chunks = chunker.chunk(&document, "default");
+        let texts = chunks
+            .iter()
+            .map(|chunk| chunk.text.as_ref())
+            .collect::<Vec<&str>>();
+        assert!(texts.contains(&code), "{:?}", texts);
+        assert!(texts.contains(&table), "{:?}", texts);
+        for chunk in &chunks {
+            assert_eq!(source(&document, chunk.chars), chunk.text);
+        }
+    }
+
+    #[test]
+    fn category_picks_the_strategy() {
+        let chunker = chunker_with(
+            "max_tokens = 200\noverlap = 0\n[file_processor.chunking.categories]\nmanuals = \"structure\"",
+        );
+        assert!(matches!(
+            chunker.strategy("manuals"),
+            ChunkingStrategy::Structure
+        ));
+        assert!(matches!(
+            chunker.strategy("default"),
+            ChunkingStrategy::Text
+        ));
+
+        let document = Document::from(vec![
+            section(&["Guide", "Alpha"], "Alpha is the first step."),
+            section(&["Guide", "Beta"], "Beta is the second step."),
+        ]);
+        assert_eq!(chunker.chunk(&document, "manuals").len(), 1);
+        assert_eq!(chunker.chunk(&document, "default").len(), 2);
+    }
+}
@@ -0,0 +1,256 @@
+/// Section is a run of text that sits under a heading path. Extractors return
+/// a document as a list of sections so that chunks never mix text from two
+/// different headings.
+#[derive(Debug, Default)]
+pub struct Section {
+    /// Heading hierarchy of the section, outermost heading first.
+    pub heading: Vec<String>,
+    /// Lines repeated at the start of every chunk of the section, e.g. the
+    /// column headers of a table.
+    pub header: Option<String>,
+    /// Fields describing where the text comes from, e.g. the sender of an
+    /// email. They're embedded along with the text & stored with the chunk.
+    pub metadata: Vec<(String, String)>,
+    /// Byte offset in text where each page starts along with its number,
+    /// in order. Empty for formats that don't have pages.
+    pub pages: Vec<(usize, i32)>,
+    pub text: String,
+}
+
+impl Section {
+    pub fn new(text: String) -> Section {
+        Section {
+            heading: vec![],
+            header: None,
+            metadata: vec![],
+            pages: vec![],
+            text,
+        }
+    }
+
+    /// Returns the first & last page of the text between the byte offsets
+    /// start & end, None if the section has no pages.
+    pub fn page_range(&self, start: usize, end: usize) -> Option<(i32, i32)> {
+        let page_at = |offset: usize| {
+            self.pages
+                .iter()
+                .take_while(|(page_start, _)| *page_start <= offset)
+                .last()
+                .or(self.pages.first())
+                .map(|(_, page)| *page)
+        };
+
+        Some((page_at(start)?, page_at(end.saturating_sub(1).max(start))?))
+    }
+
+    /// Heading path joined for display, e.g. "Design > Storage".
+    pub fn heading_path(&self) -> String {
+        self.heading.join(" > ")
+    }
+
+    /// Prefixes chunk with the section header, if any.
+    pub fn with_header(&self, chunk: &str) -> String {
+        match &self.header {
+            Some(header) => format!("{}\n{}", header, chunk),
+            None => chunk.to_string(),
+        }
+    }
+
+    /// Prefixes chunk with the heading path, metadata & header so that the
+    /// heading hierarchy is part of the text that gets embedded.
+    pub fn with_heading(&self, chunk: &str) -> String {
+        self.with_context(&self.heading, chunk)
+    }
+
+    /// Same as with_heading with the given heading path in place of the
+    /// section's, chunks made of sibling sections sit under their parent.
+    pub fn with_context(&self, heading: &[String], chunk: &str) -> String {
+        let heading = (!heading.is_empty()).then(|| heading.join(" > "));
+        embedding_text(
+            heading.as_deref(),
+            self.metadata
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+            &self.with_header(chunk),
+        )
+    }
+}
+
+/// Builds the text that's embedded for a stored chunk: the heading path &
+/// metadata fields followed by the text.
+pub fn embedding_text<'a>(
+    heading: Option<&str>,
+    metadata: impl Iterator<Item = (&'a str, &'a str)>,
+    text: &str,
+) -> String {
+    let mut context: Vec<String> = vec![];
+    if let Some(heading) = heading {
+        context.push(heading.to_string());
+    }
+    for (name, value) in metadata {
+        context.push(format!("{}: {}", name, value));
+    }
+
+    if context.is_empty() {
+        text.to_string()
+    } else {
+        format!("{}\n\n{}", context.join("\n"), text)
+    }
+}
+
+/// Document is the text extracted from a file along with the warnings
+/// raised while extracting it, warnings are shown in the upload status.
+#[derive(Debug, Default)]
+pub struct Document {
+    pub sections: Vec<Section>,
+    pub warnings: Vec<String>,
+}
+
+impl From<Vec<Section>> for Document {
+    fn from(sections: Vec<Section>) -> Document {
+        Document {
+            sections,
+            warnings: vec![],
+        }
+    }
+}
+
+/// Table collects the rows of a table, rows are rendered as cells separated
+/// by " | " so that a row stays on a single line.
+#[derive(Default)]
+struct Table {
+    rows: Vec<String>,
+    row: Vec<String>,
+    cell: String,
+}
+
+/// SectionBuilder collects blocks of text and starts a new section whenever a
+/// heading is pushed.
+#[derive(Default)]
+pub struct SectionBuilder {
+    sections: Vec<Section>,
+    heading: Vec<String>,
+    text: String,
+    notes: Vec<String>,
+
+    // Open tables, innermost table last.
+    tables: Vec<Table>,
+}
+
+impl SectionBuilder {
+    pub fn new() -> SectionBuilder {
+        SectionBuilder::default()
+    }
+
+    /// Starts a new section under a heading of the given level, level 1 being
+    /// the outermost.
+    pub fn heading(&mut self, level: usize, title: &str) {
+        let title = normalize_whitespace(title);
+        if title.is_empty() {
+            return;
+        }
+
+        // Headings inside a table are kept as cell text.
+        if !self.tables.is_empty() {
+            self.block(&title);
+            return;
+        }
+
+        self.flush();
+        self.heading.truncate(level.max(1) - 1);
+        self.heading.push(title);
+    }
+
+    /// Adds a block of text (paragraph, list item, code block) to the current
+    /// section, or to the current table cell if a table is open.
+    pub fn block(&mut self, text: &str) {
+        let text = text.trim_end();
+        if text.trim().is_empty() {
+            return;
+        }
+
+        if let Some(table) = self.tables.last_mut() {
+            if !table.cell.is_empty() {
+                table.cell.push(' ');
+            }
+            table.cell.push_str(text);
+            return;
+        }
+
+        self.text.push_str(text);
+        self.text.push_str("\n\n");
+    }
+
+    pub fn start_table(&mut self) {
+        self.tables.push(Table::default());
+    }
+
+    pub fn end_cell(&mut self) {
+        if let Some(table) = self.tables.last_mut() {
+            let cell = normalize_whitespace(&std::mem::take(&mut table.cell));
+            table.row.push(cell);
+        }
+    }
+
+    pub fn end_row(&mut self) {
+        if let Some(table) = self.tables.last_mut() {
+            let row = std::mem::take(&mut table.row);
+            if row.iter().any(|cell| !cell.is_empty()) {
+                let row = row.join(" | ");
+                table
+                    .rows
+                    .push(row.trim_end_matches(['|', ' ']).to_string());
+            }
+        }
+    }
+
+    /// Closes the innermost table, it's added to the enclosing table cell or
+    /// to the section as a single block.
+    pub fn end_table(&mut self) {
+        if let Some(table) = self.tables.pop() {
+            self.block(&table.rows.join("\n"));
+        }
+    }
+
+    /// Adds a footnote to the current section, footnotes are placed at the
+    /// end of the section they're referenced in.
+    pub fn note(&mut self, marker: &str, text: &str) {
+        let text = normalize_whitespace(text);
+        if !text.is_empty() {
+            self.notes.push(format!("[{}] {}", marker, text));
+        }
+    }
+
+    fn flush(&mut self) {
+        for note in self.notes.drain(..) {
+            self.text.push_str(&note);
+            self.text.push('\n');
+        }
+
+        let text = std::mem::take(&mut self.text);
+        if text.trim().is_empty() {
+            return;
+        }
+
+        self.sections.push(Section {
+            heading: self.heading.clone(),
+            header: None,
+            metadata: vec![],
+            pages: vec![],
+            text: text.trim_end().to_string(),
+        });
+    }
+
+    pub fn finish(mut self) -> Vec<Section> {
+        while !self.tables.is_empty() {
+            self.end_table();
+        }
+        self.flush();
+        self.sections
+    }
+}
+
+/// Collapses runs of whitespace into a single space.
+pub fn normalize_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
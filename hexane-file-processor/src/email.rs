@@ -0,0 +1,650 @@
+use base64::{
+    alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
+use encoding_rs::Encoding;
+use std::{fs, path::Path};
+
+use crate::document::{normalize_whitespace, Document, Section};
+use crate::extractor::{Extractor, Sample};
+use crate::html::html_to_sections;
+
+/// Base64 engine that accepts bodies with or without padding.
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Headers of a message or a MIME part, names are lowercased.
+struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the decoded value of a header meant to be read by a human,
+    /// e.g. From or Subject.
+    fn decoded(&self, name: &str) -> Option<String> {
+        self.get(name)
+            .map(|value| normalize_whitespace(&decode_encoded_words(value)))
+            .filter(|value| !value.is_empty())
+    }
+}
+
+/// Content-Type of a part: lowercased "type/subtype" & its parameters.
+struct ContentType {
+    mime: String,
+    params: Vec<(String, String)>,
+}
+
+impl ContentType {
+    fn parse(value: Option<&str>) -> ContentType {
+        let value = value.unwrap_or("text/plain");
+        let mut parts = value.split(';');
+        let mime = parts.next().unwrap_or_default().trim().to_lowercase();
+
+        let params = parts
+            .filter_map(|param| param.split_once('='))
+            .map(|(name, value)| {
+                (
+                    name.trim().to_lowercase(),
+                    value.trim().trim_matches('"').to_string(),
+                )
+            })
+            .collect();
+
+        ContentType {
+            mime: if mime.is_empty() {
+                "text/plain".to_string()
+            } else {
+                mime
+            },
+            params,
+        }
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Splits raw bytes into headers & body at the first empty line, folded
+/// header lines are unfolded.
+fn split_message(raw: &[u8]) -> (Headers, &[u8]) {
+    let mut head = raw;
+    let mut body: &[u8] = &[];
+    for (idx, _) in raw.iter().enumerate().filter(|(_, b)| **b == b'\n') {
+        let rest = &raw[idx + 1..];
+        if let Some(rest) = rest.strip_prefix(b"\n").or(rest.strip_prefix(b"\r\n")) {
+            (head, body) = (&raw[..idx], rest);
+            break;
+        }
+    }
+
+    let mut headers: Vec<(String, String)> = vec![];
+    for line in String::from_utf8_lossy(head).lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+    }
+
+    (Headers(headers), body)
+}
+
+/// Splits the body of a multipart part at its boundary lines, the preamble &
+/// epilogue are dropped.
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts: Vec<&[u8]> = vec![];
+    let mut start: Option<usize> = None;
+
+    let mut offset = 0;
+    for line in body.split_inclusive(|b| *b == b'\n') {
+        let trimmed = String::from_utf8_lossy(line);
+        let trimmed = trimmed.trim_end();
+        if trimmed == delimiter || trimmed == format!("{}--", delimiter) {
+            if let Some(start) = start {
+                parts.push(&body[start..offset]);
+            }
+            if trimmed != delimiter {
+                return parts;
+            }
+            start = Some(offset + line.len());
+        }
+        offset += line.len();
+    }
+
+    // Unterminated multipart, keep the last part.
+    if let Some(start) = start {
+        parts.push(&body[start..]);
+    }
+    parts
+}
+
+/// Decodes the text of a part with the charset it declares, unknown charsets
+/// are read as UTF-8.
+fn decode_charset(bytes: &[u8], charset: Option<&str>) -> String {
+    let encoding = charset
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .unwrap_or(encoding_rs::UTF_8);
+    encoding.decode(bytes).0.into_owned()
+}
+
+fn decode_quoted_printable(body: &[u8], header: bool) -> Vec<u8> {
+    let mut decoded: Vec<u8> = vec![];
+    let mut idx = 0;
+    while idx < body.len() {
+        match body[idx] {
+            b'=' => {
+                let rest = &body[idx + 1..];
+                // Soft line break.
+                if rest.starts_with(b"\r\n") {
+                    idx += 3;
+                    continue;
+                }
+                if rest.starts_with(b"\n") {
+                    idx += 2;
+                    continue;
+                }
+                let hex = rest
+                    .get(..2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        idx += 3;
+                    }
+                    None => {
+                        decoded.push(b'=');
+                        idx += 1;
+                    }
+                }
+            }
+            b'_' if header => {
+                decoded.push(b' ');
+                idx += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                idx += 1;
+            }
+        }
+    }
+    decoded
+}
+
+fn decode_base64(body: &[u8]) -> Vec<u8> {
+    let body: Vec<u8> = body
+        .iter()
+        .filter(|b| !b.is_ascii_whitespace())
+        .copied()
+        .collect();
+    BASE64.decode(body).unwrap_or_default()
+}
+
+/// Decodes RFC 2047 encoded words ("=?utf-8?Q?caf=C3=A9?=") found in header
+/// values, whitespace between adjacent encoded words is dropped.
+fn decode_encoded_words(value: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = value;
+    let mut after_word = false;
+
+    while let Some(start) = rest.find("=?") {
+        let word = &rest[start + 2..];
+        let parsed = word.split_once('?').and_then(|(charset, word)| {
+            let (encoding, word) = word.split_once('?')?;
+            let (text, word) = word.split_once("?=")?;
+            Some((charset, encoding, text, word))
+        });
+        let (charset, encoding, text, remaining) = match parsed {
+            Some(parsed) => parsed,
+            None => break,
+        };
+        let bytes = match encoding.to_ascii_lowercase().as_str() {
+            "b" => decode_base64(text.as_bytes()),
+            "q" => decode_quoted_printable(text.as_bytes(), true),
+            _ => break,
+        };
+
+        let before = &rest[..start];
+        if !(after_word && before.trim().is_empty()) {
+            decoded.push_str(before);
+        }
+        // Drop the RFC 2231 language suffix, e.g. "utf-8*en".
+        let charset = charset.split('*').next().unwrap_or_default();
+        decoded.push_str(&decode_charset(&bytes, Some(charset)));
+
+        rest = remaining;
+        after_word = true;
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Returns the readable text of a MIME part. For multipart/alternative the
+/// plain text version is preferred over HTML, attachments are skipped.
+fn part_text(headers: &Headers, body: &[u8]) -> Option<String> {
+    let content_type = ContentType::parse(headers.get("content-type"));
+
+    let attachment = headers
+        .get("content-disposition")
+        .is_some_and(|d| d.trim().to_lowercase().starts_with("attachment"));
+    if attachment {
+        return None;
+    }
+
+    if content_type.mime.starts_with("multipart/") {
+        let boundary = content_type.param("boundary")?;
+        let parts = split_multipart(body, boundary)
+            .into_iter()
+            .map(split_message)
+            .collect::<Vec<(Headers, &[u8])>>();
+
+        if content_type.mime == "multipart/alternative" {
+            let preferred = parts
+                .iter()
+                .find(|(headers, _)| {
+                    ContentType::parse(headers.get("content-type")).mime == "text/plain"
+                })
+                .or(parts.last());
+            return preferred.and_then(|(headers, body)| part_text(headers, body));
+        }
+
+        let text = parts
+            .iter()
+            .filter_map(|(headers, body)| part_text(headers, body))
+            .collect::<Vec<String>>()
+            .join("\n\n");
+        return (!text.trim().is_empty()).then_some(text);
+    }
+
+    if content_type.mime == "message/rfc822" {
+        let (headers, body) = split_message(body);
+        return part_text(&headers, body);
+    }
+
+    if !content_type.mime.starts_with("text/") {
+        return None;
+    }
+
+    let encoding = headers
+        .get("content-transfer-encoding")
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    let bytes = match encoding.as_str() {
+        "base64" => decode_base64(body),
+        "quoted-printable" => decode_quoted_printable(body, false),
+        _ => body.to_vec(),
+    };
+    let text = decode_charset(&bytes, content_type.param("charset"));
+
+    match content_type.mime.as_str() {
+        "text/plain" => Some(text),
+        "text/html" => Some(
+            html_to_sections(&text)
+                .into_iter()
+                .map(|section| section.with_heading(&section.text))
+                .collect::<Vec<String>>()
+                .join("\n\n"),
+        ),
+        _ => None,
+    }
+}
+
+/// Drops the quoted reply chain from a message body: quoted lines, the
+/// "On ... wrote:" line that introduces them and everything after an
+/// "Original Message" separator.
+fn strip_quoted(text: &str) -> String {
+    let lines = text.lines().collect::<Vec<&str>>();
+    let mut kept: Vec<&str> = vec![];
+
+    for (idx, line) in lines.iter().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('>') {
+            continue;
+        }
+        if trimmed.starts_with("-----Original Message-----") {
+            break;
+        }
+        // Attribution lines are followed by the quote, possibly after an
+        // empty line.
+        if trimmed.ends_with("wrote:")
+            && lines[idx + 1..]
+                .iter()
+                .find(|l| !l.trim().is_empty())
+                .is_none_or(|l| l.trim().starts_with('>'))
+        {
+            continue;
+        }
+        kept.push(line.trim_end());
+    }
+
+    // Collapse the empty lines left behind by the quotes.
+    let mut text = String::new();
+    let mut empty_lines = 0;
+    for line in kept {
+        if line.is_empty() {
+            empty_lines += 1;
+            if empty_lines > 1 {
+                continue;
+            }
+        } else {
+            empty_lines = 0;
+        }
+        text.push_str(line);
+        text.push('\n');
+    }
+    text.trim().to_string()
+}
+
+/// Converts a single message to a section under its subject, From, Date &
+/// Subject are kept as the section metadata.
+fn message_to_section(raw: &[u8]) -> Option<Section> {
+    let (headers, body) = split_message(raw);
+    let text = strip_quoted(&part_text(&headers, body).unwrap_or_default());
+    if text.is_empty() {
+        return None;
+    }
+
+    let subject = headers.decoded("subject");
+    let metadata = [
+        ("from", headers.decoded("from")),
+        ("date", headers.decoded("date")),
+        ("subject", subject.clone()),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| value.map(|value| (name.to_string(), value)))
+    .collect();
+
+    Some(Section {
+        heading: vec![subject.unwrap_or("(no subject)".to_string())],
+        metadata,
+        ..Section::new(text)
+    })
+}
+
+/// Reports whether text starts with the header of a message: every line up
+/// to the first empty line is a header field, and a From field is present
+/// along with a Date or Subject.
+fn looks_like_message(text: &str) -> bool {
+    let mut names: Vec<String> = vec![];
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            break;
+        }
+        if line.starts_with([' ', '\t']) && !names.is_empty() {
+            continue;
+        }
+        match line.split_once(':') {
+            Some((name, _))
+                if !name.is_empty()
+                    && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') =>
+            {
+                names.push(name.to_lowercase())
+            }
+            _ => return false,
+        }
+    }
+
+    names.iter().any(|n| n == "from") && names.iter().any(|n| n == "date" || n == "subject")
+}
+
+/// eml_to_sections extracts the text of an RFC 5322 message (.eml).
+pub fn eml_to_sections(raw: &[u8]) -> Vec<Section> {
+    message_to_section(raw).into_iter().collect()
+}
+
+/// mbox_to_sections extracts every message of an mbox archive, each message is
+/// a separate section.
+pub fn mbox_to_sections(raw: &[u8]) -> Vec<Section> {
+    let mut messages: Vec<Vec<u8>> = vec![];
+    let mut message: Vec<u8> = vec![];
+
+    for line in raw.split_inclusive(|b| *b == b'\n') {
+        // "From " lines separate messages, escaped lines in the body look
+        // like ">From " (or ">>From " in mboxrd).
+        if line.starts_with(b"From ") {
+            if !message.is_empty() {
+                messages.push(std::mem::take(&mut message));
+            }
+            continue;
+        }
+
+        let unescaped = match line.iter().position(|b| *b != b'>') {
+            Some(idx) if idx > 0 && line[idx..].starts_with(b"From ") => &line[1..],
+            _ => line,
+        };
+        message.extend_from_slice(unescaped);
+    }
+    if !message.is_empty() {
+        messages.push(message);
+    }
+
+    messages
+        .iter()
+        .filter_map(|message| message_to_section(message))
+        .collect()
+}
+
+pub struct Eml;
+
+impl Extractor for Eml {
+    fn description(&self) -> &'static str {
+        "email (.eml)"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["message/rfc822"]
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        &[("eml", "message/rfc822")]
+    }
+
+    fn sniff(&self, sample: &Sample) -> Option<&'static str> {
+        looks_like_message(&sample.text()).then_some("message/rfc822")
+    }
+
+    fn textual(&self) -> bool {
+        true
+    }
+
+    fn extract(&self, file: &Path, _: &str) -> Result<Document, String> {
+        let raw = fs::read(file).map_err(|e| format!("reading {}: {}", file.display(), e))?;
+        Ok(eml_to_sections(&raw).into())
+    }
+}
+
+pub struct Mbox;
+
+impl Extractor for Mbox {
+    fn description(&self) -> &'static str {
+        "mailbox (.mbox)"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/mbox"]
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        &[("mbox", "application/mbox")]
+    }
+
+    fn sniff(&self, sample: &Sample) -> Option<&'static str> {
+        let text = sample.text();
+        match text.split_once('\n') {
+            Some((from, message)) if from.starts_with("From ") => {
+                looks_like_message(message).then_some("application/mbox")
+            }
+            _ => None,
+        }
+    }
+
+    fn textual(&self) -> bool {
+        true
+    }
+
+    fn extract(&self, file: &Path, _: &str) -> Result<Document, String> {
+        let raw = fs::read(file).map_err(|e| format!("reading {}: {}", file.display(), e))?;
+        Ok(mbox_to_sections(&raw).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(section: &Section, name: &str) -> Option<String> {
+        section
+            .metadata
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.clone())
+    }
+
+    #[test]
+    fn encoded_words() {
+        assert_eq!(
+            decode_encoded_words("=?utf-8?Q?caf=C3=A9_au_lait?="),
+            "café au lait"
+        );
+        assert_eq!(
+            decode_encoded_words("Re: =?UTF-8?B?Y2Fmw6k=?= =?iso-8859-1?q?_cr=E8me?= now"),
+            "Re: café crème now"
+        );
+        assert_eq!(decode_encoded_words("plain =?broken"), "plain =?broken");
+    }
+
+    #[test]
+    fn transfer_encodings() {
+        assert_eq!(
+            decode_quoted_printable(b"caf=C3=A9 soft=\r\nbreak a=b =3D", false),
+            "café softbreak a=b =".as_bytes()
+        );
+        assert_eq!(decode_base64(b"aGVs\r\nbG8"), b"hello");
+    }
+
+    #[test]
+    fn multipart_prefers_plain_text() {
+        let raw = b"From: =?utf-8?Q?Ren=C3=A9e?= <renee@example.org>\r
+Date: Mon, 1 Jan 2024 10:00:00 +0000\r
+Subject: Release\r
+ notes\r
+Content-Type: multipart/mixed; boundary=\"outer\"\r
+\r
+preamble\r
+--outer\r
+Content-Type: multipart/alternative; boundary=inner\r
+\r
+--inner\r
+Content-Type: text/plain; charset=iso-8859-1\r
+Content-Transfer-Encoding: quoted-printable\r
+\r
+Caf=E9 is open.\r
+--inner\r
+Content-Type: text/html\r
+\r
+<p>HTML version</p>\r
+--inner--\r
+--outer\r
+Content-Type: text/plain\r
+Content-Disposition: attachment; filename=notes.txt\r
+\r
+attached\r
+--outer\r
+Content-Type: text/plain\r
+Content-Transfer-Encoding: base64\r
+\r
+U2lnbmVkLg==\r
+--outer--\r
+epilogue\r
+";
+
+        let sections = eml_to_sections(raw);
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].heading, vec!["Release notes"]);
+        assert_eq!(sections[0].text, "Café is open.\n\nSigned.");
+        assert_eq!(
+            metadata(&sections[0], "from").as_deref(),
+            Some("Renée <renee@example.org>")
+        );
+        assert_eq!(
+            metadata(&sections[0], "date").as_deref(),
+            Some("Mon, 1 Jan 2024 10:00:00 +0000")
+        );
+    }
+
+    #[test]
+    fn html_only_message() {
+        let raw = b"From: a@example.org\nSubject: Hi\nContent-Type: text/html\n\n\
+<html><body><script>x()</script><p>Hello <b>there</b>.</p></body></html>\n";
+
+        assert_eq!(eml_to_sections(raw)[0].text, "Hello there.");
+    }
+
+    #[test]
+    fn quoted_replies_are_stripped() {
+        let text = "Sounds good.\n\nOn Mon, Jan 1, 2024 Ada wrote:\n\n> Shall we?\n> > Earlier.\n\nThanks\n-----Original Message-----\nFrom: Ada\nOld text.";
+        assert_eq!(strip_quoted(text), "Sounds good.\n\nThanks");
+
+        // An attribution line without a quote after it is kept.
+        assert_eq!(
+            strip_quoted("The author wrote:\nsomething new"),
+            "The author wrote:\nsomething new"
+        );
+    }
+
+    #[test]
+    fn mbox_messages() {
+        let raw = b"From ada@example.org Mon Jan  1 10:00:00 2024
+From: ada@example.org
+Subject: First
+
+Body of the first.
+>From here on it's escaped.
+
+From alan@example.org Tue Jan  2 10:00:00 2024
+From: alan@example.org
+Subject: Second
+
+Body of the second.
+";
+
+        let sections = mbox_to_sections(raw);
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].heading, vec!["First"]);
+        assert_eq!(
+            sections[0].text,
+            "Body of the first.\nFrom here on it's escaped."
+        );
+        assert_eq!(sections[1].heading, vec!["Second"]);
+        assert_eq!(
+            metadata(&sections[1], "from").as_deref(),
+            Some("alan@example.org")
+        );
+    }
+
+    #[test]
+    fn message_detection() {
+        assert!(looks_like_message(
+            "From: a@example.org\nSubject: Hi\n  folded\n\nbody"
+        ));
+        assert!(!looks_like_message("Subject: Hi\n\nbody"));
+        assert!(!looks_like_message("Dear team,\nFrom: me\n"));
+    }
+}
@@ -0,0 +1,464 @@
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek},
+    path::Path,
+};
+use zip::ZipArchive;
+
+use crate::document::{normalize_whitespace, Document, Section};
+use crate::extractor::{Extractor, Sample};
+use crate::html::html_to_sections;
+use crate::{read_zip_entry, xml_attribute};
+
+struct ManifestItem {
+    href: String,
+    media_type: String,
+    properties: String,
+}
+
+/// Package is the parsed OPF file: manifest, spine and the table of contents.
+struct Package {
+    manifest: HashMap<String, ManifestItem>,
+    spine: Vec<String>,
+    // Manifest id of the EPUB 2 NCX table of contents.
+    ncx: Option<String>,
+}
+
+/// Resolves href relative to the directory of the file it was found in. The
+/// fragment is dropped and percent-encoded characters are decoded.
+fn resolve_href(base_dir: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or_default();
+
+    let mut bytes: Vec<u8> = vec![];
+    let mut chars = href.bytes();
+    while let Some(b) = chars.next() {
+        if b == b'%' {
+            let hex = [chars.next().unwrap_or(b'0'), chars.next().unwrap_or(b'0')];
+            match u8::from_str_radix(&String::from_utf8_lossy(&hex), 16) {
+                Ok(decoded) => bytes.push(decoded),
+                Err(_) => bytes.extend([b'%', hex[0], hex[1]]),
+            }
+        } else {
+            bytes.push(b);
+        }
+    }
+    let href = String::from_utf8_lossy(&bytes);
+
+    let mut parts: Vec<&str> = base_dir.split('/').filter(|p| !p.is_empty()).collect();
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+fn parent_dir(path: &str) -> &str {
+    path.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("")
+}
+
+fn parse_container(xml: &str) -> Result<String, String> {
+    let mut reader = Reader::from_str(xml);
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.local_name().as_ref() == b"rootfile" => {
+                if let Some(path) = xml_attribute(&e, "full-path") {
+                    return Ok(path);
+                }
+            }
+            Ok(Event::Eof) => return Err("no rootfile in META-INF/container.xml".to_string()),
+            Err(e) => return Err(format!("parsing META-INF/container.xml: {}", e)),
+            _ => {}
+        }
+    }
+}
+
+fn parse_package(xml: &str) -> Result<Package, String> {
+    let mut package = Package {
+        manifest: HashMap::new(),
+        spine: vec![],
+        ncx: None,
+    };
+
+    let mut reader = Reader::from_str(xml);
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => match e.local_name().as_ref() {
+                b"item" => {
+                    if let (Some(id), Some(href)) =
+                        (xml_attribute(&e, "id"), xml_attribute(&e, "href"))
+                    {
+                        package.manifest.insert(
+                            id,
+                            ManifestItem {
+                                href,
+                                media_type: xml_attribute(&e, "media-type").unwrap_or_default(),
+                                properties: xml_attribute(&e, "properties").unwrap_or_default(),
+                            },
+                        );
+                    }
+                }
+                b"spine" => package.ncx = xml_attribute(&e, "toc"),
+                b"itemref" => {
+                    let linear = xml_attribute(&e, "linear").unwrap_or_default();
+                    if let (Some(idref), false) = (xml_attribute(&e, "idref"), linear == "no") {
+                        package.spine.push(idref);
+                    }
+                }
+                _ => {}
+            },
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(format!("parsing package document: {}", e)),
+            _ => {}
+        }
+    }
+
+    Ok(package)
+}
+
+/// Parses the table of contents, either an EPUB 3 navigation document or an
+/// EPUB 2 NCX file. It returns the title of the first entry pointing to each
+/// content document, keyed by the document's path in the archive.
+fn parse_toc(xml: &str, toc_path: &str) -> Result<HashMap<String, String>, String> {
+    let mut titles: HashMap<String, String> = HashMap::new();
+    let base_dir = parent_dir(toc_path);
+
+    // In a navigation document only the "toc" nav is considered, NCX files
+    // contain the toc only.
+    let mut in_toc = !toc_path.ends_with("html");
+    let mut nav_depth = 0;
+
+    // Title & href of the entry being read.
+    let mut title: Option<String> = None;
+    let mut href: Option<String> = None;
+
+    let mut reader = Reader::from_str(xml);
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => match e.local_name().as_ref() {
+                b"nav" => {
+                    nav_depth += 1;
+                    if xml_attribute(&e, "epub:type")
+                        .is_some_and(|t| t.split(' ').any(|t| t == "toc"))
+                    {
+                        in_toc = true;
+                    }
+                }
+                // EPUB 3 entry, the title is the text of the anchor.
+                b"a" if in_toc => {
+                    href = xml_attribute(&e, "href");
+                    title = Some(String::new());
+                }
+                // EPUB 2 entry, the title is in navLabel/text.
+                b"text" if in_toc => title = Some(String::new()),
+                _ => {}
+            },
+            // NCX: navLabel comes before content, nested navPoints follow it so
+            // the entry is recorded here.
+            Ok(Event::Empty(e)) if in_toc && e.local_name().as_ref() == b"content" => {
+                if let (Some(t), Some(h)) = (title.take(), xml_attribute(&e, "src")) {
+                    let t = normalize_whitespace(&t);
+                    if !t.is_empty() {
+                        titles.entry(resolve_href(base_dir, &h)).or_insert(t);
+                    }
+                }
+            }
+            Ok(Event::Text(e)) if in_toc => {
+                if let Some(title) = title.as_mut() {
+                    let text = e
+                        .unescape()
+                        .map_err(|e| format!("parsing {}: {}", toc_path, e))?;
+                    title.push_str(&text);
+                }
+            }
+            Ok(Event::End(e)) => match e.local_name().as_ref() {
+                b"nav" => {
+                    nav_depth -= 1;
+                    if nav_depth == 0 {
+                        in_toc = false;
+                    }
+                }
+                b"a" => {
+                    if let (Some(t), Some(h)) = (title.take(), href.take()) {
+                        let t = normalize_whitespace(&t);
+                        if !t.is_empty() {
+                            titles.entry(resolve_href(base_dir, &h)).or_insert(t);
+                        }
+                    }
+                }
+                _ => {}
+            },
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(format!("parsing {}: {}", toc_path, e)),
+            _ => {}
+        }
+    }
+
+    Ok(titles)
+}
+
+/// epub_to_sections walks the spine of an EPUB in reading order and extracts
+/// the text of every chapter. The chapter title, taken from the table of
+/// contents, is kept as the "chapter" metadata of each section & is also its
+/// outermost heading.
+pub fn epub_to_sections(file: &Path) -> Result<Vec<Section>, String> {
+    let f = File::open(file).map_err(|e| format!("opening {}: {}", file.display(), e))?;
+    let mut archive =
+        ZipArchive::new(f).map_err(|e| format!("reading zip archive {}: {}", file.display(), e))?;
+    epub_archive_to_sections(&mut archive)
+}
+
+fn epub_archive_to_sections<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+) -> Result<Vec<Section>, String> {
+    let container = read_zip_entry(archive, "META-INF/container.xml")?
+        .ok_or("META-INF/container.xml not found in archive")?;
+    let opf_path = parse_container(&container)?;
+    let opf =
+        read_zip_entry(archive, &opf_path)?.ok_or(format!("{} not found in archive", opf_path))?;
+    let package = parse_package(&opf)?;
+    let opf_dir = parent_dir(&opf_path);
+
+    // Prefer the EPUB 3 navigation document over the NCX.
+    let toc_item = package
+        .manifest
+        .values()
+        .find(|item| item.properties.split(' ').any(|p| p == "nav"))
+        .or_else(|| package.ncx.as_ref().and_then(|id| package.manifest.get(id)));
+
+    let titles = match toc_item {
+        Some(item) => {
+            let toc_path = resolve_href(opf_dir, &item.href);
+            match read_zip_entry(archive, &toc_path)? {
+                Some(xml) => parse_toc(&xml, &toc_path).unwrap_or_else(|e| {
+                    tracing::warn!("ignoring table of contents: {}", e);
+                    HashMap::new()
+                }),
+                None => HashMap::new(),
+            }
+        }
+        None => HashMap::new(),
+    };
+
+    let mut sections: Vec<Section> = vec![];
+    let mut chapter: Option<String> = None;
+
+    for (idx, idref) in package.spine.iter().enumerate() {
+        let item = match package.manifest.get(idref) {
+            Some(item) => item,
+            None => continue,
+        };
+        if !matches!(
+            item.media_type.as_str(),
+            "application/xhtml+xml" | "text/html"
+        ) {
+            continue;
+        }
+
+        let path = resolve_href(opf_dir, &item.href);
+        let xhtml = match read_zip_entry(archive, &path)? {
+            Some(xhtml) => xhtml,
+            None => continue,
+        };
+
+        let mut chapter_sections = html_to_sections(&xhtml);
+
+        // Documents that aren't in the table of contents continue the previous
+        // chapter, unless they start with a heading of their own.
+        chapter = match titles.get(&path) {
+            Some(title) => Some(title.clone()),
+            None => match (&chapter, chapter_sections.first()) {
+                (Some(chapter), Some(section)) if section.heading.is_empty() => {
+                    Some(chapter.clone())
+                }
+                (_, Some(section)) if !section.heading.is_empty() => {
+                    Some(section.heading[0].clone())
+                }
+                (Some(chapter), _) => Some(chapter.clone()),
+                (None, _) => Some(format!("Chapter {}", idx + 1)),
+            },
+        };
+        let chapter = chapter.as_ref().unwrap();
+
+        for section in chapter_sections.iter_mut() {
+            if section.heading.first() != Some(chapter) {
+                section.heading.insert(0, chapter.clone());
+            }
+            section
+                .metadata
+                .push(("chapter".to_string(), chapter.clone()));
+        }
+        sections.append(&mut chapter_sections);
+    }
+
+    Ok(sections)
+}
+
+pub struct Epub;
+
+impl Extractor for Epub {
+    fn description(&self) -> &'static str {
+        "EPUB"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/epub+zip"]
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        &[("epub", "application/epub+zip")]
+    }
+
+    fn sniff(&self, sample: &Sample) -> Option<&'static str> {
+        (sample.zip_mimetype.as_deref() == Some("application/epub+zip"))
+            .then_some("application/epub+zip")
+    }
+
+    fn extract(&self, file: &Path, _: &str) -> Result<Document, String> {
+        epub_to_sections(file).map(Document::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::{write::FileOptions, ZipWriter};
+
+    const CONTAINER: &str = r#"<?xml version="1.0"?>
+<container xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#;
+
+    fn epub(entries: &[(&str, &str)]) -> Vec<Section> {
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        for (name, contents) in [("META-INF/container.xml", CONTAINER)]
+            .iter()
+            .chain(entries)
+        {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        let mut archive = ZipArchive::new(zip.finish().unwrap()).unwrap();
+        epub_archive_to_sections(&mut archive).unwrap()
+    }
+
+    fn xhtml(body: &str) -> String {
+        format!(
+            r#"<html xmlns="http://www.w3.org/1999/xhtml"><body>{}</body></html>"#,
+            body
+        )
+    }
+
+    fn chapters(sections: &[Section]) -> Vec<(String, &str, &str)> {
+        sections
+            .iter()
+            .map(|section| {
+                let chapter = section
+                    .metadata
+                    .iter()
+                    .find(|(name, _)| name == "chapter")
+                    .map(|(_, value)| value.as_str())
+                    .unwrap();
+                (section.heading_path(), chapter, section.text.as_str())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spine_order_and_nav_titles() {
+        let opf = r#"<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="c1" href="text/one.xhtml" media-type="application/xhtml+xml"/>
+    <item id="c2" href="text/two%20b.xhtml" media-type="application/xhtml+xml"/>
+    <item id="notes" href="text/notes.xhtml" media-type="application/xhtml+xml"/>
+    <item id="img" href="cover.png" media-type="image/png"/>
+  </manifest>
+  <spine>
+    <itemref idref="c2"/>
+    <itemref idref="img"/>
+    <itemref idref="notes" linear="no"/>
+    <itemref idref="c1"/>
+  </spine>
+</package>"#;
+        let nav = r#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops"><body>
+  <nav epub:type="landmarks"><ol><li><a href="text/one.xhtml">Landmark</a></li></ol></nav>
+  <nav epub:type="toc"><ol>
+    <li><a href="text/one.xhtml#start">Chapter <b>One</b></a></li>
+    <li><a href="text/two%20b.xhtml">Chapter Two</a></li>
+  </ol></nav>
+</body></html>"#;
+
+        let sections = epub(&[
+            ("OEBPS/content.opf", opf),
+            ("OEBPS/nav.xhtml", nav),
+            (
+                "OEBPS/text/one.xhtml",
+                &xhtml("<h1>Chapter One</h1><p>First.</p><h2>Part</h2><p>Nested.</p>"),
+            ),
+            ("OEBPS/text/two b.xhtml", &xhtml("<p>Second.</p>")),
+            ("OEBPS/text/notes.xhtml", &xhtml("<p>Not linear.</p>")),
+        ]);
+
+        assert_eq!(
+            chapters(&sections),
+            vec![
+                ("Chapter Two".to_string(), "Chapter Two", "Second."),
+                ("Chapter One".to_string(), "Chapter One", "First."),
+                ("Chapter One > Part".to_string(), "Chapter One", "Nested."),
+            ]
+        );
+    }
+
+    #[test]
+    fn ncx_titles() {
+        let opf = r#"<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+  <manifest>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+    <item id="c1" href="one.html" media-type="application/xhtml+xml"/>
+    <item id="c1b" href="one-continued.html" media-type="application/xhtml+xml"/>
+    <item id="c2" href="two.html" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine toc="ncx"><itemref idref="c1"/><itemref idref="c1b"/><itemref idref="c2"/></spine>
+</package>"#;
+        let ncx = r#"<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/"><navMap>
+  <navPoint id="p1"><navLabel><text>Getting started</text></navLabel><content src="one.html"/>
+    <navPoint id="p1a"><navLabel><text>Nested entry</text></navLabel><content src="one.html#a"/></navPoint>
+  </navPoint>
+  <navPoint id="p2"><navLabel><text>Usage</text></navLabel><content src="two.html"/></navPoint>
+</navMap></ncx>"#;
+
+        let sections = epub(&[
+            ("OEBPS/content.opf", opf),
+            ("OEBPS/toc.ncx", ncx),
+            ("OEBPS/one.html", &xhtml("<p>Install it.</p>")),
+            ("OEBPS/one-continued.html", &xhtml("<p>Then run it.</p>")),
+            ("OEBPS/two.html", &xhtml("<p>Ask away.</p>")),
+        ]);
+
+        assert_eq!(
+            chapters(&sections),
+            vec![
+                (
+                    "Getting started".to_string(),
+                    "Getting started",
+                    "Install it."
+                ),
+                (
+                    "Getting started".to_string(),
+                    "Getting started",
+                    "Then run it."
+                ),
+                ("Usage".to_string(), "Usage", "Ask away."),
+            ]
+        );
+    }
+}
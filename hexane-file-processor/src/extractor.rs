@@ -0,0 +1,422 @@
+use std::{fs::File, io::Read, path::Path, sync::Arc};
+use zip::ZipArchive;
+
+use hexane_shared::{FileProcessor, Tabular};
+
+use crate::document::Document;
+use crate::read_zip_entry;
+use crate::{email, epub, html, markdown, office, tabular, Image, Pdf, PlainText};
+
+/// Extractor turns an uploaded file into a Document. Every supported format
+/// implements it & is listed in the Registry, which drives both the upload
+/// validation and the file processor.
+pub trait Extractor: Send + Sync {
+    /// Name of the format shown on the upload page, e.g. "Word (.docx)".
+    fn description(&self) -> &'static str;
+
+    /// MIME types handled by the extractor, the type is stored along with
+    /// the file and passed back to extract.
+    fn mime_types(&self) -> &'static [&'static str];
+
+    /// Other names clients use for the MIME types, mapped to the type that's
+    /// stored, e.g. "text/x-markdown" to "text/markdown".
+    fn aliases(&self) -> &'static [(&'static str, &'static str)] {
+        &[]
+    }
+
+    /// File extensions (lowercase, without the dot) mapped to their MIME
+    /// type, used when the client doesn't send a useful Content-Type.
+    fn extensions(&self) -> &'static [(&'static str, &'static str)];
+
+    /// Returns the MIME type of the file if its content has the signature of
+    /// a format handled by the extractor.
+    fn sniff(&self, _sample: &Sample) -> Option<&'static str> {
+        None
+    }
+
+    /// Text based formats can't always be told apart by their content, the
+    /// type claimed by the client is trusted between them.
+    fn textual(&self) -> bool {
+        false
+    }
+
+    /// Returns the warnings known before the file is processed, e.g. parts
+    /// of the file that will be dropped, they're shown on upload. It reads
+    /// the file synchronously.
+    fn inspect(&self, _file: &Path, _type: &str) -> Vec<String> {
+        vec![]
+    }
+
+    /// Extracts the text of the file. It reads & parses the file
+    /// synchronously, Registry::extract runs it on a blocking thread.
+    fn extract(&self, file: &Path, r#type: &str) -> Result<Document, String>;
+}
+
+/// Sample is the part of an uploaded file looked at to detect its type.
+pub struct Sample {
+    /// First bytes of the file.
+    pub head: Vec<u8>,
+    /// Entries of the archive if the file is a zip archive.
+    pub zip_entries: Vec<String>,
+    /// Contents of the "mimetype" entry of zip based formats (EPUB &
+    /// OpenDocument).
+    pub zip_mimetype: Option<String>,
+}
+
+impl Sample {
+    const HEAD_SIZE: u64 = 8 * 1024;
+
+    pub fn read(file: &Path) -> Result<Sample, String> {
+        let mut head = vec![];
+        File::open(file)
+            .and_then(|f| f.take(Self::HEAD_SIZE).read_to_end(&mut head))
+            .map_err(|e| format!("reading {}: {}", file.display(), e))?;
+
+        let mut sample = Sample {
+            head,
+            zip_entries: vec![],
+            zip_mimetype: None,
+        };
+
+        if sample.head.starts_with(b"PK\x03\x04") {
+            let f = File::open(file).map_err(|e| format!("reading {}: {}", file.display(), e))?;
+            if let Ok(mut archive) = ZipArchive::new(f) {
+                sample.zip_entries = archive.file_names().map(|n| n.to_string()).collect();
+                sample.zip_mimetype = read_zip_entry(&mut archive, "mimetype")
+                    .ok()
+                    .flatten()
+                    .map(|m| m.trim().to_string());
+            }
+        }
+
+        Ok(sample)
+    }
+
+    /// Reports whether the head looks like text: no NUL bytes or control
+    /// characters other than whitespace. Any 8-bit encoding passes.
+    pub fn is_text(&self) -> bool {
+        !self.head.is_empty()
+            && self.head.iter().all(|b| {
+                !b.is_ascii_control() || matches!(b, b'\t' | b'\n' | b'\r' | b'\x0c' | b'\x1b')
+            })
+    }
+
+    /// Head of the file as text with the byte order mark & leading
+    /// whitespace removed.
+    pub fn text(&self) -> String {
+        let text = String::from_utf8_lossy(&self.head);
+        text.trim_start_matches(['\u{feff}', ' ', '\t', '\r', '\n'])
+            .to_string()
+    }
+}
+
+/// Registry holds the extractors of every supported format.
+pub struct Registry {
+    extractors: Vec<Arc<dyn Extractor>>,
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::with_limits(&Tabular::default())
+    }
+}
+
+impl Registry {
+    /// Creates a registry with every format this crate supports, limited as
+    /// configured.
+    pub fn from_config(config: &FileProcessor) -> Registry {
+        Registry::with_limits(&config.tabular)
+    }
+
+    fn with_limits(tabular: &Tabular) -> Registry {
+        let mut registry = Registry::new();
+        registry.register(PlainText);
+        registry.register(Pdf);
+        registry.register(office::Docx);
+        registry.register(office::Odt);
+        registry.register(html::Html);
+        registry.register(markdown::Markdown);
+        registry.register(epub::Epub);
+        registry.register(tabular::Delimited::new(tabular));
+        registry.register(tabular::Spreadsheet::new(tabular));
+        registry.register(email::Eml);
+        registry.register(email::Mbox);
+        registry.register(Image);
+        registry
+    }
+
+    /// Creates an empty registry, Registry::default() has every format this
+    /// crate supports.
+    pub fn new() -> Registry {
+        Registry { extractors: vec![] }
+    }
+
+    pub fn register(&mut self, extractor: impl Extractor + 'static) {
+        self.extractors.push(Arc::new(extractor));
+    }
+
+    pub fn extractors(&self) -> impl Iterator<Item = &dyn Extractor> {
+        self.extractors.iter().map(|e| e.as_ref())
+    }
+
+    /// Returns the extractor handling the (stored) MIME type.
+    pub fn get(&self, r#type: &str) -> Option<&dyn Extractor> {
+        self.extractors()
+            .find(|extractor| extractor.mime_types().contains(&r#type))
+    }
+
+    /// Extracts the text of a file of the (stored) MIME type. Extraction
+    /// reads & parses the whole file, it runs on a blocking thread so that it
+    /// doesn't hold up the other tasks.
+    pub async fn extract(&self, file: &Path, r#type: &str) -> Result<Document, String> {
+        let extractor = self
+            .extractors
+            .iter()
+            .find(|extractor| extractor.mime_types().contains(&r#type))
+            .cloned()
+            .ok_or(format!("cannot handle file type: `{}'", r#type))?;
+
+        let (file, r#type) = (file.to_path_buf(), r#type.to_string());
+        tokio::task::spawn_blocking(move || extractor.extract(&file, &r#type))
+            .await
+            .map_err(|e| format!("extracting the text: {}", e))?
+    }
+
+    /// Returns the MIME type to store for an upload given the Content-Type
+    /// sent by the client and the file name. Aliases are resolved and the
+    /// file extension is used if the Content-Type isn't supported. None means
+    /// that the file isn't supported.
+    pub fn resolve_type(&self, content_type: &str, file_name: &str) -> Option<&'static str> {
+        let content_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+
+        for extractor in self.extractors() {
+            if let Some(r#type) = extractor.mime_types().iter().find(|t| **t == content_type) {
+                return Some(r#type);
+            }
+            if let Some((_, r#type)) = extractor
+                .aliases()
+                .iter()
+                .find(|(alias, _)| *alias == content_type)
+            {
+                return Some(r#type);
+            }
+        }
+
+        self.type_from_extension(file_name)
+    }
+
+    /// Detects the MIME type of an uploaded file from its content, the
+    /// Content-Type sent by the client and the file name. It fails if the
+    /// content doesn't match the claimed type or if it isn't supported.
+    pub fn detect_type(
+        &self,
+        file: &Path,
+        content_type: &str,
+        file_name: &str,
+    ) -> Result<&'static str, String> {
+        let claimed = self.resolve_type(content_type, file_name);
+        let sample = Sample::read(file)?;
+        let sniffed = self.extractors().find_map(|e| e.sniff(&sample));
+        let textual = |r#type: &str| self.get(r#type).is_some_and(|e| e.textual());
+
+        match (sniffed, claimed) {
+            (Some(sniffed), Some(claimed)) if sniffed == claimed => Ok(sniffed),
+            (Some(sniffed), Some(claimed)) if textual(sniffed) && textual(claimed) => Ok(claimed),
+            (Some(sniffed), Some(claimed)) => Err(format!(
+                "File content ({}) doesn't match its type ({})",
+                sniffed, claimed
+            )),
+            (Some(sniffed), None) => Ok(sniffed),
+            (None, Some(claimed)) if textual(claimed) && sample.is_text() => Ok(claimed),
+            (None, Some(claimed)) => {
+                Err(format!("File content doesn't match its type ({})", claimed))
+            }
+            (None, None) if sample.is_text() && self.get("text/plain").is_some() => {
+                Ok("text/plain")
+            }
+            (None, None) => Err(format!("Unsupported file type ({})", content_type)),
+        }
+    }
+
+    /// Returns the warnings known before the file of the (stored) MIME type
+    /// is processed.
+    pub fn inspect(&self, file: &Path, r#type: &str) -> Vec<String> {
+        self.get(r#type)
+            .map(|extractor| extractor.inspect(file, r#type))
+            .unwrap_or_default()
+    }
+
+    /// Returns the MIME type matching the extension of file_name.
+    pub fn type_from_extension(&self, file_name: &str) -> Option<&'static str> {
+        let (_, extension) = file_name.rsplit_once('.')?;
+        let extension = extension.to_lowercase();
+
+        self.extractors().find_map(|extractor| {
+            extractor
+                .extensions()
+                .iter()
+                .find(|(ext, _)| *ext == extension)
+                .map(|(_, r#type)| *r#type)
+        })
+    }
+
+    /// Value for the accept attribute of file inputs.
+    pub fn accept(&self) -> String {
+        self.extractors()
+            .flat_map(|extractor| {
+                extractor.mime_types().iter().map(|t| t.to_string()).chain(
+                    extractor
+                        .extensions()
+                        .iter()
+                        .map(|(ext, _)| format!(".{}", ext)),
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use temp_dir::TempDir;
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    fn detect(content: &[u8], content_type: &str, file_name: &str) -> Result<&'static str, String> {
+        let dir = TempDir::new().unwrap();
+        let file = dir.child("upload.tmp");
+        std::fs::write(&file, content).unwrap();
+        Registry::default().detect_type(&file, content_type, file_name)
+    }
+
+    #[test]
+    fn pdf_header() {
+        assert_eq!(
+            detect(b"%PDF-1.7\n%\xe2\xe3\xcf\xd3\n", "application/pdf", "a.pdf"),
+            Ok("application/pdf")
+        );
+        assert_eq!(
+            detect(b"\xef\xbb\xbf \n%PDF-1.4\n", "", "a.pdf"),
+            Ok("application/pdf")
+        );
+    }
+
+    #[test]
+    fn pdf_header_in_text() {
+        assert_eq!(
+            detect(
+                b"PDF files start with %PDF-1.7\n",
+                "text/plain",
+                "notes.txt"
+            ),
+            Ok("text/plain")
+        );
+        assert!(detect(b"a %PDF- mention\n", "application/pdf", "a.pdf").is_err());
+    }
+
+    #[test]
+    fn mismatched_content() {
+        assert!(detect(b"\x89PNG\r\n\x1a\n\x00\x00", "application/pdf", "a.pdf").is_err());
+        assert_eq!(
+            detect(
+                b"\x89PNG\r\n\x1a\n\x00\x00",
+                "application/octet-stream",
+                "a.bin"
+            ),
+            Ok("image/png")
+        );
+        assert!(detect(b"\x00\x01\x02\x03", "application/octet-stream", "a.bin").is_err());
+    }
+
+    #[test]
+    fn textual_types() {
+        assert_eq!(
+            detect(b"# Title\n\nSome text.\n", "text/markdown", "a.md"),
+            Ok("text/markdown")
+        );
+        assert_eq!(
+            detect(b"name,age\nada,36\n", "application/octet-stream", "a.csv"),
+            Ok("text/csv")
+        );
+        assert_eq!(
+            detect(b"plain text\n", "application/octet-stream", "a.unknown"),
+            Ok("text/plain")
+        );
+    }
+
+    #[test]
+    fn zip_based_types() {
+        let mut xlsx = ZipWriter::new(std::io::Cursor::new(vec![]));
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        xlsx.start_file("xl/workbook.xml", options).unwrap();
+        xlsx.write_all(b"<workbook/>").unwrap();
+        let xlsx = xlsx.finish().unwrap().into_inner();
+
+        assert_eq!(
+            detect(&xlsx, "application/octet-stream", "a.xlsx"),
+            Ok("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet")
+        );
+        assert!(detect(&xlsx, "application/pdf", "a.pdf").is_err());
+    }
+
+    /// Slow parses for a while without yielding.
+    struct Slow;
+
+    impl Extractor for Slow {
+        fn description(&self) -> &'static str {
+            "slow"
+        }
+
+        fn mime_types(&self) -> &'static [&'static str] {
+            &["application/x-slow"]
+        }
+
+        fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+            &[]
+        }
+
+        fn extract(&self, _file: &Path, _type: &str) -> Result<Document, String> {
+            std::thread::sleep(Duration::from_millis(300));
+            Ok(Document::default())
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn extraction_runs_off_the_runtime() {
+        let mut registry = Registry::new();
+        registry.register(Slow);
+
+        // The only runtime thread keeps running other tasks while the file
+        // is extracted.
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    ticks.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+
+        registry
+            .extract(Path::new("slow"), "application/x-slow")
+            .await
+            .unwrap();
+        ticker.abort();
+        assert!(ticks.load(Ordering::Relaxed) > 5);
+
+        assert!(registry
+            .extract(Path::new("slow"), "application/x-unknown")
+            .await
+            .is_err());
+    }
+}
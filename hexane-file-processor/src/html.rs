@@ -0,0 +1,274 @@
+use scraper::{node::Node, ElementRef, Html as HtmlDocument};
+use std::{fs, path::Path};
+
+use crate::document::{Document, Section, SectionBuilder};
+use crate::extractor::{Extractor, Sample};
+
+/// Elements whose content is never part of the document text.
+const SKIPPED_ELEMENTS: [&str; 13] = [
+    "head", "script", "style", "noscript", "template", "nav", "footer", "aside", "form", "button",
+    "select", "svg", "iframe",
+];
+
+/// ARIA roles used for site navigation & boilerplate.
+const SKIPPED_ROLES: [&str; 4] = ["navigation", "banner", "contentinfo", "search"];
+
+/// HtmlWalker walks the DOM and passes blocks of text to the SectionBuilder.
+/// Inline content is collected until the enclosing block element ends.
+struct HtmlWalker {
+    builder: SectionBuilder,
+    inline: String,
+    // List marker for the next block.
+    prefix: Option<String>,
+    // Open lists, the counter is Some for ordered lists.
+    lists: Vec<Option<u64>>,
+}
+
+impl HtmlWalker {
+    fn flush(&mut self) {
+        let inline = std::mem::take(&mut self.inline);
+        let text = inline.trim();
+        if text.is_empty() {
+            return;
+        }
+
+        match self.prefix.take() {
+            Some(prefix) => self.builder.block(&format!("{}{}", prefix, text)),
+            None => self.builder.block(text),
+        }
+    }
+
+    fn walk(&mut self, element: ElementRef) {
+        let name = element.value().name();
+        if SKIPPED_ELEMENTS.contains(&name)
+            || element
+                .value()
+                .attr("role")
+                .is_some_and(|role| SKIPPED_ROLES.contains(&role))
+            || element.value().attr("hidden").is_some()
+        {
+            return;
+        }
+
+        match name {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.flush();
+                let level = name[1..].parse().unwrap_or(1);
+                let title = element.text().collect::<String>();
+                self.builder.heading(level, &title);
+            }
+            "pre" => {
+                self.flush();
+                let code = element.text().collect::<String>();
+                self.builder
+                    .block(&format!("```\n{}\n```", code.trim_matches('\n')));
+            }
+            "br" => self.inline.push('\n'),
+            "img" => {
+                if let Some(alt) = element.value().attr("alt") {
+                    self.inline.push_str(alt);
+                }
+            }
+            "ul" | "ol" => {
+                self.flush();
+                self.lists.push((name == "ol").then_some(0));
+                self.walk_children(element);
+                self.flush();
+                self.lists.pop();
+            }
+            "li" => {
+                self.flush();
+                let marker = match self.lists.last_mut() {
+                    Some(Some(counter)) => {
+                        *counter += 1;
+                        format!("{}.", counter)
+                    }
+                    _ => "-".to_string(),
+                };
+                let indent = "  ".repeat(self.lists.len().saturating_sub(1));
+                self.prefix = Some(format!("{}{} ", indent, marker));
+                self.walk_children(element);
+                self.flush();
+                self.prefix = None;
+            }
+            "table" => {
+                self.flush();
+                self.builder.start_table();
+                self.walk_children(element);
+                self.builder.end_table();
+            }
+            "tr" => {
+                self.walk_children(element);
+                self.builder.end_row();
+            }
+            "td" | "th" => {
+                self.walk_children(element);
+                self.flush();
+                self.builder.end_cell();
+            }
+            "p" | "div" | "section" | "article" | "main" | "body" | "blockquote" | "dl" | "dt"
+            | "dd" | "figure" | "figcaption" | "caption" | "address" | "hr" | "details"
+            | "summary" => {
+                self.flush();
+                self.walk_children(element);
+                self.flush();
+            }
+            _ => self.walk_children(element),
+        }
+    }
+
+    fn walk_children(&mut self, element: ElementRef) {
+        for child in element.children() {
+            match child.value() {
+                Node::Text(text) => {
+                    // Collapse whitespace like a browser would, but keep a
+                    // single space between words of adjacent nodes.
+                    let text: &str = text;
+                    if text.trim().is_empty() {
+                        if !text.is_empty() && !self.inline.ends_with([' ', '\n']) {
+                            self.inline.push(' ');
+                        }
+                        continue;
+                    }
+                    if text.starts_with(char::is_whitespace) && !self.inline.ends_with(' ') {
+                        self.inline.push(' ');
+                    }
+                    self.inline
+                        .push_str(&text.split_whitespace().collect::<Vec<&str>>().join(" "));
+                    if text.ends_with(char::is_whitespace) {
+                        self.inline.push(' ');
+                    }
+                }
+                Node::Element(_) => {
+                    if let Some(child) = ElementRef::wrap(child) {
+                        self.walk(child);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// html_to_sections extracts the text of an HTML document, it drops scripts,
+/// styles & navigation and keeps headings, lists, code blocks and tables.
+pub fn html_to_sections(html: &str) -> Vec<Section> {
+    let document = HtmlDocument::parse_document(html);
+
+    let mut walker = HtmlWalker {
+        builder: SectionBuilder::new(),
+        inline: String::new(),
+        prefix: None,
+        lists: vec![],
+    };
+    walker.walk(document.root_element());
+    walker.flush();
+
+    walker.builder.finish()
+}
+
+pub struct Html;
+
+impl Extractor for Html {
+    fn description(&self) -> &'static str {
+        "HTML"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["text/html"]
+    }
+
+    fn aliases(&self) -> &'static [(&'static str, &'static str)] {
+        &[("application/xhtml+xml", "text/html")]
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        &[
+            ("html", "text/html"),
+            ("htm", "text/html"),
+            ("xhtml", "text/html"),
+        ]
+    }
+
+    fn sniff(&self, sample: &Sample) -> Option<&'static str> {
+        let text = sample.text().to_lowercase();
+        (text.starts_with("<!doctype html") || text.starts_with("<html")).then_some("text/html")
+    }
+
+    fn textual(&self) -> bool {
+        true
+    }
+
+    fn extract(&self, file: &Path, _: &str) -> Result<Document, String> {
+        let html = fs::read(file).map_err(|e| format!("reading {}: {}", file.display(), e))?;
+        Ok(html_to_sections(&String::from_utf8_lossy(&html)).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boilerplate_is_stripped() {
+        let sections = html_to_sections(
+            r#"<!doctype html><html><head><title>Page</title><style>p { color: red }</style></head>
+            <body>
+              <nav><a href="/">Home</a></nav>
+              <div role="banner">Site banner</div>
+              <script>alert("hi")</script>
+              <p>Kept <b>text</b>.</p>
+              <p hidden>Hidden text</p>
+              <footer>Copyright</footer>
+            </body></html>"#,
+        );
+
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].text, "Kept text.");
+    }
+
+    #[test]
+    fn heading_path() {
+        let sections = html_to_sections(
+            "<h1>Design</h1><p>Intro</p><h2>Storage</h2><p>Disks</p>\
+             <h3>Backups</h3><p>Nightly</p><h2>Network</h2><p>Links</p>",
+        );
+
+        let paths = sections
+            .iter()
+            .map(|section| (section.heading_path(), section.text.as_str()))
+            .collect::<Vec<(String, &str)>>();
+        assert_eq!(
+            paths,
+            vec![
+                ("Design".to_string(), "Intro"),
+                ("Design > Storage".to_string(), "Disks"),
+                ("Design > Storage > Backups".to_string(), "Nightly"),
+                ("Design > Network".to_string(), "Links"),
+            ]
+        );
+    }
+
+    #[test]
+    fn code_blocks_and_lists() {
+        let sections = html_to_sections(
+            "<pre>\nfn main() {\n    run();\n}\n</pre>\
+             <ol><li>first</li><li>second<ul><li>nested</li></ul></li></ol>",
+        );
+
+        assert_eq!(
+            sections[0].text,
+            "```\nfn main() {\n    run();\n}\n```\n\n1. first\n\n2. second\n\n  - nested"
+        );
+    }
+
+    #[test]
+    fn table_cells() {
+        let sections = html_to_sections(
+            "<table><tr><th>name</th><th>age</th></tr>\
+             <tr><td>ada <i>lovelace</i></td><td>36</td></tr></table>",
+        );
+
+        assert_eq!(sections[0].text, "name | age\nada lovelace | 36");
+    }
+}
@@ -0,0 +1,135 @@
+use futures_util::FutureExt;
+use sqlx::{Executor, Pool, Postgres};
+use std::{panic::AssertUnwindSafe, sync::Arc};
+use tokio::time::{sleep, Duration};
+
+use hexane_shared::{Config, Metric, VectorIndex, VectorIndexMethod};
+
+/// background builds the indexes of the configured model & metric when
+/// they're missing, at startup & every 5 minutes after, so that a new model
+/// or dimension is indexed once its first chunks are embedded. Without them
+/// the backend scans every embedding.
+pub async fn background(config: Arc<Config>, model: String, pool: Pool<Postgres>) {
+    loop {
+        let built = AssertUnwindSafe(ensure(&config, &model, &pool))
+            .catch_unwind()
+            .await;
+        if built.is_err() {
+            tracing::error!("building the vector indexes panicked");
+        }
+
+        sleep(Duration::from_secs(300)).await;
+    }
+}
+
+/// ensure calls index when an index of the model's embeddings is missing or
+/// invalid.
+async fn ensure(config: &Config, model: &str, pool: &Pool<Postgres>) {
+    let metric = config.backend.retrieval.metric;
+    let existing = sqlx::query_file!("queries/datasource/get-vector-indexes.sql")
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|row| row.valid)
+        .map(|row| row.name)
+        .collect::<Vec<String>>();
+
+    let missing = sqlx::query_file!("queries/datasource/get-embedding-dimensions.sql", model)
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .any(|row| !existing.contains(&VectorIndex::name(model, row.dimensions, metric)));
+
+    if missing {
+        index(&config.embedding.index, model, metric, pool, false).await;
+    }
+}
+
+/// index builds the approximate nearest neighbour indexes of the model's
+/// embeddings for the metric, one per dimension, & drops the indexes of other
+/// models & metrics. With
+/// rebuild the existing indexes are dropped & built again, to pick up new
+/// parameters or after bulk ingestion (IVFFlat lists are computed from the
+/// rows present when the index is built). Indexes are built concurrently, the
+/// processor & backend can keep running. A concurrent build that failed
+/// leaves an invalid index behind, it's dropped & built again as well.
+pub async fn index(
+    config: &VectorIndex,
+    model: &str,
+    metric: Metric,
+    pool: &Pool<Postgres>,
+    rebuild: bool,
+) {
+    let dimensions = sqlx::query_file!("queries/datasource/get-embedding-dimensions.sql", model)
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.dimensions)
+        .collect::<Vec<i32>>();
+
+    if dimensions.is_empty() {
+        tracing::warn!("no embeddings with model {}, nothing to index", model);
+    }
+
+    let names = dimensions
+        .iter()
+        .map(|dimensions| VectorIndex::name(model, *dimensions, metric))
+        .collect::<Vec<String>>();
+
+    for name in sqlx::query_file!("queries/datasource/get-vector-indexes.sql")
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|row| rebuild || !row.valid || !names.contains(&row.name))
+        .map(|row| row.name)
+    {
+        tracing::info!("dropping index {}", name);
+        pool.execute(format!("DROP INDEX CONCURRENTLY IF EXISTS datasource.{}", name).as_str())
+            .await
+            .unwrap();
+    }
+
+    for (dimensions, name) in dimensions.iter().zip(names) {
+        let (method, parameters) = match config.method {
+            VectorIndexMethod::Hnsw => (
+                "hnsw",
+                format!(
+                    "m = {}, ef_construction = {}",
+                    config.m, config.ef_construction
+                ),
+            ),
+            VectorIndexMethod::IvfFlat => ("ivfflat", format!("lists = {}", config.lists)),
+        };
+
+        tracing::info!(
+            "building {} index {} ({} dimensions)",
+            method,
+            name,
+            dimensions
+        );
+        let start = std::time::Instant::now();
+        pool.execute(
+            format!(
+                "CREATE INDEX CONCURRENTLY IF NOT EXISTS {} ON datasource.embedding
+                 USING {} ((embedding::vector({})) {}) WITH ({})
+                 WHERE {}",
+                name,
+                method,
+                dimensions,
+                metric.operator_class(),
+                parameters,
+                VectorIndex::predicate(model, *dimensions)
+            )
+            .as_str(),
+        )
+        .await
+        .unwrap();
+        tracing::info!("built index {} in {:.2?}", name, start.elapsed());
+    }
+
+    pool.execute("ANALYZE datasource.embedding").await.unwrap();
+}
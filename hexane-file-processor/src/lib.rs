@@ -1,15 +1,162 @@
+use quick_xml::{escape::unescape, events::BytesStart};
 use sha2::{Digest, Sha256};
 use std::{
     collections::{HashMap, HashSet},
     fs,
-    path::PathBuf,
+    io::{Read, Seek},
+    path::{Path, PathBuf},
     process::Stdio,
 };
 use temp_dir::TempDir;
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
+use tokio::runtime::Handle;
+use zip::{result::ZipError, ZipArchive};
+
+use crate::document::{Document, Section};
+use crate::extractor::{Extractor, Sample};
+
+pub mod chunking;
+pub mod document;
+pub mod email;
+pub mod epub;
+pub mod extractor;
+pub mod html;
+pub mod markdown;
+pub mod office;
+pub mod tabular;
+
+pub struct PlainText;
+
+impl Extractor for PlainText {
+    fn description(&self) -> &'static str {
+        "text/plain"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["text/plain"]
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        &[("txt", "text/plain")]
+    }
+
+    fn textual(&self) -> bool {
+        true
+    }
+
+    fn extract(&self, file: &Path, _: &str) -> Result<Document, String> {
+        let text = fs::read(file).map_err(|e| format!("reading {}: {}", file.display(), e))?;
+        Ok(vec![Section::new(String::from_utf8_lossy(&text).to_string())].into())
+    }
+}
+
+pub struct Pdf;
+
+impl Extractor for Pdf {
+    fn description(&self) -> &'static str {
+        "application/pdf"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/pdf"]
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        &[("pdf", "application/pdf")]
+    }
+
+    fn sniff(&self, sample: &Sample) -> Option<&'static str> {
+        // Only a byte order mark or whitespace may precede the header, text
+        // files mentioning it aren't PDFs.
+        sample
+            .text()
+            .starts_with("%PDF-")
+            .then_some("application/pdf")
+    }
+
+    fn extract(&self, file: &Path, _: &str) -> Result<Document, String> {
+        // The text is extracted by external processes, waited on from the
+        // blocking thread.
+        Ok(vec![Handle::current().block_on(pdf_to_section(file))?].into())
+    }
+}
+
+pub struct Image;
+
+impl Extractor for Image {
+    fn description(&self) -> &'static str {
+        "images (PNG, JPEG, TIFF) via OCR"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["image/png", "image/jpeg", "image/tiff"]
+    }
 
-pub async fn pdf_to_text(pdf_file: &PathBuf) -> Result<String, String> {
+    fn aliases(&self) -> &'static [(&'static str, &'static str)] {
+        &[("image/jpg", "image/jpeg"), ("image/pjpeg", "image/jpeg")]
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        &[
+            ("png", "image/png"),
+            ("jpg", "image/jpeg"),
+            ("jpeg", "image/jpeg"),
+            ("tif", "image/tiff"),
+            ("tiff", "image/tiff"),
+        ]
+    }
+
+    fn sniff(&self, sample: &Sample) -> Option<&'static str> {
+        let head = sample.head.as_slice();
+        if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some("image/png")
+        } else if head.starts_with(b"\xff\xd8\xff") {
+            Some("image/jpeg")
+        } else if head.starts_with(b"II*\x00") || head.starts_with(b"MM\x00*") {
+            Some("image/tiff")
+        } else {
+            None
+        }
+    }
+
+    fn extract(&self, file: &Path, _: &str) -> Result<Document, String> {
+        Handle::current()
+            .block_on(image_to_sections(file))
+            .map(Document::from)
+    }
+}
+
+/// xml_attribute returns the unescaped value of an element's attribute.
+pub(crate) fn xml_attribute(e: &BytesStart, name: &str) -> Option<String> {
+    let attribute = e.try_get_attribute(name).ok().flatten()?;
+    let value = String::from_utf8_lossy(&attribute.value);
+    unescape(&value).ok().map(|v| v.into_owned())
+}
+
+/// read_zip_entry reads a file from the archive as a string, it returns None
+/// if the archive doesn't contain the file.
+pub(crate) fn read_zip_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+) -> Result<Option<String>, String> {
+    let mut entry = match archive.by_name(name) {
+        Ok(entry) => entry,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(format!("reading {} from archive: {}", name, e)),
+    };
+
+    let mut contents = String::new();
+    entry
+        .read_to_string(&mut contents)
+        .map_err(|e| format!("reading {} from archive: {}", name, e))?;
+
+    Ok(Some(contents))
+}
+
+/// pdf_to_section extracts the text of every page, along with the text of
+/// the images on it, and records where each page starts.
+pub async fn pdf_to_section(pdf_file: &Path) -> Result<Section, String> {
     let pdf_file_bytes = fs::read(pdf_file).unwrap();
 
     let mut child = Command::new("pdftotext")
@@ -37,6 +184,10 @@ pub async fn pdf_to_text(pdf_file: &PathBuf) -> Result<String, String> {
 
     let raw_output = String::from_utf8(output.stdout).unwrap();
     let mut page_output: Vec<String> = raw_output.split('\u{C}').map(|x| x.to_string()).collect();
+    // pdftotext ends every page with a form feed, including the last one.
+    if page_output.len() > 1 && page_output.last().is_some_and(|x| x.is_empty()) {
+        page_output.pop();
+    }
 
     let binding = TempDir::with_prefix("hexane").unwrap();
     let temp_dir = binding.path();
@@ -72,18 +223,7 @@ pub async fn pdf_to_text(pdf_file: &PathBuf) -> Result<String, String> {
             continue;
         }
 
-        Command::new("convert")
-            .args([
-                "-units",
-                "PixelsPerInch",
-                path.to_str().unwrap(),
-                "-resample",
-                "300",
-                path.to_str().unwrap(),
-            ])
-            .output()
-            .await
-            .unwrap();
+        resample_image(&path).await?;
 
         let image_data = fs::read(&path).unwrap();
         let file_hash = Sha256::digest(<Vec<u8> as AsRef<[u8]>>::as_ref(&image_data))
@@ -97,26 +237,7 @@ pub async fn pdf_to_text(pdf_file: &PathBuf) -> Result<String, String> {
         file_hash_seen.insert(file_hash);
 
         // tracing::trace!("running tesseract on file: {} ({})", &pdf_file.display(), &entry.path().display());
-        let mut child = Command::new("tesseract")
-            .args(["--dpi", "300", "-", "-", "tsv"])
-            .stdin(Stdio::piped())
-            .stderr(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .unwrap();
-
-        child
-            .stdin
-            .as_mut()
-            .ok_or("Child process stdin has not been captured!")
-            .unwrap()
-            .write_all(&image_data)
-            .await
-            .unwrap();
-
-        let output = child.wait_with_output().await.unwrap();
-        if output.status.success() {
-            let raw_output = String::from_utf8(output.stdout).unwrap();
+        if let Ok(parsed_output) = ocr_image(&image_data).await {
             let output_page_number: usize = path
                 .file_name()
                 .unwrap()
@@ -128,15 +249,126 @@ pub async fn pdf_to_text(pdf_file: &PathBuf) -> Result<String, String> {
                 .parse::<usize>()
                 .unwrap();
 
-            let parsed_output = tesseract_tsv_to_text(&raw_output);
-            page_output[output_page_number].push_str(&parsed_output);
-        } else {
-            // let err = String::from_utf8(output.stderr).unwrap();
-            // return Err(format!("External command failed:\n {}", err));
+            // Page numbers start at 1.
+            if let Some(page) = page_output.get_mut(output_page_number.saturating_sub(1)) {
+                page.push_str(&parsed_output);
+            }
+        }
+    }
+
+    let mut section = Section::new(String::new());
+    for (idx, page) in page_output.iter().enumerate() {
+        if idx > 0 {
+            section.text.push('\n');
+        }
+        section.pages.push((section.text.len(), idx as i32 + 1));
+        section.text.push_str(page);
+    }
+
+    Ok(section)
+}
+
+/// image_to_sections runs OCR on an uploaded image, every frame of a
+/// multi-page TIFF is a separate page.
+pub async fn image_to_sections(file: &Path) -> Result<Vec<Section>, String> {
+    let binding =
+        TempDir::with_prefix("hexane").map_err(|e| format!("creating temp dir: {}", e))?;
+    let temp_dir = binding.path();
+
+    // Split the image into one PNG per frame, single frame images produce a
+    // single page. Photos are rotated as per their EXIF orientation.
+    let output = Command::new("convert")
+        .arg(file)
+        .args(["-auto-orient", "+adjoin"])
+        .arg(temp_dir.join("page-%04d.png"))
+        .output()
+        .await
+        .map_err(|e| format!("running convert: {}", e))?;
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("External command failed:\n {}", err));
+    }
+
+    let mut pages = fs::read_dir(temp_dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<PathBuf>, _>>()
+        })
+        .map_err(|e| format!("reading {}: {}", temp_dir.display(), e))?;
+    pages.sort();
+
+    let mut sections: Vec<Section> = vec![];
+    for (idx, page) in pages.iter().enumerate() {
+        resample_image(page).await?;
+        let image_data =
+            fs::read(page).map_err(|e| format!("reading {}: {}", page.display(), e))?;
+        let text = ocr_image(&image_data).await?;
+        if text.trim().is_empty() {
+            continue;
+        }
+
+        let mut section = Section::new(text);
+        if pages.len() > 1 {
+            section.heading = vec![format!("Page {}", idx + 1)];
         }
+        section.pages = vec![(0, idx as i32 + 1)];
+        sections.push(section);
+    }
+
+    Ok(sections)
+}
+
+/// Resamples the image in place to 300 DPI, the resolution tesseract works
+/// best with.
+async fn resample_image(path: &Path) -> Result<(), String> {
+    let output = Command::new("convert")
+        .args(["-units", "PixelsPerInch"])
+        .arg(path)
+        .args(["-resample", "300"])
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| format!("running convert: {}", e))?;
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("External command failed:\n {}", err));
+    }
+
+    Ok(())
+}
+
+/// Runs tesseract on the image and returns the text laid out the way it
+/// appears in the image.
+async fn ocr_image(image_data: &[u8]) -> Result<String, String> {
+    let mut child = Command::new("tesseract")
+        .args(["--dpi", "300", "-", "-", "tsv"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("running tesseract: {}", e))?;
+
+    child
+        .stdin
+        .as_mut()
+        .ok_or("Child process stdin has not been captured!")?
+        .write_all(image_data)
+        .await
+        .map_err(|e| format!("writing to tesseract: {}", e))?;
+
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| format!("running tesseract: {}", e))?;
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("External command failed:\n {}", err));
     }
 
-    Ok(page_output.join("\n"))
+    Ok(tesseract_tsv_to_text(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
 }
 
 #[derive(Debug)]
@@ -147,6 +379,8 @@ struct OcrRow<'a> {
     width: u32,
 }
 
+/// Rebuilds the text from tesseract's TSV output, words are placed on lines
+/// as per their position with the gaps between them turned into spaces.
 fn tesseract_tsv_to_text(file: &str) -> String {
     let mut output: String = String::new();
 
@@ -155,78 +389,147 @@ fn tesseract_tsv_to_text(file: &str) -> String {
         .map(|line| line.split('\t').collect::<Vec<&str>>())
         .collect::<Vec<Vec<&str>>>();
 
-    let (headers, tsv_rows) = binding.split_first().unwrap();
+    let Some((headers, tsv_rows)) = binding.split_first() else {
+        return output;
+    };
 
     let ocr_rows: Vec<OcrRow> = tsv_rows
         .iter()
         .filter_map(|tsv| {
-            let mut ocr_row: HashMap<&str, &str> = HashMap::new();
-            for i in 0..tsv.len() {
-                let key = headers[i];
-                match key {
-                    "level" | "top" | "left" | "text" | "width" => {
-                        ocr_row.insert(key, tsv[i]);
-                    }
-                    _ => continue,
-                }
-            }
-
-            if *ocr_row.get("level").unwrap() != "5"
-                || ocr_row.get("text").unwrap().trim().is_empty()
-            {
+            let ocr_row: HashMap<&str, &str> = headers
+                .iter()
+                .zip(tsv.iter())
+                .filter(|(key, _)| matches!(**key, "level" | "top" | "left" | "text" | "width"))
+                .map(|(key, value)| (*key, *value))
+                .collect();
+
+            let text = ocr_row.get("text")?;
+            if *ocr_row.get("level")? != "5" || text.trim().is_empty() {
                 return None;
             }
 
             Some(OcrRow {
-                top: ocr_row.get("top").unwrap().parse().unwrap(),
-                left: ocr_row.get("left").unwrap().parse().unwrap(),
-                width: ocr_row.get("width").unwrap().parse().unwrap(),
-                text: ocr_row.get("text").unwrap(),
+                top: ocr_row.get("top")?.parse().ok()?,
+                left: ocr_row.get("left")?.parse().ok()?,
+                width: ocr_row.get("width")?.parse().ok()?,
+                text,
             })
         })
         .collect();
 
-    let mut counter: u32 = u32::MAX;
-    for i in 0..ocr_rows.len() - 1 {
-        if ocr_rows[i + 1].left > (ocr_rows[i].left + ocr_rows[i].width) {
-            counter = std::cmp::min(
-                counter,
-                ocr_rows[i + 1].left - (ocr_rows[i].left + ocr_rows[i].text.len() as u32),
-            );
-        }
-    }
-    let pixel_to_space = counter - 1;
+    // Pixels per space, the smallest gap between two words on a line. A
+    // single word or words without gaps leave it unknown, every gap is then a
+    // single space.
+    let pixel_to_space = ocr_rows
+        .windows(2)
+        .filter(|pair| pair[1].left > pair[0].left + pair[0].width)
+        .map(|pair| {
+            pair[1]
+                .left
+                .saturating_sub(pair[0].left + pair[0].text.len() as u32)
+        })
+        .min()
+        .map_or(u32::MAX, |gap| gap.saturating_sub(1))
+        .max(1);
 
     let mut pixel_top = 0;
     let mut pixel_left = 0;
-    let mut pixel_left_actual = 0;
+    let mut pixel_left_actual: u32 = 0;
 
     for row in ocr_rows {
         if pixel_left > row.left && row.top > pixel_top {
-            output.push_str("\n");
+            output.push('\n');
             pixel_left = 0;
             pixel_left_actual = 0;
         }
         pixel_top = row.top;
 
-        let mut spaces = std::cmp::max(1, (row.left - pixel_left) / pixel_to_space);
-        let spaces_actual = std::cmp::max(1, (row.left - pixel_left_actual) / pixel_to_space);
+        let mut spaces = std::cmp::max(1, row.left.saturating_sub(pixel_left) / pixel_to_space);
+        let spaces_actual = std::cmp::max(
+            1,
+            row.left.saturating_sub(pixel_left_actual) / pixel_to_space,
+        );
 
         if spaces > 2 && spaces_actual > 4 && pixel_left_actual < pixel_left {
             spaces = spaces_actual;
         }
 
-        output.push_str(
-            &std::iter::repeat(' ')
-                .take(spaces as usize)
-                .collect::<String>(),
-        );
+        output.push_str(&" ".repeat(spaces as usize));
         output.push_str(row.text);
 
         pixel_left = row.left + row.width;
-        pixel_left_actual +=
-            (spaces * pixel_to_space) + (row.text.chars().count() as u32 * pixel_to_space);
+        pixel_left_actual = pixel_left_actual.saturating_add(
+            (spaces + row.text.chars().count() as u32).saturating_mul(pixel_to_space),
+        );
     }
 
     output
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TSV_HEADER: &str =
+        "level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext";
+
+    fn tsv(words: &[(u32, u32, u32, &str)]) -> String {
+        let mut tsv = vec![
+            TSV_HEADER.to_string(),
+            "1\t1\t0\t0\t0\t0\t0\t0\t1000\t1000\t-1\t".to_string(),
+        ];
+        for (idx, (left, top, width, text)) in words.iter().enumerate() {
+            tsv.push(format!(
+                "5\t1\t1\t1\t1\t{}\t{}\t{}\t{}\t20\t95.0\t{}",
+                idx + 1,
+                left,
+                top,
+                width,
+                text
+            ));
+        }
+        tsv.join("\n") + "\n"
+    }
+
+    #[test]
+    fn tsv_single_word() {
+        assert_eq!(
+            tesseract_tsv_to_text(&tsv(&[(40, 10, 60, "Hello")])),
+            " Hello"
+        );
+        assert_eq!(tesseract_tsv_to_text(""), "");
+        assert_eq!(tesseract_tsv_to_text(TSV_HEADER), "");
+    }
+
+    #[test]
+    fn tsv_adjacent_words() {
+        // Gaps of 0 & 1 pixel used to underflow or divide by zero.
+        let text = tesseract_tsv_to_text(&tsv(&[(0, 10, 3, "ab"), (3, 10, 3, "cd")]));
+        assert_eq!(text, " ab cd");
+        let text = tesseract_tsv_to_text(&tsv(&[(0, 10, 2, "ab"), (3, 10, 2, "cd")]));
+        assert_eq!(text, " ab cd");
+    }
+
+    #[test]
+    fn tsv_two_columns() {
+        let text = tesseract_tsv_to_text(&tsv(&[
+            (0, 10, 40, "left"),
+            (50, 10, 40, "one"),
+            (400, 10, 40, "right"),
+            (450, 10, 40, "one"),
+            (0, 40, 40, "left"),
+            (50, 40, 40, "two"),
+            (400, 40, 40, "right"),
+            (450, 40, 40, "two"),
+        ]));
+
+        let lines = text.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 2);
+        for (line, row) in lines.iter().zip(["one", "two"]) {
+            let words = line.split_whitespace().collect::<Vec<&str>>();
+            assert_eq!(words, vec!["left", row, "right", row]);
+            // Columns are separated by a wide gap.
+            assert!(line.contains(&format!("{}   ", row)));
+        }
+    }
+}
@@ -1,22 +1,31 @@
-use clap::Parser;
-use serde_json::json;
+use clap::{Parser, Subcommand};
+use futures_util::FutureExt;
+use serde_json::{json, Map, Value};
 use sqlx::{
     postgres::{PgListener, PgPoolOptions},
-    Pool, Postgres, QueryBuilder,
+    Connection, Pool, Postgres, QueryBuilder, Transaction,
 };
 use std::{
     fs,
+    panic::AssertUnwindSafe,
     path::PathBuf,
     sync::{Arc, Mutex},
     time::Instant,
 };
-use text_splitter::TextSplitter;
 use tokio::time::{sleep, Duration};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use uuid::Uuid;
 
-use hexane_file_processor::pdf_to_text;
-use hexane_shared::{get_embeddings, Config};
+use hexane_file_processor::{chunking::Chunker, document::Document, extractor::Registry};
+use hexane_shared::{
+    embedding::{get_embeddings, Embedder, EmbeddingError},
+    Config,
+};
+
+mod index;
+mod reembed;
+
+use crate::{index::index, reembed::reembed};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -28,15 +37,48 @@ struct Args {
     /// Path to config file
     #[arg(long, env, default_value = "config.toml")]
     config: PathBuf,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-struct Embedding<'a>(Uuid, &'a str, &'a Vec<f64>);
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Re-embed all chunks embedded with another model than the configured
+    /// one, the processor does it in the background
+    Reembed {
+        /// Number of chunks embedded at a time
+        #[arg(long, default_value_t = 100)]
+        batch_size: i64,
+    },
+    /// Build the nearest neighbour indexes of the configured model's
+    /// embeddings, the processor builds the missing ones in the background
+    Index {
+        /// Drop & build the indexes again, e.g. after bulk ingestion
+        #[arg(long)]
+        rebuild: bool,
+    },
+}
+
+/// Embedding is a chunk of a file along with its embedding, as inserted in
+/// datasource.embedding.
+struct Embedding<'a> {
+    file_id: Uuid,
+    text: &'a str,
+    section: Option<String>,
+    metadata: Option<Value>,
+    pages: Option<(i32, i32)>,
+    chars: (i32, i32),
+    token: i32,
+    embedding: &'a [f64],
+}
 
 struct Datasource {
     pub id: Uuid,
     pub user_id: Uuid,
     pub path: String,
     pub r#type: String,
+    pub category: String,
 }
 
 #[tokio::main]
@@ -58,6 +100,8 @@ async fn main() {
         .unwrap(),
     );
 
+    let embedder = Arc::new(Embedder::new(&config.embedding).unwrap_or_else(|e| panic!("{}", e)));
+
     let pool = PgPoolOptions::new()
         .max_connections(20)
         .min_connections(4)
@@ -65,6 +109,44 @@ async fn main() {
         .await
         .unwrap_or_else(|_| panic!("connect to postgres db: {}", args.database_url));
 
+    match args.command {
+        Some(Command::Reembed { batch_size }) => {
+            reembed(&embedder, &pool, batch_size).await;
+            return;
+        }
+        Some(Command::Index { rebuild }) => {
+            index(
+                &config.embedding.index,
+                embedder.model(),
+                config.backend.retrieval.metric,
+                &pool,
+                rebuild,
+            )
+            .await;
+            return;
+        }
+        None => {}
+    }
+
+    let extractors = Arc::new(Registry::from_config(&config.file_processor));
+    let chunker = Arc::new(Chunker::new(&config).unwrap_or_else(|e| panic!("{}", e)));
+
+    tokio::spawn(index::background(
+        Arc::clone(&config),
+        embedder.model().to_string(),
+        pool.clone(),
+    ));
+
+    // Chunks embedded with another model are re-embedded in the background,
+    // they're searched as they are until then.
+    if config.file_processor.reembed_batch_size > 0 {
+        tokio::spawn(reembed::background(
+            Arc::clone(&embedder),
+            pool.clone(),
+            config.file_processor.reembed_batch_size,
+        ));
+    }
+
     let initial_files = sqlx::query_file!("queries/datasource/get-unprocessed-file-count.sql")
         .fetch_one(&pool)
         .await
@@ -123,13 +205,24 @@ async fn main() {
 
             let pool = pool.clone();
             let config = Arc::clone(&config);
+            let extractors = Arc::clone(&extractors);
+            let chunker = Arc::clone(&chunker);
+            let embedder = Arc::clone(&embedder);
 
             let active_process = Arc::clone(&active_process);
             let files_to_process = Arc::clone(&files_to_process);
 
             tokio::spawn(async move {
                 tracing::trace!("polling the database");
-                process_file(config, pool, files_to_process).await;
+                process_file(
+                    config,
+                    extractors,
+                    chunker,
+                    embedder,
+                    pool,
+                    files_to_process,
+                )
+                .await;
                 *active_process.lock().unwrap() -= 1;
             });
         }
@@ -141,6 +234,9 @@ async fn main() {
 /// from the queue.
 async fn process_file(
     config: Arc<Config>,
+    extractors: Arc<Registry>,
+    chunker: Arc<Chunker>,
+    embedder: Arc<Embedder>,
     pool: Pool<Postgres>,
     files_to_process: Arc<Mutex<u32>>,
 ) {
@@ -159,48 +255,191 @@ async fn process_file(
     let to_process = to_process.unwrap();
 
     tracing::debug!("processing file: {}", &to_process.id);
-    let file_text = match to_process.r#type.as_str() {
-        "text/plain" => fs::read_to_string(&config.file_store.join(&to_process.path)).unwrap(),
-        "application/pdf" => pdf_to_text(&config.file_store.join(&to_process.path))
+
+    // Processing runs in savepoints so that a failed attempt is rolled back
+    // while the file stays locked, panics are recorded as failures too.
+    let result = AssertUnwindSafe(process(
+        &config,
+        &extractors,
+        &chunker,
+        &embedder,
+        &to_process,
+        &mut tx,
+    ))
+    .catch_unwind()
+    .await
+    .unwrap_or_else(|panic| {
+        Err(panic
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| panic.downcast_ref::<&str>().map(|e| e.to_string()))
+            .unwrap_or_else(|| "processing panicked".to_string()))
+    });
+
+    match result {
+        Ok(warnings) => {
+            sqlx::query_file!(
+                "queries/datasource/set-processed.sql",
+                &to_process.id,
+                &warnings
+            )
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap();
+        }
+        Err(err) => {
+            let failed = sqlx::query_file!(
+                "queries/datasource/set-attempt-failed.sql",
+                &to_process.id,
+                &err,
+                config.file_processor.retry_delay as f64,
+                config.file_processor.max_attempts as i32
+            )
+            .fetch_one(&mut *tx)
             .await
-            .unwrap(),
-        _ => panic!("cannot handle file type: `{}'", &to_process.r#type),
-    };
+            .unwrap();
+
+            if failed.failed {
+                tracing::error!(
+                    "processing file {} failed after {} attempts: {}",
+                    &to_process.id,
+                    failed.attempts,
+                    err
+                );
+            } else {
+                tracing::warn!(
+                    "processing file {} failed (attempt {}), retrying later: {}",
+                    &to_process.id,
+                    failed.attempts,
+                    err
+                );
+            }
+        }
+    }
+
+    tx.commit().await.unwrap();
+}
+
+/// process extracts the text of the file, chunks it & stores the chunks along
+/// with their embeddings. It returns the warnings raised by the extractor.
+/// The embeddings are charged & cached in a savepoint of their own that's
+/// kept when storing the chunks fails: the provider has billed for them &
+/// retries are served from the cache.
+async fn process(
+    config: &Config,
+    extractors: &Registry,
+    chunker: &Chunker,
+    embedder: &Embedder,
+    to_process: &Datasource,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<String>, String> {
+    let file_path = config.file_store.join(&to_process.path);
+    let document: Document = extractors.extract(&file_path, &to_process.r#type).await?;
 
     tracing::debug!("got file's text data: {}", &to_process.id);
 
-    // A helpful rule of thumb is that one token generally corresponds to ~4
-    // characters of text for common English text. This translates to roughly ¾
-    // of a word (so 100 tokens ~= 75 words).
-    // https://platform.openai.com/tokenizer
-    //
-    // We're going to chunk by 500 tokens, i.e. ~2000 characters.
-    let splitter = TextSplitter::default().with_trim_chunks(true);
-    let max_characters = 1800..2000;
-    let chunks = splitter
-        .chunks(&file_text, max_characters)
-        .collect::<Vec<&str>>();
-
-    let embeddings_vec: Vec<Vec<f64>> =
-        get_embeddings(json!(chunks), &config, &to_process.user_id, &mut tx).await;
+    // Sections are chunked separately, the heading path is embedded along
+    // with the chunk and stored with it so that answers can cite the section.
+    let chunks = chunker.chunk(&document, &to_process.category);
+
+    if chunks.is_empty() {
+        return Err("no text found in the file".to_string());
+    }
+
+    let texts = chunks
+        .iter()
+        .map(|chunk| chunk.with_header())
+        .collect::<Vec<String>>();
+
+    let embedding_input = chunks
+        .iter()
+        .map(|chunk| chunk.with_heading())
+        .collect::<Vec<String>>();
+
+    let mut savepoint = tx.begin().await.map_err(|e| e.to_string())?;
+    let embeddings_vec = get_embeddings(
+        embedder,
+        &embedding_input,
+        &to_process.user_id,
+        &mut savepoint,
+    )
+    .await;
+    // Batches embedded before a provider error are kept too, only a
+    // database error leaves the savepoint unusable.
+    match &embeddings_vec {
+        Err(EmbeddingError::Database(_)) => savepoint.rollback().await,
+        _ => savepoint.commit().await,
+    }
+    .map_err(|e| e.to_string())?;
+    let embeddings_vec: Vec<Vec<f64>> = embeddings_vec.map_err(|e| e.to_string())?;
 
     let mut embeddings: Vec<Embedding> = vec![];
     for x in 0..chunks.len() {
-        embeddings.push(Embedding(to_process.id, chunks[x], &embeddings_vec[x]));
+        let section = chunks[x].section;
+        let metadata = (!section.metadata.is_empty()).then(|| {
+            Value::Object(
+                section
+                    .metadata
+                    .iter()
+                    .map(|(name, value)| (name.clone(), json!(value)))
+                    .collect::<Map<String, Value>>(),
+            )
+        });
+        embeddings.push(Embedding {
+            file_id: to_process.id,
+            text: &texts[x],
+            section: chunks[x].heading_path(),
+            metadata,
+            pages: chunks[x].pages,
+            chars: chunks[x].chars,
+            token: chunker.count_tokens(&embedding_input[x]) as i32,
+            embedding: &embeddings_vec[x],
+        });
     }
 
-    let mut query_builder =
-        QueryBuilder::new("INSERT INTO datasource.embedding (file_id, text, embedding) ");
+    let mut savepoint = tx.begin().await.map_err(|e| e.to_string())?;
+    if let Err(err) = insert_embeddings(&embeddings, embedder.model(), &mut savepoint).await {
+        savepoint.rollback().await.map_err(|e| e.to_string())?;
+        return Err(err);
+    }
+    savepoint.commit().await.map_err(|e| e.to_string())?;
 
-    query_builder.push_values(embeddings, |mut b, new| {
-        b.push_bind(new.0).push_bind(new.1).push_bind(new.2);
-    });
-    query_builder.build().execute(&mut *tx).await.unwrap();
+    Ok(document.warnings)
+}
 
-    sqlx::query_file!("queries/datasource/set-processed.sql", &to_process.id)
-        .fetch_one(&mut *tx)
-        .await
-        .unwrap();
+/// insert_embeddings stores the chunks of a file, in statements of at most
+/// 1000 rows: Postgres takes at most 65535 parameters per statement.
+async fn insert_embeddings(
+    embeddings: &[Embedding<'_>],
+    model: &str,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<(), String> {
+    for rows in embeddings.chunks(1000) {
+        let mut query_builder = QueryBuilder::new(
+            "INSERT INTO datasource.embedding (file_id, text, section, metadata, page_start, page_end, char_start, char_end, token, model, dimensions, embedding) ",
+        );
 
-    tx.commit().await.unwrap();
+        query_builder.push_values(rows, |mut b, new| {
+            let (char_start, char_end) = new.chars;
+            b.push_bind(new.file_id)
+                .push_bind(new.text)
+                .push_bind(&new.section)
+                .push_bind(&new.metadata)
+                .push_bind(new.pages.map(|pages| pages.0))
+                .push_bind(new.pages.map(|pages| pages.1))
+                .push_bind(char_start)
+                .push_bind(char_end)
+                .push_bind(new.token)
+                .push_bind(model)
+                .push_bind(new.embedding.len() as i32)
+                .push_bind(new.embedding);
+        });
+        query_builder
+            .build()
+            .execute(&mut **tx)
+            .await
+            .map_err(|e| format!("inserting embeddings: {}", e))?;
+    }
+
+    Ok(())
 }
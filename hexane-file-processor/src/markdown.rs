@@ -0,0 +1,212 @@
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use std::{fs, path::Path};
+
+use crate::document::{Document, Section, SectionBuilder};
+use crate::extractor::Extractor;
+
+/// Passes the collected inline text to the builder as a block, prefixed with
+/// the list marker if this is the first block of a list item.
+fn flush(builder: &mut SectionBuilder, inline: &mut String, prefix: &mut Option<String>) {
+    let block = std::mem::take(inline);
+    let text = block.trim();
+    if text.is_empty() {
+        return;
+    }
+
+    match prefix.take() {
+        Some(prefix) => builder.block(&format!("{}{}", prefix, text)),
+        None => builder.block(text),
+    }
+}
+
+/// markdown_to_sections extracts the text of a Markdown document, it keeps
+/// headings, lists, code blocks and tables. Raw HTML is dropped.
+pub fn markdown_to_sections(markdown: &str) -> Vec<Section> {
+    let mut builder = SectionBuilder::new();
+
+    let mut inline = String::new();
+    let mut prefix: Option<String> = None;
+
+    // Open lists, the counter is Some for ordered lists.
+    let mut lists: Vec<Option<u64>> = vec![];
+    // Code block language & contents while inside a code block.
+    let mut code: Option<(String, String)> = None;
+
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS;
+
+    for event in Parser::new_ext(markdown, options) {
+        match event {
+            Event::Start(tag) => match tag {
+                Tag::Heading { .. }
+                | Tag::Paragraph
+                | Tag::BlockQuote
+                | Tag::HtmlBlock
+                | Tag::FootnoteDefinition(_) => {
+                    flush(&mut builder, &mut inline, &mut prefix);
+                    if let Tag::FootnoteDefinition(label) = tag {
+                        prefix = Some(format!("[{}] ", label));
+                    }
+                }
+                Tag::CodeBlock(kind) => {
+                    flush(&mut builder, &mut inline, &mut prefix);
+                    let lang = match kind {
+                        CodeBlockKind::Fenced(lang) => lang.to_string(),
+                        CodeBlockKind::Indented => String::new(),
+                    };
+                    code = Some((lang, String::new()));
+                }
+                Tag::List(start) => {
+                    flush(&mut builder, &mut inline, &mut prefix);
+                    lists.push(start.map(|n| n.saturating_sub(1)));
+                }
+                Tag::Item => {
+                    flush(&mut builder, &mut inline, &mut prefix);
+                    let marker = match lists.last_mut() {
+                        Some(Some(counter)) => {
+                            *counter += 1;
+                            format!("{}.", counter)
+                        }
+                        _ => "-".to_string(),
+                    };
+                    let indent = "  ".repeat(lists.len().saturating_sub(1));
+                    prefix = Some(format!("{}{} ", indent, marker));
+                }
+                Tag::Table(_) => {
+                    flush(&mut builder, &mut inline, &mut prefix);
+                    builder.start_table();
+                }
+                _ => {}
+            },
+            Event::End(tag) => match tag {
+                TagEnd::Heading(level) => {
+                    builder.heading(level as usize, &inline);
+                    inline.clear();
+                }
+                TagEnd::CodeBlock => {
+                    if let Some((lang, text)) = code.take() {
+                        builder.block(&format!("```{}\n{}\n```", lang, text.trim_end()));
+                    }
+                }
+                TagEnd::Paragraph
+                | TagEnd::BlockQuote
+                | TagEnd::HtmlBlock
+                | TagEnd::FootnoteDefinition => {
+                    flush(&mut builder, &mut inline, &mut prefix);
+                }
+                TagEnd::Item => {
+                    flush(&mut builder, &mut inline, &mut prefix);
+                    prefix = None;
+                }
+                TagEnd::List(_) => {
+                    flush(&mut builder, &mut inline, &mut prefix);
+                    lists.pop();
+                }
+                TagEnd::TableCell => {
+                    flush(&mut builder, &mut inline, &mut prefix);
+                    builder.end_cell();
+                }
+                TagEnd::TableHead | TagEnd::TableRow => builder.end_row(),
+                TagEnd::Table => builder.end_table(),
+                _ => {}
+            },
+            Event::Text(text) => match code.as_mut() {
+                Some((_, code)) => code.push_str(&text),
+                None => inline.push_str(&text),
+            },
+            Event::Code(text) => inline.push_str(&format!("`{}`", text)),
+            Event::FootnoteReference(label) => inline.push_str(&format!("[{}]", label)),
+            Event::TaskListMarker(checked) => {
+                inline.push_str(if checked { "[x] " } else { "[ ] " })
+            }
+            Event::SoftBreak => inline.push(' '),
+            Event::HardBreak => inline.push('\n'),
+            Event::Rule => flush(&mut builder, &mut inline, &mut prefix),
+            Event::Html(_) | Event::InlineHtml(_) => {}
+        }
+    }
+    flush(&mut builder, &mut inline, &mut prefix);
+
+    builder.finish()
+}
+
+pub struct Markdown;
+
+impl Extractor for Markdown {
+    fn description(&self) -> &'static str {
+        "Markdown"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["text/markdown"]
+    }
+
+    fn aliases(&self) -> &'static [(&'static str, &'static str)] {
+        &[("text/x-markdown", "text/markdown")]
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        &[("md", "text/markdown"), ("markdown", "text/markdown")]
+    }
+
+    fn textual(&self) -> bool {
+        true
+    }
+
+    fn extract(&self, file: &Path, _: &str) -> Result<Document, String> {
+        let markdown = fs::read(file).map_err(|e| format!("reading {}: {}", file.display(), e))?;
+        Ok(markdown_to_sections(&String::from_utf8_lossy(&markdown)).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heading_path() {
+        let sections = markdown_to_sections(
+            "# Design\n\nIntro\n\n## Storage\n\nDisks\n\n### Backups\n\nNightly\n\n# Usage\n\nRun it\n",
+        );
+
+        let paths = sections
+            .iter()
+            .map(|section| (section.heading_path(), section.text.as_str()))
+            .collect::<Vec<(String, &str)>>();
+        assert_eq!(
+            paths,
+            vec![
+                ("Design".to_string(), "Intro"),
+                ("Design > Storage".to_string(), "Disks"),
+                ("Design > Storage > Backups".to_string(), "Nightly"),
+                ("Usage".to_string(), "Run it"),
+            ]
+        );
+    }
+
+    #[test]
+    fn code_blocks_and_html() {
+        let sections = markdown_to_sections(
+            "Text with `code`.\n\n```rust\nfn main() {\n\n    run();\n}\n```\n\n<div>raw html</div>\n",
+        );
+
+        assert_eq!(
+            sections[0].text,
+            "Text with `code`.\n\n```rust\nfn main() {\n\n    run();\n}\n```"
+        );
+    }
+
+    #[test]
+    fn lists_and_tables() {
+        let sections = markdown_to_sections(
+            "- [x] done\n- todo\n  1. nested\n\n| name | age |\n| --- | --- |\n| ada | 36 |\n",
+        );
+
+        assert_eq!(
+            sections[0].text,
+            "- [x] done\n\n- todo\n\n  1. nested\n\nname | age\nada | 36"
+        );
+    }
+}
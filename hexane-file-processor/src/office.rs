@@ -0,0 +1,585 @@
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek},
+    path::Path,
+};
+use zip::ZipArchive;
+
+use crate::document::{normalize_whitespace, Document, Section, SectionBuilder};
+use crate::extractor::{Extractor, Sample};
+use crate::{read_zip_entry, xml_attribute};
+
+fn open_archive(file: &Path) -> Result<ZipArchive<File>, String> {
+    let f = File::open(file).map_err(|e| format!("opening {}: {}", file.display(), e))?;
+    ZipArchive::new(f).map_err(|e| format!("reading zip archive {}: {}", file.display(), e))
+}
+
+/// Paragraph being read from word/document.xml. Paragraphs nest when a text
+/// box is anchored in a run of another paragraph.
+#[derive(Default)]
+struct DocxParagraph {
+    text: String,
+    heading_level: Option<usize>,
+    list_item: bool,
+    note_refs: Vec<String>,
+}
+
+/// docx_to_sections extracts paragraphs, headings, tables and footnotes from
+/// a Word (.docx) document.
+pub fn docx_to_sections(file: &Path) -> Result<Vec<Section>, String> {
+    docx_archive_to_sections(&mut open_archive(file)?)
+}
+
+fn docx_archive_to_sections<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+) -> Result<Vec<Section>, String> {
+    let document = read_zip_entry(archive, "word/document.xml")?
+        .ok_or("word/document.xml not found in archive")?;
+    let styles = match read_zip_entry(archive, "word/styles.xml")? {
+        Some(xml) => docx_heading_styles(&xml)?,
+        None => HashMap::new(),
+    };
+
+    let mut notes = HashMap::new();
+    for (entry, tag) in [
+        ("word/footnotes.xml", "w:footnote"),
+        ("word/endnotes.xml", "w:endnote"),
+    ] {
+        if let Some(xml) = read_zip_entry(archive, entry)? {
+            notes.extend(docx_notes(&xml, tag)?);
+        }
+    }
+
+    let mut builder = SectionBuilder::new();
+
+    // Open paragraphs, innermost last. A nested paragraph is added as a block
+    // of its own before the paragraph it's anchored in.
+    let mut paragraphs: Vec<DocxParagraph> = vec![];
+    let mut run_depth = 0;
+    let mut in_text = false;
+
+    let mut reader = Reader::from_str(&document);
+    reader.expand_empty_elements(true);
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => match e.name().as_ref() {
+                b"w:p" => paragraphs.push(DocxParagraph::default()),
+                b"w:pStyle" => {
+                    if let (Some(paragraph), Some(level)) = (
+                        paragraphs.last_mut(),
+                        xml_attribute(&e, "w:val").and_then(|v| styles.get(&v)),
+                    ) {
+                        paragraph.heading_level = Some(*level);
+                    }
+                }
+                b"w:outlineLvl" => {
+                    if let (Some(paragraph), Some(level)) = (
+                        paragraphs.last_mut(),
+                        xml_attribute(&e, "w:val").and_then(|v| v.parse::<usize>().ok()),
+                    ) {
+                        // Level 9 is body text.
+                        if level < 9 {
+                            paragraph.heading_level = Some(level + 1);
+                        }
+                    }
+                }
+                b"w:numPr" => {
+                    if let Some(paragraph) = paragraphs.last_mut() {
+                        paragraph.list_item = true;
+                    }
+                }
+                b"w:r" => run_depth += 1,
+                b"w:t" => in_text = true,
+                b"w:tab" if run_depth > 0 => {
+                    if let Some(paragraph) = paragraphs.last_mut() {
+                        paragraph.text.push('\t');
+                    }
+                }
+                b"w:br" | b"w:cr" if run_depth > 0 => {
+                    if let Some(paragraph) = paragraphs.last_mut() {
+                        paragraph.text.push('\n');
+                    }
+                }
+                b"w:footnoteReference" | b"w:endnoteReference" => {
+                    if let (Some(paragraph), Some(id)) =
+                        (paragraphs.last_mut(), xml_attribute(&e, "w:id"))
+                    {
+                        paragraph.text.push_str(&format!("[{}]", id));
+                        paragraph.note_refs.push(id);
+                    }
+                }
+                b"w:tbl" => builder.start_table(),
+                _ => {}
+            },
+            Ok(Event::End(e)) => match e.name().as_ref() {
+                b"w:p" => {
+                    let Some(paragraph) = paragraphs.pop() else {
+                        continue;
+                    };
+
+                    let mut text = paragraph.text.trim().to_string();
+                    if paragraph.list_item && paragraph.heading_level.is_none() && !text.is_empty()
+                    {
+                        text = format!("- {}", text);
+                    }
+                    match paragraph.heading_level {
+                        Some(level) => builder.heading(level, &text),
+                        None => builder.block(&text),
+                    }
+
+                    for id in paragraph.note_refs {
+                        if let Some(note) = notes.get(&id) {
+                            builder.note(&id, note);
+                        }
+                    }
+                }
+                b"w:r" => run_depth -= 1,
+                b"w:t" => in_text = false,
+                b"w:tc" => builder.end_cell(),
+                b"w:tr" => builder.end_row(),
+                b"w:tbl" => builder.end_table(),
+                _ => {}
+            },
+            Ok(Event::Text(e)) if in_text => {
+                let text = e
+                    .unescape()
+                    .map_err(|e| format!("parsing word/document.xml: {}", e))?;
+                if let Some(paragraph) = paragraphs.last_mut() {
+                    paragraph.text.push_str(&text);
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(format!("parsing word/document.xml: {}", e)),
+            _ => {}
+        }
+    }
+
+    Ok(builder.finish())
+}
+
+/// docx_heading_styles maps paragraph style ids to their heading level by
+/// looking at the style name ("heading 1", "Title") or the outline level.
+fn docx_heading_styles(xml: &str) -> Result<HashMap<String, usize>, String> {
+    let mut styles: HashMap<String, usize> = HashMap::new();
+    let mut style_id: Option<String> = None;
+
+    let mut reader = Reader::from_str(xml);
+    reader.expand_empty_elements(true);
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => match e.name().as_ref() {
+                b"w:style" => style_id = xml_attribute(&e, "w:styleId"),
+                b"w:name" => {
+                    let name = xml_attribute(&e, "w:val")
+                        .unwrap_or_default()
+                        .to_lowercase();
+                    let level = match name.strip_prefix("heading ") {
+                        Some(level) => level.trim().parse::<usize>().ok(),
+                        None if name == "title" => Some(1),
+                        None => None,
+                    };
+
+                    if let (Some(id), Some(level)) = (&style_id, level) {
+                        styles.insert(id.clone(), level);
+                    }
+                }
+                b"w:outlineLvl" => {
+                    let level = xml_attribute(&e, "w:val").and_then(|v| v.parse::<usize>().ok());
+                    if let (Some(id), Some(level)) = (&style_id, level) {
+                        if level < 9 {
+                            styles.insert(id.clone(), level + 1);
+                        }
+                    }
+                }
+                _ => {}
+            },
+            Ok(Event::End(e)) if e.name().as_ref() == b"w:style" => style_id = None,
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(format!("parsing word/styles.xml: {}", e)),
+            _ => {}
+        }
+    }
+
+    Ok(styles)
+}
+
+/// docx_notes returns the text of every footnote (or endnote) keyed by its id.
+/// Separator notes don't carry any text and are skipped.
+fn docx_notes(xml: &str, tag: &str) -> Result<HashMap<String, String>, String> {
+    let mut notes: HashMap<String, String> = HashMap::new();
+    let mut note: Option<(String, String)> = None;
+    let mut in_text = false;
+
+    let mut reader = Reader::from_str(xml);
+    reader.expand_empty_elements(true);
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => match e.name().as_ref() {
+                name if name == tag.as_bytes() => {
+                    note = match xml_attribute(&e, "w:type").as_deref() {
+                        None | Some("normal") => {
+                            xml_attribute(&e, "w:id").map(|id| (id, String::new()))
+                        }
+                        Some(_) => None,
+                    };
+                }
+                b"w:t" => in_text = true,
+                b"w:p" => {
+                    if let Some((_, text)) = note.as_mut() {
+                        text.push(' ');
+                    }
+                }
+                _ => {}
+            },
+            Ok(Event::End(e)) => match e.name().as_ref() {
+                name if name == tag.as_bytes() => {
+                    if let Some((id, text)) = note.take() {
+                        notes.insert(id, normalize_whitespace(&text));
+                    }
+                }
+                b"w:t" => in_text = false,
+                _ => {}
+            },
+            Ok(Event::Text(e)) if in_text => {
+                if let Some((_, text)) = note.as_mut() {
+                    let t = e
+                        .unescape()
+                        .map_err(|e| format!("parsing {}: {}", tag, e))?;
+                    text.push_str(&t);
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(format!("parsing {}: {}", tag, e)),
+            _ => {}
+        }
+    }
+
+    Ok(notes)
+}
+
+/// odt_to_sections extracts paragraphs, headings, lists, tables and footnotes
+/// from an OpenDocument Text (.odt) document.
+pub fn odt_to_sections(file: &Path) -> Result<Vec<Section>, String> {
+    odt_archive_to_sections(&mut open_archive(file)?)
+}
+
+fn odt_archive_to_sections<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+) -> Result<Vec<Section>, String> {
+    let content =
+        read_zip_entry(archive, "content.xml")?.ok_or("content.xml not found in archive")?;
+
+    let mut builder = SectionBuilder::new();
+
+    // Paragraphs can nest (e.g. text boxes or notes inside a paragraph), each
+    // open paragraph gets its own buffer along with its heading level.
+    let mut paragraphs: Vec<(String, Option<usize>)> = vec![];
+    let mut list_depth = 0;
+
+    // Footnote marker & text, notes are placed inline in the paragraph.
+    let mut note_citation: Option<String> = None;
+    let mut note_body: Option<String> = None;
+    let mut in_citation = false;
+
+    // Depth of elements whose content we ignore (annotations, tracked changes).
+    let mut skip_depth = 0;
+
+    let mut reader = Reader::from_str(&content);
+    reader.expand_empty_elements(true);
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => {
+                let name = e.name();
+                if skip_depth > 0 {
+                    skip_depth += 1;
+                    continue;
+                }
+
+                match name.as_ref() {
+                    b"office:annotation" | b"text:tracked-changes" => skip_depth = 1,
+                    b"text:h" => {
+                        let level = xml_attribute(&e, "text:outline-level")
+                            .and_then(|v| v.parse().ok())
+                            .unwrap_or(1);
+                        paragraphs.push((String::new(), Some(level)));
+                    }
+                    b"text:p" => paragraphs.push((String::new(), None)),
+                    b"text:list" => list_depth += 1,
+                    b"text:s" => {
+                        let count = xml_attribute(&e, "text:c")
+                            .and_then(|v| v.parse().ok())
+                            .unwrap_or(1);
+                        if let Some((text, _)) = paragraphs.last_mut() {
+                            text.push_str(&" ".repeat(count));
+                        }
+                    }
+                    b"text:tab" => {
+                        if let Some((text, _)) = paragraphs.last_mut() {
+                            text.push('\t');
+                        }
+                    }
+                    b"text:line-break" => {
+                        if let Some((text, _)) = paragraphs.last_mut() {
+                            text.push('\n');
+                        }
+                    }
+                    b"text:note" => {
+                        note_citation = Some(String::new());
+                        note_body = None;
+                    }
+                    b"text:note-citation" => in_citation = true,
+                    b"text:note-body" => note_body = Some(String::new()),
+                    b"table:table" => builder.start_table(),
+                    _ => {}
+                }
+            }
+            Ok(Event::End(e)) => {
+                if skip_depth > 0 {
+                    skip_depth -= 1;
+                    continue;
+                }
+
+                match e.name().as_ref() {
+                    b"text:h" | b"text:p" => {
+                        let (text, level) = paragraphs.pop().unwrap_or_default();
+                        let mut text = text.trim().to_string();
+
+                        if let Some(body) = note_body.as_mut() {
+                            body.push(' ');
+                            body.push_str(&text);
+                            continue;
+                        }
+
+                        if list_depth > 0 && level.is_none() && !text.is_empty() {
+                            text = format!("{}- {}", "  ".repeat(list_depth - 1), text);
+                        }
+                        match level {
+                            Some(level) => builder.heading(level, &text),
+                            None => builder.block(&text),
+                        }
+                    }
+                    b"text:list" => list_depth -= 1,
+                    b"text:note-citation" => in_citation = false,
+                    b"text:note" => {
+                        let marker = note_citation.take().unwrap_or_default();
+                        let marker = marker.trim();
+                        if let Some((text, _)) = paragraphs.last_mut() {
+                            text.push_str(&format!("[{}]", marker));
+                        }
+                        if let Some(body) = note_body.take() {
+                            builder.note(marker, &body);
+                        }
+                    }
+                    b"table:table-cell" => builder.end_cell(),
+                    b"table:table-row" => builder.end_row(),
+                    b"table:table" => builder.end_table(),
+                    _ => {}
+                }
+            }
+            Ok(Event::Text(e)) if skip_depth == 0 => {
+                let text = e
+                    .unescape()
+                    .map_err(|e| format!("parsing content.xml: {}", e))?;
+
+                if in_citation {
+                    if let Some(citation) = note_citation.as_mut() {
+                        citation.push_str(&text);
+                    }
+                } else if let Some((paragraph, _)) = paragraphs.last_mut() {
+                    paragraph.push_str(&text);
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(format!("parsing content.xml: {}", e)),
+            _ => {}
+        }
+    }
+
+    Ok(builder.finish())
+}
+
+pub struct Docx;
+
+impl Extractor for Docx {
+    fn description(&self) -> &'static str {
+        "Word (.docx)"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/vnd.openxmlformats-officedocument.wordprocessingml.document"]
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        &[(
+            "docx",
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        )]
+    }
+
+    fn sniff(&self, sample: &Sample) -> Option<&'static str> {
+        sample
+            .zip_entries
+            .iter()
+            .any(|entry| entry == "word/document.xml")
+            .then_some("application/vnd.openxmlformats-officedocument.wordprocessingml.document")
+    }
+
+    fn extract(&self, file: &Path, _: &str) -> Result<Document, String> {
+        docx_to_sections(file).map(Document::from)
+    }
+}
+
+pub struct Odt;
+
+impl Extractor for Odt {
+    fn description(&self) -> &'static str {
+        "OpenDocument Text (.odt)"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/vnd.oasis.opendocument.text"]
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        &[("odt", "application/vnd.oasis.opendocument.text")]
+    }
+
+    fn sniff(&self, sample: &Sample) -> Option<&'static str> {
+        (sample.zip_mimetype.as_deref() == Some("application/vnd.oasis.opendocument.text"))
+            .then_some("application/vnd.oasis.opendocument.text")
+    }
+
+    fn extract(&self, file: &Path, _: &str) -> Result<Document, String> {
+        odt_to_sections(file).map(Document::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::{write::FileOptions, ZipWriter};
+
+    fn archive(entries: &[(&str, &str)]) -> ZipArchive<Cursor<Vec<u8>>> {
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        for (name, contents) in entries {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        ZipArchive::new(zip.finish().unwrap()).unwrap()
+    }
+
+    fn docx(body: &str, styles: &str, footnotes: &str) -> Vec<Section> {
+        let document = format!(
+            r#"<w:document xmlns:w="w"><w:body>{}</w:body></w:document>"#,
+            body
+        );
+        docx_archive_to_sections(&mut archive(&[
+            ("word/document.xml", &document),
+            ("word/styles.xml", styles),
+            ("word/footnotes.xml", footnotes),
+        ]))
+        .unwrap()
+    }
+
+    fn p(text: &str) -> String {
+        format!("<w:p><w:r><w:t>{}</w:t></w:r></w:p>", text)
+    }
+
+    #[test]
+    fn docx_headings_from_styles_and_outline_level() {
+        let styles = r#"<w:styles xmlns:w="w">
+            <w:style w:styleId="Heading1"><w:name w:val="heading 1"/></w:style>
+            <w:style w:styleId="Custom"><w:name w:val="Custom"/><w:pPr><w:outlineLvl w:val="1"/></w:pPr></w:style>
+        </w:styles>"#;
+        let body = [
+            r#"<w:p><w:pPr><w:pStyle w:val="Heading1"/></w:pPr><w:r><w:t>Design</w:t></w:r></w:p>"#,
+            &p("Intro"),
+            r#"<w:p><w:pPr><w:pStyle w:val="Custom"/></w:pPr><w:r><w:t>Storage</w:t></w:r></w:p>"#,
+            &p("Disks"),
+            r#"<w:p><w:pPr><w:outlineLvl w:val="0"/></w:pPr><w:r><w:t>Usage</w:t></w:r></w:p>"#,
+            r#"<w:p><w:pPr><w:numPr/></w:pPr><w:r><w:t>Run it</w:t></w:r></w:p>"#,
+        ]
+        .concat();
+
+        let sections = docx(&body, styles, "<w:footnotes/>");
+        let headings = sections
+            .iter()
+            .map(|section| (section.heading.clone(), section.text.as_str()))
+            .collect::<Vec<(Vec<String>, &str)>>();
+        assert_eq!(
+            headings,
+            vec![
+                (vec!["Design".to_string()], "Intro"),
+                (vec!["Design".to_string(), "Storage".to_string()], "Disks"),
+                (vec!["Usage".to_string()], "- Run it"),
+            ]
+        );
+    }
+
+    #[test]
+    fn docx_tables_and_footnotes() {
+        let footnotes = r#"<w:footnotes xmlns:w="w">
+            <w:footnote w:type="separator" w:id="0"><w:p><w:r><w:separator/></w:r></w:p></w:footnote>
+            <w:footnote w:id="1"><w:p><w:r><w:t>A note.</w:t></w:r></w:p></w:footnote>
+        </w:footnotes>"#;
+        let body = [
+            r#"<w:p><w:r><w:t>Cited</w:t></w:r><w:r><w:footnoteReference w:id="1"/></w:r></w:p>"#,
+            "<w:tbl><w:tr><w:tc>",
+            &p("name"),
+            "</w:tc><w:tc>",
+            &p("age"),
+            "</w:tc></w:tr><w:tr><w:tc>",
+            &p("ada"),
+            "</w:tc><w:tc>",
+            &p("36"),
+            "</w:tc></w:tr></w:tbl>",
+        ]
+        .concat();
+
+        let sections = docx(&body, "<w:styles/>", footnotes);
+        assert_eq!(sections.len(), 1);
+        assert_eq!(
+            sections[0].text,
+            "Cited[1]\n\nname | age\nada | 36\n\n[1] A note."
+        );
+    }
+
+    #[test]
+    fn docx_nested_paragraph_keeps_outer_text() {
+        let body = r#"<w:p><w:r><w:t>Before the box</w:t></w:r><w:r><w:pict><w:txbxContent>
+            <w:p><w:r><w:t>Inside the box</w:t></w:r></w:p>
+            </w:txbxContent></w:pict></w:r><w:r><w:t> and after.</w:t></w:r></w:p>"#;
+
+        let sections = docx(body, "<w:styles/>", "<w:footnotes/>");
+        assert_eq!(
+            sections[0].text,
+            "Inside the box\n\nBefore the box and after."
+        );
+    }
+
+    #[test]
+    fn odt_headings_lists_and_notes() {
+        let content = r#"<office:document-content><office:body><office:text>
+            <text:h text:outline-level="1">Design</text:h>
+            <text:p>Cited<text:note><text:note-citation>1</text:note-citation><text:note-body><text:p>A note.</text:p></text:note-body></text:note> here.</text:p>
+            <text:list><text:list-item><text:p>first</text:p></text:list-item></text:list>
+            <text:h text:outline-level="2">Storage</text:h>
+            <text:p>Disks<office:annotation><text:p>a comment</text:p></office:annotation></text:p>
+        </office:text></office:body></office:document-content>"#;
+
+        let sections = odt_archive_to_sections(&mut archive(&[("content.xml", content)])).unwrap();
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].heading, vec!["Design"]);
+        assert_eq!(sections[0].text, "Cited[1] here.\n\n- first\n\n[1] A note.");
+        assert_eq!(sections[1].heading, vec!["Design", "Storage"]);
+        assert_eq!(sections[1].text, "Disks");
+    }
+}
@@ -0,0 +1,158 @@
+use futures_util::FutureExt;
+use serde_json::Value;
+use sqlx::{Pool, Postgres};
+use std::{panic::AssertUnwindSafe, sync::Arc};
+use tokio::time::{sleep, Duration};
+use uuid::Uuid;
+
+use hexane_file_processor::document::embedding_text;
+use hexane_shared::embedding::{get_embeddings, Embedder, EmbeddingError};
+
+struct StaleEmbedding {
+    id: Uuid,
+    text: String,
+    section: Option<String>,
+    metadata: Option<Value>,
+}
+
+/// reembed embeds again the chunks that were embedded with another model
+/// than the configured one until none is left, batch_size chunks of a user
+/// at a time. It can run along with the processor, rows are locked while
+/// they're re-embedded.
+pub async fn reembed(embedder: &Embedder, pool: &Pool<Postgres>, batch_size: i64) {
+    let total = sqlx::query_file!(
+        "queries/datasource/get-stale-embedding-count.sql",
+        embedder.model()
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap()
+    .count;
+
+    tracing::info!(
+        "re-embedding {} chunks with model {}",
+        total,
+        embedder.model()
+    );
+
+    let mut done = 0;
+    loop {
+        let updated = reembed_pass(embedder, pool, batch_size).await;
+        if updated == 0 {
+            break;
+        }
+
+        done += updated as i64;
+        tracing::info!("re-embedded {}/{} chunks", done, total.max(done));
+    }
+
+    tracing::info!("re-embedding done, {} chunks updated", done);
+}
+
+/// background re-embeds the chunks of other models while the processor
+/// runs, they're searched as they are until they're replaced. Once none is
+/// left it checks again every 5 minutes.
+pub async fn background(embedder: Arc<Embedder>, pool: Pool<Postgres>, batch_size: i64) {
+    loop {
+        let updated = AssertUnwindSafe(reembed_pass(&embedder, &pool, batch_size))
+            .catch_unwind()
+            .await
+            .unwrap_or_else(|_| {
+                tracing::error!("re-embedding panicked");
+                0
+            });
+
+        if updated == 0 {
+            sleep(Duration::from_secs(300)).await;
+        } else {
+            tracing::info!("re-embedded {} chunks", updated);
+        }
+    }
+}
+
+/// reembed_pass re-embeds up to batch_size chunks of every user that has
+/// credits left, it returns the number of chunks updated. Every user is
+/// charged for their own chunks in a transaction of their own, a failure
+/// doesn't affect the others.
+async fn reembed_pass(embedder: &Embedder, pool: &Pool<Postgres>, batch_size: i64) -> usize {
+    let users = sqlx::query_file!(
+        "queries/datasource/get-stale-embedding-users.sql",
+        embedder.model()
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap();
+
+    let mut updated = 0;
+    for user in users {
+        match reembed_user(embedder, pool, &user.user_id, batch_size).await {
+            Ok(count) => updated += count,
+            Err(err) => tracing::warn!("re-embedding chunks of {} failed: {}", user.user_id, err),
+        }
+    }
+
+    updated
+}
+
+async fn reembed_user(
+    embedder: &Embedder,
+    pool: &Pool<Postgres>,
+    user_id: &Uuid,
+    batch_size: i64,
+) -> Result<usize, String> {
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+    let stale = sqlx::query_file_as!(
+        StaleEmbedding,
+        "queries/datasource/get-stale-embeddings.sql",
+        embedder.model(),
+        user_id,
+        batch_size
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    if stale.is_empty() {
+        return Ok(0);
+    }
+
+    let inputs = stale
+        .iter()
+        .map(|chunk| {
+            let metadata = chunk
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.as_object())
+                .into_iter()
+                .flatten()
+                .filter_map(|(name, value)| Some((name.as_str(), value.as_str()?)));
+            embedding_text(chunk.section.as_deref(), metadata, &chunk.text)
+        })
+        .collect::<Vec<String>>();
+
+    let embeddings = match get_embeddings(embedder, &inputs, user_id, &mut tx).await {
+        Ok(embeddings) => embeddings,
+        Err(EmbeddingError::Database(err)) => return Err(err.to_string()),
+        // Batches embedded before a provider error are charged & cached.
+        Err(err) => {
+            tx.commit().await.map_err(|e| e.to_string())?;
+            return Err(err.to_string());
+        }
+    };
+
+    for (chunk, embedding) in stale.iter().zip(embeddings) {
+        sqlx::query_file!(
+            "queries/datasource/set-embedding.sql",
+            chunk.id,
+            &embedding,
+            embedder.model(),
+            embedding.len() as i32
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+    }
+
+    tx.commit().await.map_err(|e| e.to_string())?;
+    Ok(stale.len())
+}
@@ -0,0 +1,495 @@
+use calamine::{open_workbook, Ods, Reader, SheetType, SheetVisible, Xlsx};
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek},
+    path::Path,
+};
+
+use hexane_shared::Tabular;
+
+use crate::document::{normalize_whitespace, Document, Section};
+use crate::extractor::{Extractor, Sample};
+
+/// Rows & columns of a table, counted over the whole table including those
+/// beyond the limits.
+#[derive(Default)]
+struct TableSize {
+    rows: usize,
+    columns: usize,
+}
+
+/// Reads the rows of a table in a single pass, the first non-empty row is the
+/// header. Every row within the limits is rendered on its own line with cells
+/// separated by " | " & passed to line, header first. Rows beyond the limits
+/// are only counted.
+fn read_table(
+    rows: impl Iterator<Item = Vec<String>>,
+    limits: &Tabular,
+    mut line: impl FnMut(String),
+) -> TableSize {
+    let mut size = TableSize::default();
+    let mut header = false;
+
+    for row in rows {
+        // Trailing empty cells aren't part of the row.
+        let width = row
+            .iter()
+            .rposition(|cell| !cell.trim().is_empty())
+            .map_or(0, |idx| idx + 1);
+        if width == 0 {
+            continue;
+        }
+        size.columns = size.columns.max(width);
+
+        if header {
+            size.rows += 1;
+            if size.rows > limits.max_rows {
+                continue;
+            }
+        }
+        header = true;
+
+        line(
+            row[..width.min(limits.max_columns)]
+                .iter()
+                .map(|cell| normalize_whitespace(cell))
+                .collect::<Vec<String>>()
+                .join(" | "),
+        );
+    }
+
+    size
+}
+
+/// Warnings for the rows & columns of a table that are beyond the limits.
+fn table_warnings(name: Option<&str>, size: &TableSize, limits: &Tabular) -> Vec<String> {
+    let source = match name {
+        Some(name) => format!("Sheet '{}'", name),
+        None => "Table".to_string(),
+    };
+
+    let mut warnings = vec![];
+    if size.columns > limits.max_columns {
+        warnings.push(format!(
+            "{}: only the first {} of {} columns were processed",
+            source, limits.max_columns, size.columns
+        ));
+    }
+    if size.rows > limits.max_rows {
+        warnings.push(format!(
+            "{}: only the first {} of {} rows were processed",
+            source, limits.max_rows, size.rows
+        ));
+    }
+    warnings
+}
+
+/// Builds a section out of the rows of a table. The header row is stored as
+/// the section header so that it's repeated in every chunk. Rows & columns
+/// beyond the limits are dropped with a warning.
+fn table_to_section(
+    name: Option<&str>,
+    rows: impl Iterator<Item = Vec<String>>,
+    limits: &Tabular,
+    warnings: &mut Vec<String>,
+) -> Option<Section> {
+    let mut lines: Vec<String> = vec![];
+    let size = read_table(rows, limits, |line| lines.push(line));
+    warnings.extend(table_warnings(name, &size, limits));
+
+    // A table with a single row has nothing to repeat, the row is the text.
+    let (header, text) = match lines.len() {
+        0 => return None,
+        1 => (None, lines.remove(0)),
+        _ => {
+            let header = lines.remove(0);
+            (Some(header), lines.join("\n"))
+        }
+    };
+
+    Some(Section {
+        heading: name.map(|name| vec![name.to_string()]).unwrap_or_default(),
+        header,
+        ..Section::new(text)
+    })
+}
+
+/// Reads the records of a CSV or TSV file one at a time & passes them to
+/// read, rows with a different number of fields than the header are kept as
+/// is.
+fn read_csv<T>(
+    file: &Path,
+    r#type: &str,
+    read: impl FnOnce(&mut dyn Iterator<Item = Vec<String>>) -> T,
+) -> Result<T, String> {
+    let delimiter = match r#type {
+        "text/tab-separated-values" => b'\t',
+        _ => {
+            let mut line = vec![];
+            File::open(file)
+                .map(BufReader::new)
+                .and_then(|mut f| f.read_until(b'\n', &mut line))
+                .map_err(|e| format!("reading {}: {}", file.display(), e))?;
+            sniff_csv_delimiter(&line)
+        }
+    };
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(false)
+        .flexible(true)
+        .from_path(file)
+        .map_err(|e| format!("opening {}: {}", file.display(), e))?;
+
+    // Reading stops at the first malformed record, the error is returned
+    // once read is done with the rows before it.
+    let mut error: Option<csv::Error> = None;
+    let mut rows = reader.byte_records().map_while(|record| match record {
+        Ok(record) => Some(
+            record
+                .iter()
+                .map(|field| String::from_utf8_lossy(field).to_string())
+                .collect(),
+        ),
+        Err(e) => {
+            error = Some(e);
+            None
+        }
+    });
+
+    let result = read(&mut rows);
+    match error {
+        Some(e) => Err(format!("reading {}: {}", file.display(), e)),
+        None => Ok(result),
+    }
+}
+
+/// csv_to_document extracts the rows of a CSV or TSV file.
+pub fn csv_to_document(file: &Path, r#type: &str, limits: &Tabular) -> Result<Document, String> {
+    let mut document = Document::default();
+    let section = read_csv(file, r#type, |rows| {
+        table_to_section(None, rows, limits, &mut document.warnings)
+    })?;
+    document.sections.extend(section);
+    Ok(document)
+}
+
+/// Guesses the delimiter of a CSV file from its first line, files exported
+/// with a comma as decimal separator use semicolons.
+fn sniff_csv_delimiter(line: &[u8]) -> u8 {
+    let count = |delimiter: u8| line.iter().filter(|b| **b == delimiter).count();
+
+    if count(b';') > count(b',') {
+        b';'
+    } else {
+        b','
+    }
+}
+
+/// Passes the rows of every visible sheet of the workbook to read along with
+/// the sheet's name, hidden sheets & chart sheets are skipped.
+fn read_workbook<RS, W>(
+    mut workbook: W,
+    mut read: impl FnMut(&str, &mut dyn Iterator<Item = Vec<String>>),
+) -> Result<(), String>
+where
+    RS: Read + Seek,
+    W: Reader<RS>,
+    W::Error: Display,
+{
+    let sheets = workbook
+        .sheets_metadata()
+        .iter()
+        .filter(|sheet| sheet.typ == SheetType::WorkSheet && sheet.visible == SheetVisible::Visible)
+        .map(|sheet| sheet.name.clone())
+        .collect::<Vec<String>>();
+
+    for sheet in sheets {
+        let range = workbook
+            .worksheet_range(&sheet)
+            .map_err(|e| format!("reading sheet '{}': {}", sheet, e))?;
+        let mut rows = range
+            .rows()
+            .map(|row| row.iter().map(|cell| cell.to_string()).collect());
+        read(&sheet, &mut rows);
+    }
+
+    Ok(())
+}
+
+/// Opens the spreadsheet & passes the rows of its sheets to read_workbook.
+fn read_spreadsheet(
+    file: &Path,
+    r#type: &str,
+    read: impl FnMut(&str, &mut dyn Iterator<Item = Vec<String>>),
+) -> Result<(), String> {
+    match r#type {
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" => {
+            let workbook: Xlsx<_> =
+                open_workbook(file).map_err(|e| format!("opening {}: {}", file.display(), e))?;
+            read_workbook(workbook, read)
+        }
+        "application/vnd.oasis.opendocument.spreadsheet" => {
+            let workbook: Ods<_> =
+                open_workbook(file).map_err(|e| format!("opening {}: {}", file.display(), e))?;
+            read_workbook(workbook, read)
+        }
+        _ => Err(format!("not a spreadsheet: {}", r#type)),
+    }
+}
+
+/// spreadsheet_to_document extracts every visible sheet of an Excel (.xlsx)
+/// or OpenDocument (.ods) spreadsheet as a separate section.
+pub fn spreadsheet_to_document(
+    file: &Path,
+    r#type: &str,
+    limits: &Tabular,
+) -> Result<Document, String> {
+    let mut document = Document::default();
+    read_spreadsheet(file, r#type, |sheet, rows| {
+        if let Some(section) = table_to_section(Some(sheet), rows, limits, &mut document.warnings) {
+            document.sections.push(section);
+        }
+    })?;
+    Ok(document)
+}
+
+pub struct Delimited {
+    limits: Tabular,
+}
+
+impl Delimited {
+    pub fn new(limits: &Tabular) -> Delimited {
+        Delimited {
+            limits: limits.clone(),
+        }
+    }
+}
+
+impl Extractor for Delimited {
+    fn description(&self) -> &'static str {
+        "CSV/TSV"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["text/csv", "text/tab-separated-values"]
+    }
+
+    fn aliases(&self) -> &'static [(&'static str, &'static str)] {
+        &[("text/x-csv", "text/csv"), ("application/csv", "text/csv")]
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        &[("csv", "text/csv"), ("tsv", "text/tab-separated-values")]
+    }
+
+    fn textual(&self) -> bool {
+        true
+    }
+
+    fn inspect(&self, file: &Path, r#type: &str) -> Vec<String> {
+        // Rows are only counted, none of them is kept.
+        read_csv(file, r#type, |rows| {
+            let size = read_table(rows, &self.limits, |_| {});
+            table_warnings(None, &size, &self.limits)
+        })
+        .unwrap_or_default()
+    }
+
+    fn extract(&self, file: &Path, r#type: &str) -> Result<Document, String> {
+        csv_to_document(file, r#type, &self.limits)
+    }
+}
+
+pub struct Spreadsheet {
+    limits: Tabular,
+}
+
+impl Spreadsheet {
+    pub fn new(limits: &Tabular) -> Spreadsheet {
+        Spreadsheet {
+            limits: limits.clone(),
+        }
+    }
+}
+
+impl Extractor for Spreadsheet {
+    fn description(&self) -> &'static str {
+        "Excel (.xlsx), OpenDocument Spreadsheet (.ods)"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &[
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            "application/vnd.oasis.opendocument.spreadsheet",
+        ]
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        &[
+            (
+                "xlsx",
+                "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            ),
+            ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+        ]
+    }
+
+    fn sniff(&self, sample: &Sample) -> Option<&'static str> {
+        if sample
+            .zip_entries
+            .iter()
+            .any(|entry| entry == "xl/workbook.xml")
+        {
+            Some("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet")
+        } else if sample.zip_mimetype.as_deref()
+            == Some("application/vnd.oasis.opendocument.spreadsheet")
+        {
+            Some("application/vnd.oasis.opendocument.spreadsheet")
+        } else {
+            None
+        }
+    }
+
+    fn inspect(&self, file: &Path, r#type: &str) -> Vec<String> {
+        // Rows are only counted, none of them is kept.
+        let mut warnings = vec![];
+        let counted = read_spreadsheet(file, r#type, |sheet, rows| {
+            let size = read_table(rows, &self.limits, |_| {});
+            warnings.extend(table_warnings(Some(sheet), &size, &self.limits));
+        });
+        counted.map(|_| warnings).unwrap_or_default()
+    }
+
+    fn extract(&self, file: &Path, r#type: &str) -> Result<Document, String> {
+        spreadsheet_to_document(file, r#type, &self.limits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    fn rows(rows: &[&[&str]]) -> impl Iterator<Item = Vec<String>> {
+        rows.iter()
+            .map(|row| row.iter().map(|cell| cell.to_string()).collect())
+            .collect::<Vec<Vec<String>>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn table_header_is_repeated() {
+        let mut warnings = vec![];
+        let section = table_to_section(
+            Some("People"),
+            rows(&[&["", ""], &["name", "age"], &["ada", "36"], &["alan", "41"]]),
+            &Tabular::default(),
+            &mut warnings,
+        )
+        .unwrap();
+
+        assert_eq!(section.heading, vec!["People"]);
+        assert_eq!(section.header.as_deref(), Some("name | age"));
+        assert_eq!(section.text, "ada | 36\nalan | 41");
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn table_is_capped() {
+        let limits = Tabular {
+            max_rows: 2,
+            max_columns: 2,
+        };
+        let mut warnings = vec![];
+        let section = table_to_section(
+            None,
+            rows(&[
+                &["a", "b", "c"],
+                &["1", "2", "3"],
+                &["4", "5", "6"],
+                &["7", "8", "9"],
+            ]),
+            &limits,
+            &mut warnings,
+        )
+        .unwrap();
+
+        assert_eq!(section.header.as_deref(), Some("a | b"));
+        assert_eq!(section.text, "1 | 2\n4 | 5");
+        assert_eq!(
+            warnings,
+            vec![
+                "Table: only the first 2 of 3 columns were processed",
+                "Table: only the first 2 of 3 rows were processed",
+            ]
+        );
+    }
+
+    #[test]
+    fn table_with_a_single_row() {
+        let mut warnings = vec![];
+        let section = table_to_section(
+            None,
+            rows(&[&["only", "row"]]),
+            &Tabular::default(),
+            &mut warnings,
+        )
+        .unwrap();
+
+        assert_eq!(section.header, None);
+        assert_eq!(section.text, "only | row");
+        assert!(table_to_section(None, rows(&[]), &Tabular::default(), &mut warnings).is_none());
+    }
+
+    #[test]
+    fn rows_beyond_the_cap_are_only_counted() {
+        let limits = Tabular {
+            max_rows: 2,
+            max_columns: 50,
+        };
+        let mut kept = 0;
+        let size = read_table((0..1000).map(|row| vec![row.to_string()]), &limits, |_| {
+            kept += 1
+        });
+
+        // The header & the first 2 rows.
+        assert_eq!(kept, 3);
+        assert_eq!(size.rows, 999);
+    }
+
+    #[test]
+    fn csv_is_capped_while_reading() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.child("people.csv");
+        let mut csv = "name;age\n".to_string();
+        for idx in 0..100 {
+            csv.push_str(&format!("person {};{}\n", idx, idx));
+        }
+        std::fs::write(&file, csv).unwrap();
+
+        let limits = Tabular {
+            max_rows: 2,
+            max_columns: 50,
+        };
+        let document = csv_to_document(&file, "text/csv", &limits).unwrap();
+        assert_eq!(document.sections.len(), 1);
+        assert_eq!(document.sections[0].header.as_deref(), Some("name | age"));
+        assert_eq!(document.sections[0].text, "person 0 | 0\nperson 1 | 1");
+        assert_eq!(
+            document.warnings,
+            vec!["Table: only the first 2 of 100 rows were processed"]
+        );
+        assert_eq!(
+            Delimited::new(&limits).inspect(&file, "text/csv"),
+            document.warnings
+        );
+    }
+
+    #[test]
+    fn csv_delimiter() {
+        assert_eq!(sniff_csv_delimiter(b"a,b,c\n"), b',');
+        assert_eq!(sniff_csv_delimiter(b"a;b;\"1,5\"\n"), b';');
+    }
+}
@@ -0,0 +1,102 @@
+//! A document is processed & a query answered with the local embedding
+//! provider, without network access.
+
+use temp_dir::TempDir;
+
+use hexane_file_processor::{chunking::Chunker, extractor::Registry};
+use hexane_shared::{embedding::Embedder, Config};
+
+const DOCUMENT: &str = "# Kitchen notes
+
+## Sourdough bread
+
+Feed the sourdough starter the night before. Mix flour, water, salt & the
+starter, let the dough rise overnight & bake the bread in a hot oven.
+
+## Tomato soup
+
+Roast the tomatoes with garlic & onions, blend them with stock & season the
+soup with basil.
+
+## Knife care
+
+Sharpen knives on a whetstone & dry them right after washing, never leave
+them in the sink.
+";
+
+fn config() -> Config {
+    toml::from_str(
+        r#"
+        file_store = "/tmp"
+
+        [backend]
+        template_directory = "/tmp"
+        resources = "/tmp"
+        stop_words = "/tmp"
+        system_prompt = ""
+
+        [file_processor]
+        max_active_process = 1
+
+        [embedding]
+        provider = "local"
+        model = "local"
+
+        [chat_completion]
+        api = ""
+        body_param = {}
+        pricing = { input = 0.0, output = 0.0 }
+        "#,
+    )
+    .unwrap()
+}
+
+fn similarity(a: &[f64], b: &[f64]) -> f64 {
+    // Local vectors are normalized, the dot product is the cosine similarity.
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+#[tokio::test]
+async fn process_and_query_locally() {
+    let config = config();
+    let dir = TempDir::new().unwrap();
+    let file = dir.child("kitchen.md");
+    std::fs::write(&file, DOCUMENT).unwrap();
+
+    let registry = Registry::from_config(&config.file_processor);
+    let r#type = registry
+        .detect_type(&file, "text/markdown", "kitchen.md")
+        .unwrap();
+    let document = registry.extract(&file, r#type).await.unwrap();
+
+    let chunker = Chunker::new(&config).unwrap();
+    let chunks = chunker.chunk(&document, "default");
+    assert_eq!(chunks.len(), 3);
+
+    let embedder = Embedder::new(&config.embedding).unwrap();
+    let inputs = chunks
+        .iter()
+        .map(|chunk| chunk.with_heading())
+        .collect::<Vec<String>>();
+    let embeddings = embedder.embed(&inputs).await.unwrap();
+    assert_eq!(embeddings.vectors.len(), chunks.len());
+    assert_eq!(embeddings.tokens, Some(0));
+
+    let query = "how long should the sourdough dough rise before I bake bread?";
+    let query_vector = embedder
+        .embed(&[query.to_string()])
+        .await
+        .unwrap()
+        .vectors
+        .remove(0);
+
+    let mut ranked = (0..chunks.len()).collect::<Vec<usize>>();
+    ranked.sort_by(|a, b| {
+        similarity(&query_vector, &embeddings.vectors[*b])
+            .total_cmp(&similarity(&query_vector, &embeddings.vectors[*a]))
+    });
+    assert_eq!(
+        chunks[ranked[0]].heading_path().as_deref(),
+        Some("Kitchen notes > Sourdough bread")
+    );
+}
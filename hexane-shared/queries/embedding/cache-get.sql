@@ -0,0 +1,4 @@
+SELECT hash, embedding::real[] AS "embedding!"
+FROM datasource.embedding_cache
+WHERE model = $1
+  AND hash = ANY($2);
@@ -0,0 +1,799 @@
+use async_trait::async_trait;
+use reqwest::{Client, RequestBuilder, Response};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tiktoken_rs::CoreBPE;
+use tokio::time::timeout;
+
+use crate::{merge_json, ChatCompletion, ChatProviderKind};
+
+/// Usage is the number of tokens of a completion.
+#[derive(Clone, Copy)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// Reply is the answer of the provider, usage is None if the provider
+/// doesn't report it.
+pub struct Reply {
+    pub content: String,
+    pub usage: Option<Usage>,
+}
+
+/// ChatProvider is a chat completion API. Messages are given in the OpenAI
+/// shape ({"role", "content"} with an optional leading system message), every
+/// provider converts them to its own dialect.
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    async fn complete(&self, messages: &[Value]) -> Result<Reply, String>;
+
+    /// stream starts the completion, the answer is read from ChatStream as
+    /// it's generated.
+    async fn stream(&self, messages: &[Value]) -> Result<ChatStream, String>;
+}
+
+/// Long answers take a while when streamed, only the connection is timed
+/// out by the client, other requests set their own timeout.
+fn client() -> Client {
+    Client::builder()
+        .connect_timeout(Duration::from_secs(10))
+        .build()
+        .unwrap()
+}
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest wait for the next part of a streamed answer, a stalled stream is
+/// failed rather than read forever.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sends the request, error statuses are returned with the body.
+async fn send(request: RequestBuilder) -> Result<Response, String> {
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("completion request failed: {}", e))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!(
+            "chat completion returned {}: {}",
+            status,
+            response.text().await.unwrap_or_default()
+        ));
+    }
+
+    Ok(response)
+}
+
+async fn post_json(request: RequestBuilder) -> Result<Value, String> {
+    send(request.timeout(TIMEOUT))
+        .await?
+        .json::<Value>()
+        .await
+        .map_err(|e| format!("reading completion response: {}", e))
+}
+
+/// Keys are optional for servers hosted locally.
+pub(crate) fn bearer_auth(request: RequestBuilder, key: &str) -> RequestBuilder {
+    match key.is_empty() {
+        true => request,
+        false => request.bearer_auth(key),
+    }
+}
+
+fn usage(prompt_tokens: Option<u64>, completion_tokens: Option<u64>) -> Option<Usage> {
+    Some(Usage {
+        prompt_tokens: prompt_tokens?,
+        completion_tokens: completion_tokens?,
+    })
+}
+
+/// Delta is what a single streamed event carries, any of it may be missing.
+#[derive(Default)]
+struct Delta {
+    token: Option<String>,
+    prompt_tokens: Option<u64>,
+    completion_tokens: Option<u64>,
+}
+
+/// Framing of the streamed events.
+enum Framing {
+    /// Server-sent events, the JSON is on "data:" lines.
+    Sse,
+    /// A JSON object per line.
+    NdJson,
+}
+
+/// ChatStream reads the tokens of a streamed completion. Usage reported
+/// along the way is kept until the stream ends.
+pub struct ChatStream {
+    response: Response,
+    framing: Framing,
+    parse: fn(&Value) -> Result<Delta, String>,
+    idle_timeout: Duration,
+    buffer: Vec<u8>,
+    prompt_tokens: Option<u64>,
+    completion_tokens: Option<u64>,
+}
+
+impl ChatStream {
+    fn new(
+        response: Response,
+        framing: Framing,
+        parse: fn(&Value) -> Result<Delta, String>,
+    ) -> ChatStream {
+        ChatStream {
+            response,
+            framing,
+            parse,
+            idle_timeout: IDLE_TIMEOUT,
+            buffer: vec![],
+            prompt_tokens: None,
+            completion_tokens: None,
+        }
+    }
+
+    /// next returns the next part of the answer, None once it's complete.
+    /// It fails when nothing is received for IDLE_TIMEOUT.
+    pub async fn next(&mut self) -> Result<Option<String>, String> {
+        loop {
+            // A chunk may end mid-line, lines are parsed once complete.
+            let line = match self.buffer.iter().position(|b| *b == b'\n') {
+                Some(end) => self.buffer.drain(..=end).collect::<Vec<u8>>(),
+                None => match timeout(self.idle_timeout, self.response.chunk())
+                    .await
+                    .map_err(|_| format!("completion stream idle for {:?}", self.idle_timeout))?
+                    .map_err(|e| format!("reading completion stream: {}", e))?
+                {
+                    Some(chunk) => {
+                        self.buffer.extend_from_slice(&chunk);
+                        continue;
+                    }
+                    None if self.buffer.is_empty() => return Ok(None),
+                    None => std::mem::take(&mut self.buffer),
+                },
+            };
+
+            if let Some(token) = self.line(&String::from_utf8_lossy(&line))? {
+                return Ok(Some(token));
+            }
+        }
+    }
+
+    fn line(&mut self, line: &str) -> Result<Option<String>, String> {
+        let line = line.trim();
+        let data = match self.framing {
+            Framing::Sse => match line.strip_prefix("data:").map(str::trim) {
+                Some(data) => data,
+                None => return Ok(None),
+            },
+            Framing::NdJson => line,
+        };
+        if data.is_empty() || data == "[DONE]" {
+            return Ok(None);
+        }
+
+        let data = serde_json::from_str::<Value>(data)
+            .map_err(|e| format!("parsing completion event {}: {}", data, e))?;
+        let delta = (self.parse)(&data)?;
+        self.prompt_tokens = delta.prompt_tokens.or(self.prompt_tokens);
+        self.completion_tokens = delta.completion_tokens.or(self.completion_tokens);
+
+        Ok(delta.token.filter(|token| !token.is_empty()))
+    }
+
+    /// Usage reported by the provider, None if it didn't.
+    pub fn usage(&self) -> Option<Usage> {
+        usage(self.prompt_tokens, self.completion_tokens)
+    }
+}
+
+/// OpenAi posts to an OpenAI compatible chat completions endpoint
+/// (/v1/chat/completions), most hosted APIs & local servers (vLLM,
+/// llama.cpp) speak it.
+pub struct OpenAi {
+    client: Client,
+    config: ChatCompletion,
+}
+
+impl OpenAi {
+    pub fn new(config: &ChatCompletion) -> OpenAi {
+        OpenAi {
+            client: client(),
+            config: config.clone(),
+        }
+    }
+
+    fn request(&self, messages: &[Value], stream: bool) -> RequestBuilder {
+        let mut body_params = json!({ "messages": messages });
+        merge_json(&mut body_params, &self.config.body_param);
+        if stream {
+            body_params["stream"] = json!(true);
+            body_params["stream_options"] = json!({ "include_usage": true });
+        }
+
+        bearer_auth(self.client.post(&self.config.api), &self.config.key).json(&body_params)
+    }
+
+    fn delta(data: &Value) -> Result<Delta, String> {
+        if data["error"].is_object() {
+            return Err(format!("chat completion failed: {}", data["error"]));
+        }
+
+        Ok(Delta {
+            token: data["choices"][0]["delta"]["content"]
+                .as_str()
+                .map(str::to_string),
+            prompt_tokens: data["usage"]["prompt_tokens"].as_u64(),
+            completion_tokens: data["usage"]["completion_tokens"].as_u64(),
+        })
+    }
+
+    fn reply(res: &Value) -> Result<Reply, String> {
+        let content = res["choices"][0]["message"]["content"]
+            .as_str()
+            .ok_or_else(|| format!("no message in {}", res))?;
+
+        Ok(Reply {
+            content: content.to_string(),
+            usage: usage(
+                res["usage"]["prompt_tokens"].as_u64(),
+                res["usage"]["completion_tokens"].as_u64(),
+            ),
+        })
+    }
+}
+
+#[async_trait]
+impl ChatProvider for OpenAi {
+    async fn complete(&self, messages: &[Value]) -> Result<Reply, String> {
+        OpenAi::reply(&post_json(self.request(messages, false)).await?)
+    }
+
+    async fn stream(&self, messages: &[Value]) -> Result<ChatStream, String> {
+        let response = send(self.request(messages, true)).await?;
+        Ok(ChatStream::new(response, Framing::Sse, OpenAi::delta))
+    }
+}
+
+/// Anthropic posts to an Anthropic style messages endpoint (/v1/messages).
+/// The system prompt is a top level field rather than a message & the
+/// maximum number of tokens of the answer is required.
+pub struct Anthropic {
+    client: Client,
+    config: ChatCompletion,
+}
+
+impl Anthropic {
+    const VERSION: &'static str = "2023-06-01";
+    const MAX_TOKENS: u64 = 1024;
+
+    pub fn new(config: &ChatCompletion) -> Anthropic {
+        Anthropic {
+            client: client(),
+            config: config.clone(),
+        }
+    }
+
+    fn request(&self, messages: &[Value], stream: bool) -> RequestBuilder {
+        let system = messages
+            .iter()
+            .filter(|message| message["role"] == "system")
+            .filter_map(|message| message["content"].as_str())
+            .collect::<Vec<&str>>()
+            .join("\n\n");
+        let messages = messages
+            .iter()
+            .filter(|message| message["role"] != "system")
+            .collect::<Vec<&Value>>();
+
+        let mut body_params = json!({
+            "messages": messages,
+            "max_tokens": Anthropic::MAX_TOKENS
+        });
+        if !system.is_empty() {
+            body_params["system"] = json!(system);
+        }
+        merge_json(&mut body_params, &self.config.body_param);
+        body_params["stream"] = json!(stream);
+
+        self.client
+            .post(&self.config.api)
+            .header("x-api-key", &self.config.key)
+            .header("anthropic-version", Anthropic::VERSION)
+            .json(&body_params)
+    }
+
+    fn delta(data: &Value) -> Result<Delta, String> {
+        match data["type"].as_str() {
+            // output_tokens of message_start is a placeholder, the count
+            // comes with message_delta.
+            Some("message_start") => Ok(Delta {
+                prompt_tokens: data["message"]["usage"]["input_tokens"].as_u64(),
+                ..Default::default()
+            }),
+            Some("content_block_delta") => Ok(Delta {
+                token: data["delta"]["text"].as_str().map(str::to_string),
+                ..Default::default()
+            }),
+            Some("message_delta") => Ok(Delta {
+                completion_tokens: data["usage"]["output_tokens"].as_u64(),
+                ..Default::default()
+            }),
+            Some("error") => Err(format!("chat completion failed: {}", data["error"])),
+            _ => Ok(Delta::default()),
+        }
+    }
+
+    fn reply(res: &Value) -> Result<Reply, String> {
+        // The answer is a list of content blocks.
+        let content = res["content"]
+            .as_array()
+            .ok_or_else(|| format!("no content in {}", res))?
+            .iter()
+            .filter(|block| block["type"] == "text")
+            .filter_map(|block| block["text"].as_str())
+            .collect::<String>();
+
+        Ok(Reply {
+            content,
+            usage: usage(
+                res["usage"]["input_tokens"].as_u64(),
+                res["usage"]["output_tokens"].as_u64(),
+            ),
+        })
+    }
+}
+
+#[async_trait]
+impl ChatProvider for Anthropic {
+    async fn complete(&self, messages: &[Value]) -> Result<Reply, String> {
+        Anthropic::reply(&post_json(self.request(messages, false)).await?)
+    }
+
+    async fn stream(&self, messages: &[Value]) -> Result<ChatStream, String> {
+        let response = send(self.request(messages, true)).await?;
+        Ok(ChatStream::new(response, Framing::Sse, Anthropic::delta))
+    }
+}
+
+/// Ollama posts to the chat endpoint of an Ollama server (/api/chat), for
+/// models hosted locally. Streamed answers are a JSON object per line.
+pub struct Ollama {
+    client: Client,
+    config: ChatCompletion,
+}
+
+impl Ollama {
+    pub fn new(config: &ChatCompletion) -> Ollama {
+        Ollama {
+            client: client(),
+            config: config.clone(),
+        }
+    }
+
+    fn request(&self, messages: &[Value], stream: bool) -> RequestBuilder {
+        let mut body_params = json!({ "messages": messages });
+        merge_json(&mut body_params, &self.config.body_param);
+        // Ollama streams unless told otherwise.
+        body_params["stream"] = json!(stream);
+
+        bearer_auth(self.client.post(&self.config.api), &self.config.key).json(&body_params)
+    }
+
+    fn delta(data: &Value) -> Result<Delta, String> {
+        if let Some(error) = data["error"].as_str() {
+            return Err(format!("chat completion failed: {}", error));
+        }
+
+        Ok(Delta {
+            token: data["message"]["content"].as_str().map(str::to_string),
+            prompt_tokens: data["prompt_eval_count"].as_u64(),
+            completion_tokens: data["eval_count"].as_u64(),
+        })
+    }
+
+    fn reply(res: &Value) -> Result<Reply, String> {
+        let content = res["message"]["content"]
+            .as_str()
+            .ok_or_else(|| format!("no message in {}", res))?;
+
+        Ok(Reply {
+            content: content.to_string(),
+            usage: usage(
+                res["prompt_eval_count"].as_u64(),
+                res["eval_count"].as_u64(),
+            ),
+        })
+    }
+}
+
+#[async_trait]
+impl ChatProvider for Ollama {
+    async fn complete(&self, messages: &[Value]) -> Result<Reply, String> {
+        Ollama::reply(&post_json(self.request(messages, false)).await?)
+    }
+
+    async fn stream(&self, messages: &[Value]) -> Result<ChatStream, String> {
+        let response = send(self.request(messages, true)).await?;
+        Ok(ChatStream::new(response, Framing::NdJson, Ollama::delta))
+    }
+}
+
+/// Completion is the answer of the chat model along with its usage & cost.
+pub struct Completion {
+    pub content: String,
+    pub usage: Usage,
+    pub cost: f64,
+}
+
+/// ChatModel is the provider set up from the config. Usage is counted with
+/// the model's tokenizer when the provider doesn't report it.
+pub struct ChatModel {
+    provider: Box<dyn ChatProvider>,
+    config: ChatCompletion,
+    tokenizer: CoreBPE,
+}
+
+impl ChatModel {
+    pub fn new(config: &ChatCompletion) -> Result<ChatModel, String> {
+        let provider: Box<dyn ChatProvider> = match config.provider {
+            ChatProviderKind::OpenAi => Box::new(OpenAi::new(config)),
+            ChatProviderKind::Anthropic => Box::new(Anthropic::new(config)),
+            ChatProviderKind::Ollama => Box::new(Ollama::new(config)),
+        };
+
+        ChatModel::with_provider(config, provider)
+    }
+
+    pub fn with_provider(
+        config: &ChatCompletion,
+        provider: Box<dyn ChatProvider>,
+    ) -> Result<ChatModel, String> {
+        // Models tiktoken doesn't know are counted as cl100k.
+        let tokenizer = config.body_param["model"]
+            .as_str()
+            .and_then(|model| tiktoken_rs::get_bpe_from_model(model).ok())
+            .map_or_else(tiktoken_rs::cl100k_base, Ok)
+            .map_err(|e| format!("loading tokenizer: {}", e))?;
+
+        Ok(ChatModel {
+            provider,
+            config: config.clone(),
+            tokenizer,
+        })
+    }
+
+    pub fn tokenizer(&self) -> &CoreBPE {
+        &self.tokenizer
+    }
+
+    pub async fn complete(&self, messages: &[Value]) -> Result<Completion, String> {
+        let reply = self.provider.complete(messages).await?;
+        Ok(self.completion(messages, reply.content, reply.usage))
+    }
+
+    pub async fn stream(&self, messages: &[Value]) -> Result<ChatStream, String> {
+        self.provider.stream(messages).await
+    }
+
+    /// completion prices the answer to the messages, usage is counted if
+    /// it's not given.
+    pub fn completion(
+        &self,
+        messages: &[Value],
+        content: String,
+        usage: Option<Usage>,
+    ) -> Completion {
+        let usage = usage.unwrap_or_else(|| Usage {
+            prompt_tokens: messages
+                .iter()
+                .map(|message| {
+                    let content = message["content"].as_str().unwrap_or_default();
+                    self.tokenizer.encode_ordinary(content).len() as u64
+                })
+                .sum(),
+            completion_tokens: self.tokenizer.encode_ordinary(&content).len() as u64,
+        });
+        let pricing = &self.config.pricing;
+        let cost = (usage.prompt_tokens as f64 * pricing.input
+            + usage.completion_tokens as f64 * pricing.output)
+            / 1000.0;
+
+        Completion {
+            content,
+            usage,
+            cost,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn config(key: &str) -> ChatCompletion {
+        serde_json::from_value(json!({
+            "api": "http://localhost/chat",
+            "key": key,
+            "body_param": { "model": "model", "temperature": 0.5 },
+            "pricing": { "input": 1.0, "output": 2.0 }
+        }))
+        .unwrap()
+    }
+
+    fn messages() -> Vec<Value> {
+        vec![
+            json!({ "role": "system", "content": "Answer from the context." }),
+            json!({ "role": "user", "content": "What rises overnight?" }),
+        ]
+    }
+
+    /// Returns the headers & the JSON body of the request.
+    fn sent(request: RequestBuilder) -> (reqwest::header::HeaderMap, Value) {
+        let request = request.build().unwrap();
+        let body = request.body().unwrap().as_bytes().unwrap();
+        (
+            request.headers().clone(),
+            serde_json::from_slice(body).unwrap(),
+        )
+    }
+
+    /// Returns a stream reading the canned body.
+    fn canned(
+        body: &str,
+        framing: Framing,
+        parse: fn(&Value) -> Result<Delta, String>,
+    ) -> ChatStream {
+        let response = http::Response::new(body.to_string());
+        ChatStream::new(Response::from(response), framing, parse)
+    }
+
+    async fn read(stream: &mut ChatStream) -> Result<String, String> {
+        let mut answer = String::new();
+        while let Some(token) = stream.next().await? {
+            answer.push_str(&token);
+        }
+        Ok(answer)
+    }
+
+    #[test]
+    fn openai_request() {
+        let (headers, body) = sent(OpenAi::new(&config("secret")).request(&messages(), true));
+        assert_eq!(headers["authorization"], "Bearer secret");
+        // The system prompt stays the first message.
+        assert_eq!(body["messages"], json!(messages()));
+        assert_eq!(body["model"], "model");
+        assert_eq!(body["temperature"], 0.5);
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["stream_options"]["include_usage"], true);
+
+        let (headers, body) = sent(OpenAi::new(&config("")).request(&messages(), false));
+        assert!(!headers.contains_key("authorization"));
+        assert!(body.get("stream").is_none());
+    }
+
+    #[test]
+    fn openai_reply() {
+        let reply = OpenAi::reply(&json!({
+            "choices": [{ "message": { "role": "assistant", "content": "The dough." } }],
+            "usage": { "prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15 }
+        }))
+        .unwrap();
+        assert_eq!(reply.content, "The dough.");
+        let usage = reply.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (12, 3));
+
+        let reply = OpenAi::reply(&json!({
+            "choices": [{ "message": { "content": "The dough." } }]
+        }))
+        .unwrap();
+        assert!(reply.usage.is_none());
+        assert!(OpenAi::reply(&json!({ "choices": [] })).is_err());
+    }
+
+    #[tokio::test]
+    async fn openai_stream() {
+        let mut stream = canned(
+            ": keep-alive\n\
+             data: {\"choices\":[{\"delta\":{\"role\":\"assistant\",\"content\":\"\"}}]}\n\n\
+             data: {\"choices\":[{\"delta\":{\"content\":\"The \"}}]}\n\n\
+             data: {\"choices\":[{\"delta\":{\"content\":\"dough.\"}}]}\n\n\
+             data: {\"choices\":[],\"usage\":{\"prompt_tokens\":12,\"completion_tokens\":3}}\n\n\
+             data: [DONE]\n\n",
+            Framing::Sse,
+            OpenAi::delta,
+        );
+        assert_eq!(read(&mut stream).await.unwrap(), "The dough.");
+        let usage = stream.usage().unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (12, 3));
+
+        let mut stream = canned(
+            "data: {\"error\":{\"message\":\"overloaded\"}}\n\n",
+            Framing::Sse,
+            OpenAi::delta,
+        );
+        assert!(read(&mut stream).await.is_err());
+    }
+
+    #[test]
+    fn anthropic_request() {
+        let (headers, body) = sent(Anthropic::new(&config("secret")).request(&messages(), true));
+        assert_eq!(headers["x-api-key"], "secret");
+        assert_eq!(headers["anthropic-version"], Anthropic::VERSION);
+        assert!(!headers.contains_key("authorization"));
+        // The system prompt is a top level field.
+        assert_eq!(body["system"], "Answer from the context.");
+        assert_eq!(body["messages"], json!([messages()[1]]));
+        assert_eq!(body["max_tokens"], Anthropic::MAX_TOKENS);
+        assert_eq!(body["model"], "model");
+        assert_eq!(body["stream"], true);
+
+        let (_, body) = sent(Anthropic::new(&config("secret")).request(&messages()[1..], false));
+        assert!(body.get("system").is_none());
+        assert_eq!(body["stream"], false);
+    }
+
+    #[test]
+    fn anthropic_reply() {
+        let reply = Anthropic::reply(&json!({
+            "type": "message",
+            "content": [
+                { "type": "text", "text": "The " },
+                { "type": "tool_use", "id": "tool", "name": "search", "input": {} },
+                { "type": "text", "text": "dough." }
+            ],
+            "usage": { "input_tokens": 12, "output_tokens": 3 }
+        }))
+        .unwrap();
+        assert_eq!(reply.content, "The dough.");
+        let usage = reply.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (12, 3));
+    }
+
+    #[tokio::test]
+    async fn anthropic_stream() {
+        let events = "event: message_start\n\
+             data: {\"type\":\"message_start\",\"message\":{\"usage\":{\"input_tokens\":12,\"output_tokens\":1}}}\n\n\
+             event: content_block_start\n\
+             data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\n\
+             event: ping\n\
+             data: {\"type\":\"ping\"}\n\n\
+             event: content_block_delta\n\
+             data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"The \"}}\n\n\
+             event: content_block_delta\n\
+             data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"dough.\"}}\n\n\
+             event: content_block_stop\n\
+             data: {\"type\":\"content_block_stop\",\"index\":0}\n\n";
+        let end = "event: message_delta\n\
+             data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\"},\"usage\":{\"output_tokens\":3}}\n\n\
+             event: message_stop\n\
+             data: {\"type\":\"message_stop\"}\n\n";
+
+        let mut stream = canned(
+            &format!("{}{}", events, end),
+            Framing::Sse,
+            Anthropic::delta,
+        );
+        assert_eq!(read(&mut stream).await.unwrap(), "The dough.");
+        let usage = stream.usage().unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (12, 3));
+
+        // Without message_delta the placeholder of message_start isn't taken
+        // as the count, usage is counted instead.
+        let mut stream = canned(events, Framing::Sse, Anthropic::delta);
+        assert_eq!(read(&mut stream).await.unwrap(), "The dough.");
+        assert!(stream.usage().is_none());
+
+        let mut stream = canned(
+            "event: error\n\
+             data: {\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\"}}\n\n",
+            Framing::Sse,
+            Anthropic::delta,
+        );
+        assert!(read(&mut stream).await.is_err());
+    }
+
+    #[test]
+    fn ollama_request() {
+        let (headers, body) = sent(Ollama::new(&config("")).request(&messages(), true));
+        assert!(!headers.contains_key("authorization"));
+        assert_eq!(body["messages"], json!(messages()));
+        assert_eq!(body["stream"], true);
+
+        // Ollama streams unless told otherwise.
+        let (headers, body) = sent(Ollama::new(&config("secret")).request(&messages(), false));
+        assert_eq!(headers["authorization"], "Bearer secret");
+        assert_eq!(body["stream"], false);
+    }
+
+    #[test]
+    fn ollama_reply() {
+        let reply = Ollama::reply(&json!({
+            "message": { "role": "assistant", "content": "The dough." },
+            "done": true,
+            "prompt_eval_count": 12,
+            "eval_count": 3
+        }))
+        .unwrap();
+        assert_eq!(reply.content, "The dough.");
+        let usage = reply.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (12, 3));
+    }
+
+    #[tokio::test]
+    async fn ollama_stream() {
+        // The last line isn't terminated.
+        let mut stream = canned(
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"The \"},\"done\":false}\n\
+             {\"message\":{\"role\":\"assistant\",\"content\":\"dough.\"},\"done\":false}\n\
+             {\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done\":true,\"prompt_eval_count\":12,\"eval_count\":3}",
+            Framing::NdJson,
+            Ollama::delta,
+        );
+        assert_eq!(read(&mut stream).await.unwrap(), "The dough.");
+        let usage = stream.usage().unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (12, 3));
+
+        let mut stream = canned(
+            "{\"error\":\"model not found\"}\n",
+            Framing::NdJson,
+            Ollama::delta,
+        );
+        assert!(read(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stalled_stream_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 4096];
+            let _ = socket.read(&mut request).await;
+
+            let event = "data: {\"choices\":[{\"delta\":{\"content\":\"The \"}}]}\n\n";
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n{:x}\r\n{}\r\n",
+                event.len(),
+                event
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            // The connection stays open without sending the rest.
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+        });
+
+        let response = send(client().post(format!("http://{}", address)))
+            .await
+            .unwrap();
+        let mut stream = ChatStream::new(response, Framing::Sse, OpenAi::delta);
+        stream.idle_timeout = Duration::from_millis(100);
+        assert_eq!(stream.next().await.unwrap().as_deref(), Some("The "));
+        assert!(stream.next().await.unwrap_err().contains("idle"));
+    }
+
+    #[test]
+    fn usage_is_counted_when_not_reported() {
+        let model = ChatModel::new(&config("")).unwrap();
+        let completion = model.completion(&messages(), "The dough.".to_string(), None);
+        let tokens = |text: &str| model.tokenizer().encode_ordinary(text).len() as u64;
+        assert_eq!(completion.usage.completion_tokens, tokens("The dough."));
+        assert_eq!(
+            completion.usage.prompt_tokens,
+            tokens("Answer from the context.") + tokens("What rises overnight?")
+        );
+        let cost = (completion.usage.prompt_tokens + 2 * completion.usage.completion_tokens) as f64;
+        assert_eq!(completion.cost, cost / 1000.0);
+    }
+}
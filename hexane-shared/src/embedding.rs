@@ -0,0 +1,848 @@
+use async_trait::async_trait;
+use reqwest::{header::RETRY_AFTER, Client, StatusCode};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::postgres::PgConnection;
+use sqlx::types::BigDecimal;
+use sqlx::QueryBuilder;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    time::Duration,
+};
+use tiktoken_rs::CoreBPE;
+use tokio::time::sleep;
+use uuid::Uuid;
+
+use crate::{Embedding, EmbeddingProviderKind};
+
+/// Longest wait between two attempts, delays asked for by the provider are
+/// capped to it as well.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(64);
+
+/// EmbeddingError is returned when texts couldn't be embedded.
+#[derive(Debug)]
+pub enum EmbeddingError {
+    /// The request couldn't be sent or the response couldn't be read.
+    Request(reqwest::Error),
+    /// The provider answered with an error status, retry_after is the
+    /// delay asked for by rate limited responses.
+    Status {
+        status: StatusCode,
+        body: String,
+        retry_after: Option<Duration>,
+    },
+    /// The response doesn't have the expected shape.
+    Response(String),
+    /// An input has more tokens than the provider accepts.
+    InputTooLong { tokens: usize, limit: usize },
+    /// The provider couldn't be set up from the config.
+    Config(String),
+    /// Charging the credits failed.
+    Database(sqlx::Error),
+}
+
+impl EmbeddingError {
+    /// Rate limits, server errors & network errors are retried, the request
+    /// may go through later.
+    fn is_retryable(&self) -> bool {
+        match self {
+            EmbeddingError::Request(e) => e.is_timeout() || e.is_connect() || e.is_request(),
+            EmbeddingError::Status { status, .. } => {
+                *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for EmbeddingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmbeddingError::Request(e) => write!(f, "embedding request failed: {}", e),
+            EmbeddingError::Status { status, body, .. } => {
+                write!(f, "embedding provider returned {}: {}", status, body)
+            }
+            EmbeddingError::Response(e) => write!(f, "unexpected embedding response: {}", e),
+            EmbeddingError::InputTooLong { tokens, limit } => write!(
+                f,
+                "input has {} tokens, the embedding model accepts {}",
+                tokens, limit
+            ),
+            EmbeddingError::Config(e) => write!(f, "embedding config: {}", e),
+            EmbeddingError::Database(e) => write!(f, "charging embedding credits: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EmbeddingError {}
+
+impl From<reqwest::Error> for EmbeddingError {
+    fn from(e: reqwest::Error) -> EmbeddingError {
+        EmbeddingError::Request(e)
+    }
+}
+
+/// Embeddings are the vectors of a batch of inputs, in order, along with the
+/// number of tokens billed for them.
+pub struct Embeddings {
+    pub vectors: Vec<Vec<f64>>,
+    /// None if the provider doesn't report usage.
+    pub tokens: Option<u64>,
+}
+
+/// EmbeddingProvider is an API that turns texts into vectors. A single call
+/// embeds a batch that fits the provider's limits, Embedder takes care of
+/// batching & retries.
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    async fn embed(&self, inputs: &[String]) -> Result<Embeddings, EmbeddingError>;
+}
+
+/// Sends the request & returns the JSON body, error statuses are returned
+/// as EmbeddingError::Status.
+async fn post_json(request: reqwest::RequestBuilder) -> Result<Value, EmbeddingError> {
+    let response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.parse::<u64>().ok())
+            .map(Duration::from_secs);
+        return Err(EmbeddingError::Status {
+            status,
+            body: response.text().await.unwrap_or_default(),
+            retry_after,
+        });
+    }
+
+    Ok(response.json::<Value>().await?)
+}
+
+fn parse_vector(value: &Value) -> Result<Vec<f64>, EmbeddingError> {
+    value
+        .as_array()
+        .ok_or_else(|| EmbeddingError::Response("embedding is not an array".to_string()))?
+        .iter()
+        .map(|x| {
+            x.as_f64()
+                .ok_or_else(|| EmbeddingError::Response("embedding is not numeric".to_string()))
+        })
+        .collect()
+}
+
+fn client() -> Client {
+    Client::builder()
+        .timeout(Duration::from_secs(60))
+        .connect_timeout(Duration::from_secs(30))
+        .build()
+        .unwrap()
+}
+
+/// OpenAi posts to an OpenAI compatible embeddings endpoint (/v1/embeddings).
+pub struct OpenAi {
+    client: Client,
+    api: String,
+    key: String,
+    model: String,
+}
+
+impl OpenAi {
+    pub fn new(config: &Embedding) -> OpenAi {
+        OpenAi {
+            client: client(),
+            api: config.api.clone(),
+            key: config.key.clone(),
+            model: config.model.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for OpenAi {
+    async fn embed(&self, inputs: &[String]) -> Result<Embeddings, EmbeddingError> {
+        let res = post_json(
+            self.client
+                .post(&self.api)
+                .bearer_auth(&self.key)
+                .json(&json!({
+                    "model": &self.model,
+                    "input": inputs
+                })),
+        )
+        .await?;
+
+        let mut data = res["data"]
+            .as_array()
+            .ok_or_else(|| EmbeddingError::Response(format!("no data in {}", res)))?
+            .iter()
+            .map(|x| Ok((x["index"].as_u64(), parse_vector(&x["embedding"])?)))
+            .collect::<Result<Vec<(Option<u64>, Vec<f64>)>, EmbeddingError>>()?;
+        // Embeddings are returned in order, the index is there to make sure.
+        data.sort_by_key(|(index, _)| *index);
+
+        Ok(Embeddings {
+            vectors: data.into_iter().map(|(_, vector)| vector).collect(),
+            tokens: res["usage"]["total_tokens"].as_u64(),
+        })
+    }
+}
+
+/// Ollama posts to the embed endpoint of an Ollama server (/api/embed), for
+/// models hosted locally.
+pub struct Ollama {
+    client: Client,
+    api: String,
+    model: String,
+}
+
+impl Ollama {
+    pub fn new(config: &Embedding) -> Ollama {
+        Ollama {
+            client: client(),
+            api: config.api.clone(),
+            model: config.model.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for Ollama {
+    async fn embed(&self, inputs: &[String]) -> Result<Embeddings, EmbeddingError> {
+        let res = post_json(self.client.post(&self.api).json(&json!({
+            "model": &self.model,
+            "input": inputs
+        })))
+        .await?;
+
+        let vectors = res["embeddings"]
+            .as_array()
+            .ok_or_else(|| EmbeddingError::Response(format!("no embeddings in {}", res)))?
+            .iter()
+            .map(parse_vector)
+            .collect::<Result<Vec<Vec<f64>>, EmbeddingError>>()?;
+
+        Ok(Embeddings {
+            vectors,
+            tokens: res["prompt_eval_count"].as_u64(),
+        })
+    }
+}
+
+/// Local embeds texts in process without any network access, for tests &
+/// air-gapped deployments. Word unigrams & bigrams and character trigrams of
+/// every word are hashed into a fixed number of dimensions (the hashing
+/// trick), weighted by their log frequency & normalized. Texts sharing words
+/// end up close to each other, it's a lexical match rather than a semantic
+/// one.
+pub struct Local {
+    dimensions: usize,
+}
+
+impl Local {
+    pub fn new(config: &Embedding) -> Local {
+        Local {
+            dimensions: config.dimensions.unwrap_or(Local::DEFAULT_DIMENSIONS),
+        }
+    }
+
+    const DEFAULT_DIMENSIONS: usize = 384;
+
+    /// Returns the features of the text: words, word bigrams & character
+    /// trigrams of words with their boundaries marked.
+    fn features(text: &str) -> Vec<String> {
+        let words = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(|w| w.to_lowercase())
+            .collect::<Vec<String>>();
+
+        let mut features: Vec<String> = vec![];
+        for (idx, word) in words.iter().enumerate() {
+            features.push(format!("w:{}", word));
+            if let Some(next) = words.get(idx + 1) {
+                features.push(format!("b:{} {}", word, next));
+            }
+
+            let chars = format!("<{}>", word).chars().collect::<Vec<char>>();
+            for trigram in chars.windows(3) {
+                features.push(format!("c:{}", trigram.iter().collect::<String>()));
+            }
+        }
+
+        features
+    }
+
+    fn embed_text(&self, text: &str) -> Vec<f64> {
+        let mut counts: HashMap<u64, f64> = HashMap::new();
+        for feature in Local::features(text) {
+            *counts.entry(fnv1a(feature.as_bytes())).or_default() += 1.0;
+        }
+
+        // The lowest bits pick the dimension, the highest one the sign so
+        // that collisions cancel out rather than add up.
+        let mut vector = vec![0.0; self.dimensions];
+        for (hash, count) in counts {
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            vector[(hash % self.dimensions as u64) as usize] += sign * (1.0 + count.ln());
+        }
+
+        let norm = vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+        if norm > 0.0 {
+            vector.iter_mut().for_each(|x| *x /= norm);
+        }
+        vector
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for Local {
+    async fn embed(&self, inputs: &[String]) -> Result<Embeddings, EmbeddingError> {
+        // Local embeddings are free, no tokens are billed.
+        Ok(Embeddings {
+            vectors: inputs.iter().map(|input| self.embed_text(input)).collect(),
+            tokens: Some(0),
+        })
+    }
+}
+
+/// 64-bit FNV-1a hash, std's hasher isn't stable across releases and the
+/// vectors are stored.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Embedder splits inputs into batches that fit the provider's limits &
+/// retries failed requests with backoff.
+pub struct Embedder {
+    provider: Box<dyn EmbeddingProvider>,
+    config: Embedding,
+    tokenizer: CoreBPE,
+}
+
+impl Embedder {
+    pub fn new(config: &Embedding) -> Result<Embedder, EmbeddingError> {
+        let provider: Box<dyn EmbeddingProvider> = match config.provider {
+            EmbeddingProviderKind::OpenAi => Box::new(OpenAi::new(config)),
+            EmbeddingProviderKind::Ollama => Box::new(Ollama::new(config)),
+            EmbeddingProviderKind::Local => Box::new(Local::new(config)),
+        };
+
+        Embedder::with_provider(config, provider)
+    }
+
+    pub fn with_provider(
+        config: &Embedding,
+        provider: Box<dyn EmbeddingProvider>,
+    ) -> Result<Embedder, EmbeddingError> {
+        // Token counts only have to be close to the provider's, models
+        // tiktoken doesn't know about are counted like OpenAI's.
+        let tokenizer = tiktoken_rs::get_bpe_from_model(&config.model)
+            .or_else(|_| tiktoken_rs::cl100k_base())
+            .map_err(|e| EmbeddingError::Config(format!("loading tokenizer: {}", e)))?;
+
+        Ok(Embedder {
+            provider,
+            config: config.clone(),
+            tokenizer,
+        })
+    }
+
+    pub fn model(&self) -> &str {
+        &self.config.model
+    }
+
+    /// Embeds the inputs, returns a vector for every input in order.
+    pub async fn embed(&self, inputs: &[String]) -> Result<Embeddings, EmbeddingError> {
+        let mut vectors: Vec<Vec<f64>> = Vec::with_capacity(inputs.len());
+        let mut total_tokens: u64 = 0;
+
+        for batch in self.batches(inputs)? {
+            let (batch, tokens) = (&inputs[batch.0..batch.1], batch.2);
+            let result = self.embed_batch(batch).await?;
+            if result.vectors.len() != batch.len() {
+                return Err(EmbeddingError::Response(format!(
+                    "got {} embeddings for {} inputs",
+                    result.vectors.len(),
+                    batch.len()
+                )));
+            }
+
+            vectors.extend(result.vectors);
+            // Providers that don't report usage are billed for our count.
+            total_tokens += result.tokens.unwrap_or(tokens as u64);
+        }
+
+        Ok(Embeddings {
+            vectors,
+            tokens: Some(total_tokens),
+        })
+    }
+
+    /// Returns the batches as ranges of inputs along with their token count.
+    /// A batch has at most max_batch_items inputs & max_batch_tokens tokens.
+    fn batches(&self, inputs: &[String]) -> Result<Vec<(usize, usize, usize)>, EmbeddingError> {
+        let mut batches: Vec<(usize, usize, usize)> = vec![];
+        let (mut start, mut batch_tokens) = (0, 0);
+
+        for (idx, input) in inputs.iter().enumerate() {
+            let tokens = self.tokenizer.encode_ordinary(input).len();
+            if tokens > self.config.max_input_tokens {
+                return Err(EmbeddingError::InputTooLong {
+                    tokens,
+                    limit: self.config.max_input_tokens,
+                });
+            }
+
+            if idx > start
+                && (idx - start >= self.config.max_batch_items
+                    || batch_tokens + tokens > self.config.max_batch_tokens)
+            {
+                batches.push((start, idx, batch_tokens));
+                (start, batch_tokens) = (idx, 0);
+            }
+            batch_tokens += tokens;
+        }
+        if start < inputs.len() {
+            batches.push((start, inputs.len(), batch_tokens));
+        }
+
+        Ok(batches)
+    }
+
+    /// Embeds a batch, retrying with exponential backoff (1s, 2s, 4s, ...
+    /// up to 64s) or after the delay asked by the provider.
+    async fn embed_batch(&self, batch: &[String]) -> Result<Embeddings, EmbeddingError> {
+        let mut attempt = 0;
+        loop {
+            match self.provider.embed(batch).await {
+                Err(e) if e.is_retryable() && attempt < self.config.max_retries => {
+                    let delay = match &e {
+                        EmbeddingError::Status {
+                            retry_after: Some(retry_after),
+                            ..
+                        } => (*retry_after).min(MAX_RETRY_DELAY),
+                        _ => Duration::from_secs(1 << attempt.min(6)),
+                    };
+                    tracing::warn!("embedding failed, retrying in {:?}: {}", delay, e);
+
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// EmbeddingStore holds the embedding cache & the credits get_embeddings
+/// charges.
+#[async_trait]
+trait EmbeddingStore: Send {
+    /// Returns the cached vectors of the hashes, keyed by hash.
+    async fn cached(
+        &mut self,
+        model: &str,
+        hashes: &[String],
+    ) -> Result<HashMap<String, Vec<f64>>, EmbeddingError>;
+
+    async fn charge(&mut self, user_id: &Uuid, cost: f64) -> Result<(), EmbeddingError>;
+
+    async fn cache(
+        &mut self,
+        model: &str,
+        rows: &[(&String, Vec<f64>)],
+    ) -> Result<(), EmbeddingError>;
+}
+
+#[async_trait]
+impl EmbeddingStore for PgConnection {
+    async fn cached(
+        &mut self,
+        model: &str,
+        hashes: &[String],
+    ) -> Result<HashMap<String, Vec<f64>>, EmbeddingError> {
+        Ok(
+            sqlx::query_file!("queries/embedding/cache-get.sql", model, hashes)
+                .fetch_all(&mut *self)
+                .await
+                .map_err(EmbeddingError::Database)?
+                .into_iter()
+                .map(|row| {
+                    let embedding = row.embedding.into_iter().map(f64::from).collect();
+                    (row.hash, embedding)
+                })
+                .collect(),
+        )
+    }
+
+    async fn charge(&mut self, user_id: &Uuid, cost: f64) -> Result<(), EmbeddingError> {
+        sqlx::query_file!(
+            "queries/account/credit-decrement.sql",
+            user_id,
+            BigDecimal::try_from(cost).unwrap()
+        )
+        .execute(&mut *self)
+        .await
+        .map_err(EmbeddingError::Database)?;
+
+        Ok(())
+    }
+
+    async fn cache(
+        &mut self,
+        model: &str,
+        rows: &[(&String, Vec<f64>)],
+    ) -> Result<(), EmbeddingError> {
+        // Postgres takes at most 65535 parameters per statement.
+        for rows in rows.chunks(1000) {
+            let mut query_builder = QueryBuilder::new(
+                "INSERT INTO datasource.embedding_cache (hash, model, embedding) ",
+            );
+            query_builder.push_values(rows, |mut b, (hash, embedding)| {
+                b.push_bind(*hash).push_bind(model).push_bind(embedding);
+            });
+            query_builder.push(" ON CONFLICT DO NOTHING");
+            query_builder
+                .build()
+                .execute(&mut *self)
+                .await
+                .map_err(EmbeddingError::Database)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// get_embeddings embeds the inputs & charges the user for the tokens used.
+/// Vectors are looked up in the embedding cache first, only the inputs that
+/// aren't cached are sent to the provider & billed. Batches embedded before
+/// an error are charged & cached on pool all the same.
+pub async fn get_embeddings(
+    embedder: &Embedder,
+    inputs: &[String],
+    user_id: &Uuid,
+    pool: &mut PgConnection,
+) -> Result<Vec<Vec<f64>>, EmbeddingError> {
+    embeddings_from_store(embedder, inputs, user_id, pool).await
+}
+
+async fn embeddings_from_store<S: EmbeddingStore + ?Sized>(
+    embedder: &Embedder,
+    inputs: &[String],
+    user_id: &Uuid,
+    store: &mut S,
+) -> Result<Vec<Vec<f64>>, EmbeddingError> {
+    let hashes = inputs
+        .iter()
+        .map(|input| {
+            Sha256::digest(input.as_bytes())
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        })
+        .collect::<Vec<String>>();
+
+    let mut cached = store.cached(embedder.model(), &hashes).await?;
+
+    // Identical inputs are only embedded once.
+    let mut seen: HashSet<&String> = HashSet::new();
+    let misses = (0..inputs.len())
+        .filter(|idx| !cached.contains_key(&hashes[*idx]) && seen.insert(&hashes[*idx]))
+        .collect::<Vec<usize>>();
+
+    // Every batch is charged & cached as soon as it's embedded, a failure
+    // later on doesn't lose what the provider has already billed for.
+    for batch in misses.chunks(embedder.config.max_batch_items.max(1)) {
+        let embeddings = embedder
+            .embed(
+                &batch
+                    .iter()
+                    .map(|idx| inputs[*idx].clone())
+                    .collect::<Vec<String>>(),
+            )
+            .await?;
+
+        let tokens = embeddings.tokens.unwrap_or_default() as f64;
+        store
+            .charge(user_id, (tokens * embedder.config.pricing) / 1000.0)
+            .await?;
+
+        let new = batch
+            .iter()
+            .map(|idx| &hashes[*idx])
+            .zip(embeddings.vectors)
+            .collect::<Vec<(&String, Vec<f64>)>>();
+        store.cache(embedder.model(), &new).await?;
+
+        cached.extend(
+            new.into_iter()
+                .map(|(hash, embedding)| (hash.clone(), embedding)),
+        );
+    }
+
+    Ok(hashes.iter().map(|hash| cached[hash].clone()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Mock answers every input with its length & records the batches it
+    /// got. The first `failures` requests are rate limited.
+    #[derive(Clone, Default)]
+    struct Mock {
+        batches: Arc<Mutex<Vec<usize>>>,
+        failures: Arc<Mutex<u32>>,
+        retry_after: Option<Duration>,
+    }
+
+    #[async_trait]
+    impl EmbeddingProvider for Mock {
+        async fn embed(&self, inputs: &[String]) -> Result<Embeddings, EmbeddingError> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(EmbeddingError::Status {
+                    status: StatusCode::TOO_MANY_REQUESTS,
+                    body: String::new(),
+                    retry_after: self.retry_after,
+                });
+            }
+
+            self.batches.lock().unwrap().push(inputs.len());
+            Ok(Embeddings {
+                vectors: inputs
+                    .iter()
+                    .map(|input| vec![input.len() as f64])
+                    .collect(),
+                tokens: None,
+            })
+        }
+    }
+
+    fn embedder(limits: Value, mock: &Mock) -> Embedder {
+        let mut config = json!({"provider": "openai", "model": "text-embedding-3-small"});
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(limits.as_object().unwrap().clone());
+        let config: Embedding = serde_json::from_value(config).unwrap();
+
+        Embedder::with_provider(&config, Box::new(mock.clone())).unwrap()
+    }
+
+    /// Returns an input of `tokens` tokens.
+    fn input(embedder: &Embedder, tokens: usize) -> String {
+        let input = "word ".repeat(tokens).trim_end().to_string();
+        assert_eq!(embedder.tokenizer.encode_ordinary(&input).len(), tokens);
+        input
+    }
+
+    #[tokio::test]
+    async fn batches_are_split_on_item_limit() {
+        let mock = Mock::default();
+        let embedder = embedder(json!({"max_batch_items": 3}), &mock);
+        let inputs: Vec<String> = (0..7).map(|_| input(&embedder, 1)).collect();
+
+        let embeddings = embedder.embed(&inputs).await.unwrap();
+        assert_eq!(*mock.batches.lock().unwrap(), vec![3, 3, 1]);
+        assert_eq!(embeddings.vectors.len(), 7);
+        // The mock doesn't report usage, our count is billed.
+        assert_eq!(embeddings.tokens, Some(7));
+    }
+
+    #[tokio::test]
+    async fn batches_are_split_on_token_limit() {
+        let mock = Mock::default();
+        let embedder = embedder(json!({"max_batch_tokens": 10}), &mock);
+        // 4 + 6 fills a batch exactly, one more token starts a new one.
+        let inputs: Vec<String> = [4, 6, 1, 9, 2, 10]
+            .iter()
+            .map(|tokens| input(&embedder, *tokens))
+            .collect();
+
+        let batches = embedder.batches(&inputs).unwrap();
+        assert_eq!(batches, vec![(0, 2, 10), (2, 4, 10), (4, 5, 2), (5, 6, 10)]);
+
+        let embeddings = embedder.embed(&inputs).await.unwrap();
+        assert_eq!(*mock.batches.lock().unwrap(), vec![2, 2, 1, 1]);
+        // Vectors are returned in the order of the inputs.
+        let lengths: Vec<f64> = inputs.iter().map(|input| input.len() as f64).collect();
+        assert_eq!(embeddings.vectors.concat(), lengths);
+    }
+
+    #[tokio::test]
+    async fn oversize_input_is_rejected() {
+        let mock = Mock::default();
+        let embedder = embedder(json!({"max_input_tokens": 5}), &mock);
+
+        // An input right at the limit is accepted.
+        let inputs = vec![input(&embedder, 5)];
+        assert_eq!(embedder.batches(&inputs).unwrap(), vec![(0, 1, 5)]);
+
+        let inputs = vec![input(&embedder, 1), input(&embedder, 6)];
+        match embedder.embed(&inputs).await {
+            Err(EmbeddingError::InputTooLong { tokens, limit }) => {
+                assert_eq!((tokens, limit), (6, 5));
+            }
+            _ => panic!("expected InputTooLong"),
+        }
+        // Nothing is sent when an input doesn't fit.
+        assert!(mock.batches.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn input_larger_than_a_batch_is_sent_alone() {
+        let mock = Mock::default();
+        let embedder = embedder(json!({"max_batch_tokens": 4}), &mock);
+        let inputs: Vec<String> = [1, 6, 1]
+            .iter()
+            .map(|tokens| input(&embedder, *tokens))
+            .collect();
+
+        embedder.embed(&inputs).await.unwrap();
+        assert_eq!(*mock.batches.lock().unwrap(), vec![1, 1, 1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_after_is_capped() {
+        let mock = Mock {
+            failures: Arc::new(Mutex::new(2)),
+            retry_after: Some(Duration::from_secs(3600)),
+            ..Mock::default()
+        };
+        let embedder = embedder(json!({}), &mock);
+        let inputs = vec![input(&embedder, 1)];
+
+        let start = tokio::time::Instant::now();
+        embedder.embed(&inputs).await.unwrap();
+        assert_eq!(start.elapsed(), MAX_RETRY_DELAY * 2);
+        assert_eq!(*mock.batches.lock().unwrap(), vec![1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_give_up_after_max_retries() {
+        let mock = Mock {
+            failures: Arc::new(Mutex::new(3)),
+            ..Mock::default()
+        };
+        let embedder = embedder(json!({"max_retries": 2}), &mock);
+        let inputs = vec![input(&embedder, 1)];
+
+        let start = tokio::time::Instant::now();
+        assert!(matches!(
+            embedder.embed(&inputs).await,
+            Err(EmbeddingError::Status { .. })
+        ));
+        // Backoff of 1s then 2s.
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+    }
+
+    /// MemoryStore keeps the cache in memory & records the charges.
+    #[derive(Default)]
+    struct MemoryStore {
+        cache: HashMap<(String, String), Vec<f64>>,
+        charges: Vec<f64>,
+    }
+
+    #[async_trait]
+    impl EmbeddingStore for MemoryStore {
+        async fn cached(
+            &mut self,
+            model: &str,
+            hashes: &[String],
+        ) -> Result<HashMap<String, Vec<f64>>, EmbeddingError> {
+            Ok(hashes
+                .iter()
+                .filter_map(|hash| {
+                    let embedding = self.cache.get(&(model.to_string(), hash.clone()))?;
+                    Some((hash.clone(), embedding.clone()))
+                })
+                .collect())
+        }
+
+        async fn charge(&mut self, _user_id: &Uuid, cost: f64) -> Result<(), EmbeddingError> {
+            self.charges.push(cost);
+            Ok(())
+        }
+
+        async fn cache(
+            &mut self,
+            model: &str,
+            rows: &[(&String, Vec<f64>)],
+        ) -> Result<(), EmbeddingError> {
+            for (hash, embedding) in rows {
+                self.cache
+                    .entry((model.to_string(), hash.to_string()))
+                    .or_insert_with(|| embedding.clone());
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn only_cache_misses_are_billed() {
+        let mock = Mock::default();
+        let embedder = embedder(json!({"pricing": 1.0}), &mock);
+        let mut store = MemoryStore::default();
+        let user_id = Uuid::nil();
+        let inputs = vec![
+            input(&embedder, 2),
+            input(&embedder, 3),
+            input(&embedder, 2),
+        ];
+
+        let first = embeddings_from_store(&embedder, &inputs, &user_id, &mut store)
+            .await
+            .unwrap();
+        // The repeated input is embedded & billed once.
+        assert_eq!(*mock.batches.lock().unwrap(), vec![2]);
+        assert_eq!(store.charges, vec![5.0 / 1000.0]);
+        assert_eq!(first[0], first[2]);
+
+        let second = embeddings_from_store(&embedder, &inputs, &user_id, &mut store)
+            .await
+            .unwrap();
+        assert_eq!(second, first);
+        assert_eq!(*mock.batches.lock().unwrap(), vec![2]);
+        assert_eq!(store.charges.iter().sum::<f64>(), 5.0 / 1000.0);
+
+        // A new input is the only one sent along.
+        let mut inputs = inputs;
+        inputs.push(input(&embedder, 4));
+        embeddings_from_store(&embedder, &inputs, &user_id, &mut store)
+            .await
+            .unwrap();
+        assert_eq!(*mock.batches.lock().unwrap(), vec![2, 1]);
+        assert_eq!(store.charges, vec![5.0 / 1000.0, 4.0 / 1000.0]);
+    }
+
+    #[tokio::test]
+    async fn cache_is_kept_per_model() {
+        let mock = Mock::default();
+        let mut store = MemoryStore::default();
+        let user_id = Uuid::nil();
+
+        let small = embedder(json!({"pricing": 1.0}), &mock);
+        let inputs = vec![input(&small, 2)];
+        embeddings_from_store(&small, &inputs, &user_id, &mut store)
+            .await
+            .unwrap();
+
+        let other = embedder(
+            json!({"model": "text-embedding-3-large", "pricing": 1.0}),
+            &mock,
+        );
+        embeddings_from_store(&other, &inputs, &user_id, &mut store)
+            .await
+            .unwrap();
+        assert_eq!(*mock.batches.lock().unwrap(), vec![1, 1]);
+        assert_eq!(store.charges, vec![2.0 / 1000.0, 2.0 / 1000.0]);
+    }
+}
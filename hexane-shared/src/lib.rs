@@ -1,9 +1,14 @@
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-use sqlx::postgres::PgConnection;
-use sqlx::types::BigDecimal;
-use std::{collections::HashSet, path::PathBuf, time::Duration};
-use uuid::Uuid;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+pub mod chat;
+pub mod embedding;
+pub mod rerank;
 
 pub fn merge_json(a: &mut Value, b: &Value) {
     match (a, b) {
@@ -18,59 +23,6 @@ pub fn merge_json(a: &mut Value, b: &Value) {
     }
 }
 
-pub async fn get_embeddings(
-    chunks: Value,
-    config: &Config,
-    user_id: &Uuid,
-    pool: &mut PgConnection,
-) -> Vec<Vec<f64>> {
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(60))
-        .connect_timeout(Duration::from_secs(30))
-        .build()
-        .unwrap();
-
-    let res = client
-        .post(&config.embedding.api)
-        .header("Authorization", format!("Bearer {}", &config.embedding.key))
-        .json(&json!({
-            "model": &config.embedding.model,
-            "input": chunks // can be string or an array
-        }))
-        .send()
-        .await
-        .unwrap()
-        .json::<Value>()
-        .await
-        .unwrap();
-
-    let usage = &res["usage"];
-    let total_cost = (usage["total_tokens"].as_f64().unwrap() * config.embedding.pricing) / 1000.0;
-
-    sqlx::query_file!(
-        "queries/account/credit-decrement.sql",
-        user_id,
-        BigDecimal::try_from(total_cost).unwrap()
-    )
-    .execute(pool)
-    .await
-    .unwrap();
-
-    res["data"]
-        .as_array()
-        .unwrap()
-        .iter()
-        .map(|x| {
-            x["embedding"]
-                .as_array()
-                .unwrap()
-                .iter()
-                .map(|y| y.as_f64().unwrap())
-                .collect::<Vec<f64>>()
-        })
-        .collect::<Vec<Vec<f64>>>()
-}
-
 /// Shared configuration state for hexane programs.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -79,6 +31,9 @@ pub struct Config {
     pub file_processor: FileProcessor,
     pub embedding: Embedding,
     pub chat_completion: ChatCompletion,
+    /// Reranking of the retrieved chunks, disabled when not set.
+    #[serde(default)]
+    pub rerank: Option<Rerank>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -87,21 +42,490 @@ pub struct Backend {
     pub resources: PathBuf,
     pub stop_words: PathBuf,
     pub system_prompt: String,
+    #[serde(default)]
+    pub retrieval: Retrieval,
+    #[serde(default)]
+    pub chat: Chat,
+}
+
+/// Chat configures multi-turn conversations.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Chat {
+    /// Maximum number of tokens of earlier turns sent with a question, the
+    /// oldest turns are left out first.
+    #[serde(default = "Chat::default_history_tokens")]
+    pub history_tokens: usize,
+    /// Follow-up questions are condensed into a standalone question by the
+    /// chat completion model before retrieval.
+    #[serde(default = "Chat::default_condense")]
+    pub condense: bool,
+    /// Answers are streamed to the page as they're generated.
+    #[serde(default = "Chat::default_stream")]
+    pub stream: bool,
+}
+
+impl Chat {
+    fn default_history_tokens() -> usize {
+        2000
+    }
+
+    fn default_condense() -> bool {
+        true
+    }
+
+    fn default_stream() -> bool {
+        true
+    }
+}
+
+impl Default for Chat {
+    fn default() -> Chat {
+        Chat {
+            history_tokens: Chat::default_history_tokens(),
+            condense: Chat::default_condense(),
+            stream: Chat::default_stream(),
+        }
+    }
+}
+
+/// Retrieval configures how the context of a query is selected, the query
+/// form can override these per query.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Retrieval {
+    /// Number of chunks sent as context.
+    #[serde(default = "Retrieval::default_top_k")]
+    pub top_k: i64,
+    /// The indexes the file processor builds use this metric, queries with
+    /// another metric (the query form marks them) scan every chunk.
+    #[serde(default)]
+    pub metric: Metric,
+    /// Minimum similarity of a chunk to the query, see Metric::max_distance.
+    /// Chunks matching by keyword are kept regardless.
+    #[serde(default = "Retrieval::default_threshold")]
+    pub threshold: f64,
+    /// Maximum number of tokens of context, chunks that don't fit are left
+    /// out.
+    #[serde(default = "Retrieval::default_max_context_tokens")]
+    pub max_context_tokens: usize,
+    #[serde(default)]
+    pub hybrid: Hybrid,
+    #[serde(default)]
+    pub mmr: Mmr,
+    #[serde(default)]
+    pub expansion: Expansion,
+}
+
+impl Retrieval {
+    fn default_top_k() -> i64 {
+        5
+    }
+
+    fn default_threshold() -> f64 {
+        0.28
+    }
+
+    fn default_max_context_tokens() -> usize {
+        3000
+    }
+}
+
+impl Default for Retrieval {
+    fn default() -> Retrieval {
+        Retrieval {
+            top_k: Retrieval::default_top_k(),
+            metric: Metric::default(),
+            threshold: Retrieval::default_threshold(),
+            max_context_tokens: Retrieval::default_max_context_tokens(),
+            hybrid: Hybrid::default(),
+            mmr: Mmr::default(),
+            expansion: Expansion::default(),
+        }
+    }
+}
+
+/// Mmr configures maximal marginal relevance: chunks are selected one at a
+/// time from the candidates, scoring
+/// (1 - diversity) * relevance - diversity * similarity to the selected chunks.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Mmr {
+    /// 0 selects by relevance only, 1 by diversity only.
+    #[serde(default = "Mmr::default_diversity")]
+    pub diversity: f64,
+    /// Number of candidates chunks are selected from.
+    #[serde(default = "Mmr::default_candidates")]
+    pub candidates: i64,
+}
+
+impl Mmr {
+    fn default_diversity() -> f64 {
+        0.3
+    }
+
+    fn default_candidates() -> i64 {
+        20
+    }
+}
+
+impl Default for Mmr {
+    fn default() -> Mmr {
+        Mmr {
+            diversity: Mmr::default_diversity(),
+            candidates: Mmr::default_candidates(),
+        }
+    }
+}
+
+/// Expansion configures the queries generated by the chat completion model
+/// before retrieval, their results are merged with the query's.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Expansion {
+    #[serde(default)]
+    pub mode: ExpansionMode,
+    /// Number of reformulations asked for by rewrite.
+    #[serde(default = "Expansion::default_queries")]
+    pub queries: usize,
+}
+
+impl Expansion {
+    fn default_queries() -> usize {
+        3
+    }
+}
+
+impl Default for Expansion {
+    fn default() -> Expansion {
+        Expansion {
+            mode: ExpansionMode::default(),
+            queries: Expansion::default_queries(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpansionMode {
+    /// The query is used as is.
+    #[default]
+    None,
+    /// The model reformulates the query.
+    Rewrite,
+    /// The model writes a hypothetical answer (HyDE), which is embedded.
+    Hyde,
+}
+
+impl ExpansionMode {
+    pub const ALL: [ExpansionMode; 3] = [
+        ExpansionMode::None,
+        ExpansionMode::Rewrite,
+        ExpansionMode::Hyde,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExpansionMode::None => "none",
+            ExpansionMode::Rewrite => "rewrite",
+            ExpansionMode::Hyde => "hyde",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ExpansionMode> {
+        ExpansionMode::ALL
+            .into_iter()
+            .find(|mode| mode.name() == name)
+    }
+}
+
+/// Metric is the distance between vectors used for retrieval.
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    #[default]
+    L2,
+    Cosine,
+    InnerProduct,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 3] = [Metric::L2, Metric::Cosine, Metric::InnerProduct];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::L2 => "l2",
+            Metric::Cosine => "cosine",
+            Metric::InnerProduct => "inner_product",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Metric> {
+        Metric::ALL.into_iter().find(|metric| metric.name() == name)
+    }
+
+    /// pgvector distance operator.
+    pub fn operator(&self) -> &'static str {
+        match self {
+            Metric::L2 => "<->",
+            Metric::Cosine => "<=>",
+            Metric::InnerProduct => "<#>",
+        }
+    }
+
+    /// pgvector operator class of the index.
+    pub fn operator_class(&self) -> &'static str {
+        match self {
+            Metric::L2 => "vector_l2_ops",
+            Metric::Cosine => "vector_cosine_ops",
+            Metric::InnerProduct => "vector_ip_ops",
+        }
+    }
+
+    /// Distance below which vectors are at least threshold similar. The
+    /// similarity is the cosine similarity for cosine, the inner product for
+    /// inner product, & 1 - distance² / 2 for L2, which is the cosine
+    /// similarity of normalized vectors.
+    pub fn max_distance(&self, threshold: f64) -> f64 {
+        match self {
+            Metric::L2 => (2.0 - 2.0 * threshold).max(0.0).sqrt(),
+            Metric::Cosine => 1.0 - threshold,
+            // <#> returns the negative inner product.
+            Metric::InnerProduct => -threshold,
+        }
+    }
+}
+
+/// Hybrid configures how the vector & keyword (full text search) rankings
+/// are fused, with reciprocal rank fusion: a chunk scores
+/// weight / (rrf_k + rank) in each ranking it appears in.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Hybrid {
+    #[serde(default = "Hybrid::default_weight")]
+    pub vector_weight: f64,
+    /// 0 disables keyword search.
+    #[serde(default = "Hybrid::default_weight")]
+    pub keyword_weight: f64,
+    /// Higher values flatten the difference between the top ranks.
+    #[serde(default = "Hybrid::default_rrf_k")]
+    pub rrf_k: f64,
+    /// Number of chunks taken from each ranking before fusing.
+    #[serde(default = "Hybrid::default_candidates")]
+    pub candidates: i64,
+}
+
+impl Hybrid {
+    fn default_weight() -> f64 {
+        1.0
+    }
+
+    fn default_rrf_k() -> f64 {
+        60.0
+    }
+
+    fn default_candidates() -> i64 {
+        20
+    }
+}
+
+impl Default for Hybrid {
+    fn default() -> Hybrid {
+        Hybrid {
+            vector_weight: Hybrid::default_weight(),
+            keyword_weight: Hybrid::default_weight(),
+            rrf_k: Hybrid::default_rrf_k(),
+            candidates: Hybrid::default_candidates(),
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct FileProcessor {
     pub max_active_process: u32,
+    /// Number of times processing a file is attempted before it's marked as
+    /// failed.
+    #[serde(default = "FileProcessor::default_max_attempts")]
+    pub max_attempts: u32,
+    /// Seconds to wait before retrying a failed file, the delay doubles with
+    /// every attempt.
+    #[serde(default = "FileProcessor::default_retry_delay")]
+    pub retry_delay: u64,
+    /// Number of chunks of a user re-embedded at a time when the embedding
+    /// model changes, 0 disables re-embedding in the background.
+    #[serde(default = "FileProcessor::default_reembed_batch_size")]
+    pub reembed_batch_size: i64,
+    #[serde(default)]
+    pub chunking: Chunking,
+    #[serde(default)]
+    pub tabular: Tabular,
+}
+
+impl FileProcessor {
+    fn default_max_attempts() -> u32 {
+        5
+    }
+
+    fn default_retry_delay() -> u64 {
+        60
+    }
+
+    fn default_reembed_batch_size() -> i64 {
+        100
+    }
+}
+
+/// Tabular limits the part of CSV files & spreadsheets that's processed,
+/// the rest is dropped with a warning.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Tabular {
+    /// Rows beyond this are dropped, the count doesn't include the header
+    /// row.
+    #[serde(default = "Tabular::default_max_rows")]
+    pub max_rows: usize,
+    /// Columns beyond this are dropped.
+    #[serde(default = "Tabular::default_max_columns")]
+    pub max_columns: usize,
+}
+
+impl Tabular {
+    fn default_max_rows() -> usize {
+        10_000
+    }
+
+    fn default_max_columns() -> usize {
+        50
+    }
+}
+
+impl Default for Tabular {
+    fn default() -> Tabular {
+        Tabular {
+            max_rows: Tabular::default_max_rows(),
+            max_columns: Tabular::default_max_columns(),
+        }
+    }
+}
+
+/// Chunking configures how extracted text is split before it's embedded.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Chunking {
+    #[serde(default)]
+    pub strategy: ChunkingStrategy,
+    /// Maximum size of a chunk in tokens, including the overlap & the
+    /// section header repeated in every chunk.
+    #[serde(default = "Chunking::default_max_tokens")]
+    pub max_tokens: usize,
+    /// Number of tokens from the end of a chunk that are repeated at the
+    /// start of the next one.
+    #[serde(default = "Chunking::default_overlap")]
+    pub overlap: usize,
+    /// Tokenizer used to count tokens: "cl100k_base", "o200k_base",
+    /// "p50k_base" or "r50k_base". Defaults to the tokenizer of the embedding
+    /// model.
+    pub tokenizer: Option<String>,
+    /// Strategy used for the files of a category, in place of strategy.
+    #[serde(default)]
+    pub categories: HashMap<String, ChunkingStrategy>,
+}
+
+impl Chunking {
+    fn default_max_tokens() -> usize {
+        500
+    }
+
+    fn default_overlap() -> usize {
+        50
+    }
+}
+
+impl Default for Chunking {
+    fn default() -> Chunking {
+        Chunking {
+            strategy: ChunkingStrategy::default(),
+            max_tokens: Chunking::default_max_tokens(),
+            overlap: Chunking::default_overlap(),
+            tokenizer: None,
+            categories: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkingStrategy {
+    /// Splits at the largest unit of text (paragraph, sentence, word) that
+    /// fits in the chunk, sizes are counted in tokens.
+    #[default]
+    Text,
+    /// Same as Text with sizes counted in characters, 4 per token. For
+    /// embedding models whose tokenizer isn't known.
+    Characters,
+    /// Splits along the structure of the document: sections, paragraphs &
+    /// columns of text. Small sibling sections are merged into one chunk,
+    /// blocks larger than a chunk are split as with Text.
+    Structure,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ChatCompletion {
+    #[serde(default)]
+    pub provider: ChatProviderKind,
     pub api: String,
+    /// Unused by servers hosted locally.
+    #[serde(default)]
     pub key: String,
     pub body_param: Value,
     pub pricing: Pricing,
 }
 
+/// Rerank configures the reranker that scores the retrieved chunks against
+/// the query before the top ones are kept.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Rerank {
+    pub provider: RerankProviderKind,
+    /// Endpoint of the http provider.
+    #[serde(default)]
+    pub api: String,
+    #[serde(default)]
+    pub model: String,
+    #[serde(default)]
+    pub key: String,
+    /// Price per 1000 tokens of the http provider, the llm provider is
+    /// charged as chat completion.
+    #[serde(default)]
+    pub pricing: f64,
+    /// Number of chunks retrieved for reranking.
+    #[serde(default = "Rerank::default_candidates")]
+    pub candidates: i64,
+}
+
+impl Rerank {
+    fn default_candidates() -> i64 {
+        20
+    }
+}
+
+/// There's no in process reranker, a lexical one would only repeat the
+/// keyword ranking of the hybrid search. A local cross-encoder can be served
+/// by a compatible rerank server & used with the http provider.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RerankProviderKind {
+    /// A rerank endpoint (Cohere, Jina, Voyage & compatible servers).
+    Http,
+    /// The chat completion model, prompted to score the chunks.
+    Llm,
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatProviderKind {
+    /// OpenAI compatible chat completions API.
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAi,
+    /// Anthropic style messages API.
+    Anthropic,
+    /// Ollama's chat API.
+    Ollama,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Pricing {
     pub input: f64,
@@ -110,10 +534,166 @@ pub struct Pricing {
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Embedding {
+    #[serde(default)]
+    pub provider: EmbeddingProviderKind,
+    /// Endpoint of the provider, unused by the local provider.
+    #[serde(default)]
     pub api: String,
     pub model: String,
+    #[serde(default)]
     pub key: String,
+    /// Price per 1000 tokens, the local provider is free.
+    #[serde(default)]
     pub pricing: f64,
+    /// Size of the vectors of the local provider, 384 by default.
+    pub dimensions: Option<usize>,
+    /// Maximum number of inputs sent in a single request.
+    #[serde(default = "Embedding::default_max_batch_items")]
+    pub max_batch_items: usize,
+    /// Maximum number of tokens sent in a single request.
+    #[serde(default = "Embedding::default_max_batch_tokens")]
+    pub max_batch_tokens: usize,
+    /// Maximum number of tokens of a single input.
+    #[serde(default = "Embedding::default_max_input_tokens")]
+    pub max_input_tokens: usize,
+    /// Number of times a request is retried on rate limits & server errors.
+    #[serde(default = "Embedding::default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default)]
+    pub index: VectorIndex,
+}
+
+impl Embedding {
+    fn default_max_batch_items() -> usize {
+        512
+    }
+
+    fn default_max_batch_tokens() -> usize {
+        100_000
+    }
+
+    fn default_max_input_tokens() -> usize {
+        8191
+    }
+
+    fn default_max_retries() -> u32 {
+        5
+    }
+}
+
+/// VectorIndex configures the approximate nearest neighbour indexes built by
+/// `hexane-file-processor index` & how they're searched.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VectorIndex {
+    #[serde(default)]
+    pub method: VectorIndexMethod,
+    /// HNSW: maximum number of connections per layer.
+    #[serde(default = "VectorIndex::default_m")]
+    pub m: u32,
+    /// HNSW: size of the candidate list while building the index.
+    #[serde(default = "VectorIndex::default_ef_construction")]
+    pub ef_construction: u32,
+    /// HNSW: size of the candidate list while searching, higher is more
+    /// accurate & slower.
+    #[serde(default = "VectorIndex::default_ef_search")]
+    pub ef_search: u32,
+    /// IVFFlat: number of lists, rows / 1000 is a good start.
+    #[serde(default = "VectorIndex::default_lists")]
+    pub lists: u32,
+    /// IVFFlat: number of lists searched, higher is more accurate & slower.
+    #[serde(default = "VectorIndex::default_probes")]
+    pub probes: u32,
+}
+
+impl VectorIndex {
+    fn default_m() -> u32 {
+        16
+    }
+
+    fn default_ef_construction() -> u32 {
+        64
+    }
+
+    fn default_ef_search() -> u32 {
+        40
+    }
+
+    fn default_lists() -> u32 {
+        100
+    }
+
+    fn default_probes() -> u32 {
+        1
+    }
+
+    /// Name of the index of the model's vectors of the given dimension. The
+    /// model is hashed with sha256 so that migrations can name the indexes
+    /// the same way.
+    pub fn name(model: &str, dimensions: i32, metric: Metric) -> String {
+        let hash = Sha256::digest(model.as_bytes())
+            .iter()
+            .take(8)
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        format!(
+            "datasource_embedding_ann_{}_{}_{}",
+            hash,
+            dimensions,
+            metric.name()
+        )
+    }
+
+    /// Rows covered by the index of the model's vectors of the given
+    /// dimension. Queries must repeat it for the index to be used.
+    pub fn predicate(model: &str, dimensions: i32) -> String {
+        format!(
+            "model = '{}' AND dimensions = {}",
+            model.replace('\'', "''"),
+            dimensions
+        )
+    }
+
+    /// Settings to apply (with SET LOCAL) before searching the index.
+    pub fn search_settings(&self) -> String {
+        match self.method {
+            VectorIndexMethod::Hnsw => format!("SET LOCAL hnsw.ef_search = {}", self.ef_search),
+            VectorIndexMethod::IvfFlat => format!("SET LOCAL ivfflat.probes = {}", self.probes),
+        }
+    }
+}
+
+impl Default for VectorIndex {
+    fn default() -> VectorIndex {
+        VectorIndex {
+            method: VectorIndexMethod::default(),
+            m: VectorIndex::default_m(),
+            ef_construction: VectorIndex::default_ef_construction(),
+            ef_search: VectorIndex::default_ef_search(),
+            lists: VectorIndex::default_lists(),
+            probes: VectorIndex::default_probes(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VectorIndexMethod {
+    #[default]
+    Hnsw,
+    IvfFlat,
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingProviderKind {
+    /// OpenAI compatible embeddings API.
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAi,
+    /// Ollama's embed API.
+    Ollama,
+    /// Hashed n-grams computed in process, no network access needed.
+    Local,
 }
 
 impl Config {
@@ -125,3 +705,19 @@ impl Config {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_name_matches_the_migration() {
+        // left(encode(sha256(convert_to(model, 'UTF8')), 'hex'), 16)
+        assert_eq!(
+            VectorIndex::name("text-embedding-3-small", 1536, Metric::L2),
+            "datasource_embedding_ann_31a7134a5bdc1c09_1536_l2"
+        );
+        // Postgres truncates identifiers to 63 bytes.
+        assert!(VectorIndex::name("model", 1536, Metric::InnerProduct).len() <= 63);
+    }
+}
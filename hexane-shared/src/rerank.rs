@@ -0,0 +1,447 @@
+use async_trait::async_trait;
+use reqwest::{Client, RequestBuilder};
+use serde_json::{json, Value};
+use sqlx::postgres::PgConnection;
+use sqlx::types::BigDecimal;
+use std::time::Duration;
+use tiktoken_rs::CoreBPE;
+use uuid::Uuid;
+
+use crate::chat::{bearer_auth, ChatModel};
+use crate::{Config, Rerank, RerankProviderKind};
+
+/// Scores of the documents in order, higher is more relevant, along with the
+/// cost of the call.
+pub struct Scores {
+    pub scores: Vec<f64>,
+    pub cost: f64,
+}
+
+/// RerankProvider scores documents against a query. Scores are only
+/// comparable within a call.
+#[async_trait]
+pub trait RerankProvider: Send + Sync {
+    async fn rerank(&self, query: &str, documents: &[String]) -> Result<Scores, String>;
+}
+
+fn client() -> Client {
+    Client::builder()
+        .timeout(Duration::from_secs(30))
+        .connect_timeout(Duration::from_secs(10))
+        .build()
+        .unwrap()
+}
+
+async fn post_json(request: reqwest::RequestBuilder) -> Result<Value, String> {
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("rerank request failed: {}", e))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!(
+            "reranker returned {}: {}",
+            status,
+            response.text().await.unwrap_or_default()
+        ));
+    }
+
+    response
+        .json::<Value>()
+        .await
+        .map_err(|e| format!("reading rerank response: {}", e))
+}
+
+/// Http posts to a rerank endpoint (/v1/rerank) taking the query & the
+/// documents, answering with the relevance score of every document index.
+pub struct Http {
+    client: Client,
+    api: String,
+    key: String,
+    model: String,
+    pricing: f64,
+    tokenizer: CoreBPE,
+}
+
+impl Http {
+    pub fn new(config: &Rerank) -> Result<Http, String> {
+        Ok(Http {
+            client: client(),
+            api: config.api.clone(),
+            key: config.key.clone(),
+            model: config.model.clone(),
+            pricing: config.pricing,
+            tokenizer: tiktoken_rs::cl100k_base()
+                .map_err(|e| format!("loading tokenizer: {}", e))?,
+        })
+    }
+
+    /// The key is left out for servers hosted locally.
+    fn request(&self, query: &str, documents: &[String]) -> RequestBuilder {
+        bearer_auth(self.client.post(&self.api), &self.key).json(&json!({
+            "model": &self.model,
+            "query": query,
+            "documents": documents,
+            "return_documents": false
+        }))
+    }
+
+    /// Scores of the response, documents missing from it score f64::MIN.
+    fn scores(&self, res: &Value, query: &str, documents: &[String]) -> Result<Scores, String> {
+        // Cohere & Jina return results, Voyage returns data.
+        let results = res["results"]
+            .as_array()
+            .or_else(|| res["data"].as_array())
+            .ok_or_else(|| format!("no results in {}", res))?;
+
+        let mut scores = vec![f64::MIN; documents.len()];
+        for result in results {
+            match (result["index"].as_u64(), result["relevance_score"].as_f64()) {
+                (Some(index), Some(score)) if (index as usize) < documents.len() => {
+                    scores[index as usize] = score
+                }
+                _ => return Err(format!("unexpected rerank result: {}", result)),
+            }
+        }
+
+        // Providers that don't report usage are billed for our count.
+        let tokens = res["usage"]["total_tokens"].as_u64().unwrap_or_else(|| {
+            documents
+                .iter()
+                .map(|document| {
+                    self.tokenizer.encode_ordinary(query).len()
+                        + self.tokenizer.encode_ordinary(document).len()
+                })
+                .sum::<usize>() as u64
+        });
+
+        Ok(Scores {
+            scores,
+            cost: (tokens as f64 * self.pricing) / 1000.0,
+        })
+    }
+}
+
+#[async_trait]
+impl RerankProvider for Http {
+    async fn rerank(&self, query: &str, documents: &[String]) -> Result<Scores, String> {
+        let res = post_json(self.request(query, documents)).await?;
+        self.scores(&res, query, documents)
+    }
+}
+
+/// Llm asks the chat completion model to score every document from 0 to 10
+/// in a single request.
+pub struct Llm {
+    chat: ChatModel,
+}
+
+impl Llm {
+    pub fn new(chat: ChatModel) -> Llm {
+        Llm { chat }
+    }
+
+    const PROMPT: &'static str = "Rate how well each numbered passage answers the question, \
+        from 0 (irrelevant) to 10 (answers it completely). Reply with only a JSON array of \
+        numbers, one per passage, in order.";
+
+    /// Parses the scores of the completion, there must be one per document.
+    fn scores(content: &str, documents: usize) -> Result<Vec<f64>, String> {
+        // Models sometimes wrap the array in prose or a code block.
+        let array = content
+            .find('[')
+            .zip(content.rfind(']'))
+            .filter(|(start, end)| start < end)
+            .map(|(start, end)| &content[start..=end])
+            .ok_or_else(|| format!("no scores in {}", content))?;
+        let scores = serde_json::from_str::<Vec<f64>>(array)
+            .map_err(|e| format!("parsing scores {}: {}", array, e))?;
+        if scores.len() != documents {
+            return Err(format!(
+                "got {} scores for {} passages",
+                scores.len(),
+                documents
+            ));
+        }
+
+        Ok(scores)
+    }
+}
+
+#[async_trait]
+impl RerankProvider for Llm {
+    async fn rerank(&self, query: &str, documents: &[String]) -> Result<Scores, String> {
+        let passages = documents
+            .iter()
+            .enumerate()
+            .map(|(idx, document)| format!("[{}] {}", idx + 1, document))
+            .collect::<Vec<String>>()
+            .join("\n\n");
+
+        let completion = self
+            .chat
+            .complete(&[
+                json!({ "role": "system", "content": Llm::PROMPT }),
+                json!({
+                    "role": "user",
+                    "content": format!("QUESTION: {}\n\nPASSAGES:\n{}", query, passages)
+                }),
+            ])
+            .await?;
+
+        Ok(Scores {
+            scores: Llm::scores(&completion.content, documents.len())?,
+            cost: completion.cost,
+        })
+    }
+}
+
+/// Reranker is the provider set up from the config.
+pub struct Reranker {
+    provider: Box<dyn RerankProvider>,
+    config: Rerank,
+}
+
+impl Reranker {
+    pub fn new(config: &Config) -> Result<Option<Reranker>, String> {
+        let Some(rerank) = &config.rerank else {
+            return Ok(None);
+        };
+
+        let provider: Box<dyn RerankProvider> = match rerank.provider {
+            RerankProviderKind::Http => Box::new(Http::new(rerank)?),
+            RerankProviderKind::Llm => Box::new(Llm::new(ChatModel::new(&config.chat_completion)?)),
+        };
+
+        Ok(Some(Reranker::with_provider(rerank, provider)))
+    }
+
+    pub fn with_provider(config: &Rerank, provider: Box<dyn RerankProvider>) -> Reranker {
+        Reranker {
+            provider,
+            config: config.clone(),
+        }
+    }
+
+    /// Number of chunks retrieved for reranking.
+    pub fn candidates(&self) -> i64 {
+        self.config.candidates
+    }
+}
+
+/// Credits are charged for the rerank calls.
+#[async_trait]
+trait Credits: Send {
+    async fn charge(&mut self, user_id: &Uuid, cost: f64) -> Result<(), String>;
+}
+
+#[async_trait]
+impl Credits for PgConnection {
+    async fn charge(&mut self, user_id: &Uuid, cost: f64) -> Result<(), String> {
+        sqlx::query_file!(
+            "queries/account/credit-decrement.sql",
+            user_id,
+            BigDecimal::try_from(cost).unwrap()
+        )
+        .execute(&mut *self)
+        .await
+        .map_err(|e| format!("charging rerank credits: {}", e))?;
+
+        Ok(())
+    }
+}
+
+/// rerank scores the documents against the query & charges the user for the
+/// call.
+pub async fn rerank(
+    reranker: &Reranker,
+    query: &str,
+    documents: &[String],
+    user_id: &Uuid,
+    pool: &mut PgConnection,
+) -> Result<Vec<f64>, String> {
+    rerank_charged(reranker, query, documents, user_id, pool).await
+}
+
+async fn rerank_charged<C: Credits + ?Sized>(
+    reranker: &Reranker,
+    query: &str,
+    documents: &[String],
+    user_id: &Uuid,
+    credits: &mut C,
+) -> Result<Vec<f64>, String> {
+    let scores = reranker.provider.rerank(query, documents).await?;
+
+    // Free providers aren't charged.
+    if scores.cost > 0.0 {
+        credits.charge(user_id, scores.cost).await?;
+    }
+
+    Ok(scores.scores)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(key: &str) -> Rerank {
+        serde_json::from_value(json!({
+            "provider": "http",
+            "api": "http://localhost/v1/rerank",
+            "model": "rerank-model",
+            "key": key,
+            "pricing": 2.0
+        }))
+        .unwrap()
+    }
+
+    fn documents() -> Vec<String> {
+        ["Sourdough rises overnight.", "Knives are sharpened weekly."]
+            .map(str::to_string)
+            .to_vec()
+    }
+
+    #[test]
+    fn http_request() {
+        let request = Http::new(&config("secret"))
+            .unwrap()
+            .request("what rises?", &documents())
+            .build()
+            .unwrap();
+        assert_eq!(request.headers()["authorization"], "Bearer secret");
+        let body: Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "model": "rerank-model",
+                "query": "what rises?",
+                "documents": documents(),
+                "return_documents": false
+            })
+        );
+
+        // Servers hosted locally take no key.
+        let request = Http::new(&config(""))
+            .unwrap()
+            .request("what rises?", &documents())
+            .build()
+            .unwrap();
+        assert!(!request.headers().contains_key("authorization"));
+    }
+
+    #[test]
+    fn http_scores() {
+        let http = Http::new(&config("")).unwrap();
+        let documents = documents();
+
+        // Cohere & Jina.
+        let scores = http
+            .scores(
+                &json!({
+                    "results": [
+                        { "index": 1, "relevance_score": 0.25 },
+                        { "index": 0, "relevance_score": 0.75 }
+                    ],
+                    "usage": { "total_tokens": 500 }
+                }),
+                "what rises?",
+                &documents,
+            )
+            .unwrap();
+        assert_eq!(scores.scores, vec![0.75, 0.25]);
+        assert_eq!(scores.cost, 1.0);
+
+        // Voyage, documents left out of the response rank last.
+        let scores = http
+            .scores(
+                &json!({ "data": [{ "index": 1, "relevance_score": 0.5 }] }),
+                "what rises?",
+                &documents,
+            )
+            .unwrap();
+        assert_eq!(scores.scores, vec![f64::MIN, 0.5]);
+
+        let tokens = |text: &str| http.tokenizer.encode_ordinary(text).len();
+        let counted = documents
+            .iter()
+            .map(|document| tokens("what rises?") + tokens(document))
+            .sum::<usize>();
+        assert_eq!(scores.cost, counted as f64 * 2.0 / 1000.0);
+    }
+
+    #[test]
+    fn http_unexpected_responses() {
+        let http = Http::new(&config("")).unwrap();
+        let documents = documents();
+
+        for res in [
+            json!({ "results": [{ "index": 2, "relevance_score": 0.5 }] }),
+            json!({ "results": [{ "index": 0 }] }),
+            json!({ "error": "model not found" }),
+        ] {
+            assert!(
+                http.scores(&res, "what rises?", &documents).is_err(),
+                "{}",
+                res
+            );
+        }
+    }
+
+    #[test]
+    fn llm_scores() {
+        assert_eq!(Llm::scores("[7, 2.5]", 2).unwrap(), vec![7.0, 2.5]);
+        assert_eq!(
+            Llm::scores("Here are the scores:\n```json\n[10, 0]\n```", 2).unwrap(),
+            vec![10.0, 0.0]
+        );
+
+        assert!(Llm::scores("[7, 2, 1]", 2).is_err());
+        assert!(Llm::scores("The first passage answers it.", 2).is_err());
+        assert!(Llm::scores("] not an array [", 2).is_err());
+        assert!(Llm::scores("[high, low]", 2).is_err());
+    }
+
+    /// Fixed answers the scores it's given at the given cost.
+    struct Fixed(f64);
+
+    #[async_trait]
+    impl RerankProvider for Fixed {
+        async fn rerank(&self, _query: &str, documents: &[String]) -> Result<Scores, String> {
+            Ok(Scores {
+                scores: (0..documents.len()).map(|idx| idx as f64).collect(),
+                cost: self.0,
+            })
+        }
+    }
+
+    #[derive(Default)]
+    struct Charges(Vec<f64>);
+
+    #[async_trait]
+    impl Credits for Charges {
+        async fn charge(&mut self, _user_id: &Uuid, cost: f64) -> Result<(), String> {
+            self.0.push(cost);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn only_paid_calls_are_charged() {
+        let mut charges = Charges::default();
+        let user_id = Uuid::nil();
+
+        let free = Reranker::with_provider(&config(""), Box::new(Fixed(0.0)));
+        let scores = rerank_charged(&free, "what rises?", &documents(), &user_id, &mut charges)
+            .await
+            .unwrap();
+        assert_eq!(scores, vec![0.0, 1.0]);
+        assert!(charges.0.is_empty());
+
+        let paid = Reranker::with_provider(&config(""), Box::new(Fixed(0.5)));
+        rerank_charged(&paid, "what rises?", &documents(), &user_id, &mut charges)
+            .await
+            .unwrap();
+        assert_eq!(charges.0, vec![0.5]);
+    }
+}